@@ -0,0 +1,39 @@
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel_demo::DbConnection;
+
+use std::time::{Duration, Instant};
+
+/// Runs `f` in a transaction that is always rolled back, so every iteration
+/// starts from the same rows. Auto-increment counters still advance.
+pub fn rolled_back<F>(conn: &DbConnection, f: F)
+where
+    F: FnOnce() -> QueryResult<()>,
+{
+    let result = conn.transaction::<(), Error, _>(|| {
+        f()?;
+        Err(Error::RollbackTransaction)
+    });
+    match result {
+        Err(Error::RollbackTransaction) => {}
+        other => panic!("benchmark query failed: {:?}", other),
+    }
+}
+
+/// Like `rolled_back`, but runs `setup` first in the same transaction and
+/// times only `f`.
+pub fn timed_rolled_back<T, S, F>(conn: &DbConnection, setup: S, f: F) -> Duration
+where
+    S: FnOnce() -> QueryResult<T>,
+    F: FnOnce(T) -> QueryResult<()>,
+{
+    let mut elapsed = Duration::default();
+    rolled_back(conn, || {
+        let prepared = setup()?;
+        let start = Instant::now();
+        f(prepared)?;
+        elapsed = start.elapsed();
+        Ok(())
+    });
+    elapsed
+}
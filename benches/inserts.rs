@@ -0,0 +1,100 @@
+//! Compares the insert styles shown in `src/lib.rs` for growing batches.
+//! Needs `DATABASE_URL`. Every iteration is rolled back, but the users
+//! AUTO_INCREMENT still moves past the ids it used. Results go to
+//! `target/criterion/report/index.html`.
+
+mod common;
+
+use common::{rolled_back, timed_rolled_back};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use diesel::expression::dsl::max;
+use diesel::prelude::*;
+use diesel_demo::generator::Generator;
+use diesel_demo::schema::users;
+use diesel_demo::{
+    establish_connection, insert_forms, insert_names, insert_or_ignore_names, insert_tuples,
+    replace_names, DbConnection, UserForm,
+};
+
+use std::time::Duration;
+
+const BATCH_SIZES: &[usize] = &[1, 10, 100, 1_000];
+
+/// Inserts `forms` and returns the ids they were given, so the strategies
+/// that write explicit ids have rows to conflict with.
+fn existing_rows<'a>(
+    conn: &DbConnection,
+    forms: &[UserForm<'a>],
+    names: &[&'a str],
+) -> QueryResult<Vec<(i32, &'a str)>> {
+    insert_forms(conn, None, forms)?;
+    let last = users::table
+        .select(max(users::id))
+        .first::<Option<i32>>(conn)?
+        .unwrap_or(0);
+    let first = last - forms.len() as i32 + 1;
+    Ok((first..=last).zip(names.iter().copied()).collect())
+}
+
+fn insert_strategies(c: &mut Criterion) {
+    let conn = establish_connection();
+    let mut group = c.benchmark_group("insert_users");
+
+    for &size in BATCH_SIZES {
+        let mut generator = Generator::new(0);
+        let fakes = (0..size).map(|_| generator.user(0.1)).collect::<Vec<_>>();
+        let names = fakes
+            .iter()
+            .map(|user| user.name.as_str())
+            .collect::<Vec<_>>();
+        let forms = fakes
+            .iter()
+            .map(|user| UserForm::new(&user.name, user.hair_color.clone()))
+            .collect::<Vec<_>>();
+        group.throughput(Throughput::Elements(size as u64));
+
+        group.bench_function(BenchmarkId::new("single_column", size), |b| {
+            b.iter(|| rolled_back(&conn, || insert_names(&conn, None, &names).map(drop)))
+        });
+
+        group.bench_function(BenchmarkId::new("tuple_batch", size), |b| {
+            b.iter(|| rolled_back(&conn, || insert_tuples(&conn, None, &forms).map(drop)))
+        });
+
+        group.bench_function(BenchmarkId::new("struct_batch", size), |b| {
+            b.iter(|| rolled_back(&conn, || insert_forms(&conn, None, &forms).map(drop)))
+        });
+
+        group.bench_function(BenchmarkId::new("replace_into", size), |b| {
+            b.iter_custom(|iters| {
+                (0..iters)
+                    .map(|_| {
+                        timed_rolled_back(
+                            &conn,
+                            || existing_rows(&conn, &forms, &names),
+                            |rows| replace_names(&conn, None, &rows).map(drop),
+                        )
+                    })
+                    .sum::<Duration>()
+            })
+        });
+
+        group.bench_function(BenchmarkId::new("insert_or_ignore", size), |b| {
+            b.iter_custom(|iters| {
+                (0..iters)
+                    .map(|_| {
+                        timed_rolled_back(
+                            &conn,
+                            || existing_rows(&conn, &forms, &names),
+                            |rows| insert_or_ignore_names(&conn, None, &rows).map(drop),
+                        )
+                    })
+                    .sum::<Duration>()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, insert_strategies);
+criterion_main!(benches);
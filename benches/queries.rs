@@ -0,0 +1,123 @@
+//! Times listing and searching over generated data. Needs `DATABASE_URL`;
+//! the data lives in a test transaction and is never committed, though the
+//! AUTO_INCREMENT counters move past it. Results go to
+//! `target/criterion/report/index.html`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use diesel::prelude::*;
+use diesel_demo::feed::published_entries;
+use diesel_demo::generator::{generate, GenerateOptions};
+use diesel_demo::models::Post;
+use diesel_demo::schema::{posts, users};
+use diesel_demo::slugs::find_by_slug;
+use diesel_demo::soft_delete::{active_posts, active_users};
+use diesel_demo::User;
+use diesel_demo::{establish_connection, DbConnection};
+
+const PAGE: i64 = 20;
+
+fn seeded_connection() -> DbConnection {
+    let conn = establish_connection();
+    conn.begin_test_transaction().unwrap();
+    let options = GenerateOptions {
+        users: 2_000,
+        posts: 20_000,
+        ..GenerateOptions::default()
+    };
+    generate(&conn, None, &options, |_| {}).unwrap();
+    conn
+}
+
+fn queries(c: &mut Criterion) {
+    let conn = seeded_connection();
+
+    let mut group = c.benchmark_group("list");
+    group.bench_function("published_posts", |b| {
+        b.iter(|| {
+            active_posts()
+                .filter(posts::published.eq(true))
+                .order(posts::published_at.desc())
+                .limit(PAGE)
+                .load::<Post>(&conn)
+                .unwrap()
+        })
+    });
+    group.bench_function("feed_entries", |b| {
+        b.iter(|| published_entries(&conn, PAGE).unwrap())
+    });
+    group.bench_function("newest_users", |b| {
+        b.iter(|| {
+            active_users()
+                .order((users::created_at.desc(), users::id.desc()))
+                .limit(PAGE)
+                .load::<User>(&conn)
+                .unwrap()
+        })
+    });
+    // A deep page two ways: OFFSET reads and discards the rows before it,
+    // keyset pagination seeks straight to it.
+    let deep_id = active_posts()
+        .select(posts::id)
+        .order(posts::id)
+        .offset(10_000)
+        .first::<i32>(&conn)
+        .unwrap();
+    group.bench_function("posts_page_offset", |b| {
+        b.iter(|| {
+            active_posts()
+                .order(posts::id)
+                .offset(10_000)
+                .limit(PAGE)
+                .load::<Post>(&conn)
+                .unwrap()
+        })
+    });
+    group.bench_function("posts_page_keyset", |b| {
+        b.iter(|| {
+            active_posts()
+                .filter(posts::id.ge(deep_id))
+                .order(posts::id)
+                .limit(PAGE)
+                .load::<Post>(&conn)
+                .unwrap()
+        })
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("search");
+    group.bench_function("posts_title_like", |b| {
+        b.iter(|| {
+            active_posts()
+                .filter(posts::title.like("%locking%"))
+                .limit(PAGE)
+                .load::<Post>(&conn)
+                .unwrap()
+        })
+    });
+    let name = active_users()
+        .select(users::name)
+        .first::<String>(&conn)
+        .unwrap();
+    group.bench_function("users_by_name", |b| {
+        b.iter(|| {
+            active_users()
+                .filter(users::name.eq(&name))
+                .order((users::created_at.desc(), users::id.desc()))
+                .limit(5)
+                .load::<User>(&conn)
+                .unwrap()
+        })
+    });
+    let slug = posts::table
+        .select(posts::slug)
+        .find(deep_id)
+        .first::<String>(&conn)
+        .unwrap();
+    group.bench_function("post_by_slug", |b| {
+        b.iter(|| find_by_slug(&conn, &slug).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, queries);
+criterion_main!(benches);
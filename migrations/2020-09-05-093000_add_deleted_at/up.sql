@@ -0,0 +1,4 @@
+-- Soft delete support: rows with a non-NULL `deleted_at` are hidden from the
+-- default queries and can be restored until they are purged.
+ALTER TABLE users ADD COLUMN deleted_at TIMESTAMP NULL DEFAULT NULL;
+ALTER TABLE posts ADD COLUMN deleted_at TIMESTAMP NULL DEFAULT NULL;
@@ -0,0 +1,3 @@
+-- Optimistic locking: every update must name the version it read and bumps it.
+ALTER TABLE users ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE posts ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,5 @@
+-- Login credentials. Both columns stay nullable so users created before
+-- registration existed keep working; they simply cannot log in.
+ALTER TABLE users ADD COLUMN email VARCHAR(255) NULL DEFAULT NULL;
+ALTER TABLE users ADD COLUMN password_hash VARCHAR(255) NULL DEFAULT NULL;
+CREATE UNIQUE INDEX users_email_unique ON users (email);
@@ -0,0 +1,14 @@
+-- Bearer tokens for sessions and API clients. Only a SHA-256 of the token is
+-- stored; the plaintext is shown once when the token is issued.
+CREATE TABLE api_tokens (
+  id INTEGER AUTO_INCREMENT PRIMARY KEY,
+  user_id INTEGER NOT NULL,
+  token_hash CHAR(64) NOT NULL,
+  scopes TEXT NOT NULL,
+  expires_at DATETIME NOT NULL,
+  last_used_at DATETIME NULL DEFAULT NULL,
+  revoked_at DATETIME NULL DEFAULT NULL,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  UNIQUE KEY api_tokens_token_hash_unique (token_hash),
+  CONSTRAINT api_tokens_user_id_fk FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE posts DROP FOREIGN KEY posts_author_id_fk;
+ALTER TABLE posts DROP COLUMN author_id;
+ALTER TABLE users DROP COLUMN role;
@@ -0,0 +1,6 @@
+-- Every user has one role (admin, editor, author or reader) and every post
+-- may have an author; posts written before authors existed have none.
+ALTER TABLE users ADD COLUMN role VARCHAR(16) NOT NULL DEFAULT 'reader';
+ALTER TABLE posts ADD COLUMN author_id INTEGER NULL DEFAULT NULL;
+ALTER TABLE posts ADD CONSTRAINT posts_author_id_fk
+  FOREIGN KEY (author_id) REFERENCES users (id) ON DELETE SET NULL;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+-- The original spellings are not kept, so normalisation cannot be undone;
+-- the lowercase names remain valid input for the previous code.
+SELECT 1;
@@ -0,0 +1,9 @@
+-- Hair colours are stored as lowercase names (see `HairColor`). Fold case,
+-- whitespace and alternative spellings of recognised colours; anything else
+-- is left as it was and read back as `HairColor::Other`.
+UPDATE users SET hair_color = NULL WHERE TRIM(hair_color) = '';
+UPDATE users SET hair_color = LOWER(TRIM(hair_color))
+  WHERE LOWER(TRIM(hair_color)) IN
+    ('black', 'blonde', 'blond', 'brown', 'grey', 'gray', 'red', 'white', 'yellow');
+UPDATE users SET hair_color = 'blonde' WHERE hair_color = 'blond';
+UPDATE users SET hair_color = 'grey' WHERE hair_color = 'gray';
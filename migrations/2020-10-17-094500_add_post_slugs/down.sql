@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE post_slugs;
+DROP INDEX posts_slug_unique ON posts;
+ALTER TABLE posts DROP COLUMN slug;
@@ -0,0 +1,14 @@
+-- URL slugs for posts. Existing posts get a placeholder slug that is
+-- replaced the next time their title is edited.
+ALTER TABLE posts ADD COLUMN slug VARCHAR(255) NULL DEFAULT NULL;
+UPDATE posts SET slug = CONCAT('post-', id);
+ALTER TABLE posts MODIFY slug VARCHAR(255) NOT NULL;
+CREATE UNIQUE INDEX posts_slug_unique ON posts (slug);
+
+-- Slugs a post used to have, so links to a renamed post keep resolving.
+CREATE TABLE post_slugs (
+  slug VARCHAR(255) NOT NULL PRIMARY KEY,
+  post_id INTEGER NOT NULL,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  CONSTRAINT post_slugs_post_id_fk FOREIGN KEY (post_id) REFERENCES posts (id) ON DELETE CASCADE
+);
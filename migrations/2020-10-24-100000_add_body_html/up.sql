@@ -0,0 +1,3 @@
+-- Sanitised HTML rendered from the Markdown body. NULL until the post is
+-- next written or `render_posts` is run; readers fall back to rendering.
+ALTER TABLE posts ADD COLUMN body_html TEXT NULL DEFAULT NULL;
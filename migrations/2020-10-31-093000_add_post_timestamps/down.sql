@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX posts_published_at ON posts;
+ALTER TABLE posts DROP COLUMN published_at;
+ALTER TABLE posts DROP COLUMN updated_at;
+ALTER TABLE posts DROP COLUMN created_at;
@@ -0,0 +1,7 @@
+-- When posts were written, last changed and first published. Posts that are
+-- already published are taken to have been published when they were created.
+ALTER TABLE posts ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP;
+ALTER TABLE posts ADD COLUMN updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP;
+ALTER TABLE posts ADD COLUMN published_at TIMESTAMP NULL DEFAULT NULL;
+UPDATE posts SET published_at = created_at WHERE published;
+CREATE INDEX posts_published_at ON posts (published_at);
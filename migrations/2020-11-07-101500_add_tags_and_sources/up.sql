@@ -0,0 +1,16 @@
+-- Tags are stored normalised (trimmed, lowercase), one row per post and tag.
+CREATE TABLE post_tags (
+  post_id INTEGER NOT NULL,
+  tag VARCHAR(64) NOT NULL,
+  PRIMARY KEY (post_id, tag),
+  INDEX post_tags_tag (tag),
+  CONSTRAINT post_tags_post_id_fk FOREIGN KEY (post_id) REFERENCES posts (id) ON DELETE CASCADE
+);
+
+-- The Markdown file a post was imported from, relative to the import root.
+CREATE TABLE post_sources (
+  path VARCHAR(255) NOT NULL PRIMARY KEY,
+  post_id INTEGER NOT NULL,
+  imported_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  CONSTRAINT post_sources_post_id_fk FOREIGN KEY (post_id) REFERENCES posts (id) ON DELETE CASCADE
+);
@@ -0,0 +1,15 @@
+-- One row per changed user or post: who changed it, how, and the row before
+-- and after as JSON. There is no foreign key to users, so the history of a
+-- purged row or actor is kept; actor_id is NULL when nobody was logged in.
+CREATE TABLE audit_log (
+  id INTEGER AUTO_INCREMENT PRIMARY KEY,
+  actor_id INTEGER NULL,
+  action VARCHAR(16) NOT NULL,
+  table_name VARCHAR(64) NOT NULL,
+  row_id INTEGER NOT NULL,
+  before_json TEXT NULL,
+  after_json TEXT NULL,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX audit_log_row ON audit_log (table_name, row_id, id);
+CREATE INDEX audit_log_actor ON audit_log (actor_id);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM post_sources WHERE CHAR_LENGTH(path) > 255;
+ALTER TABLE post_sources MODIFY path VARCHAR(255) NOT NULL;
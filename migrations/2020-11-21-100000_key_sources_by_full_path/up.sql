@@ -0,0 +1,5 @@
+-- Sources are keyed by the canonical path of the file, not the path below
+-- the import root, so files with the same name in different directories
+-- are different sources. Rows keyed the old way no longer match any file;
+-- the posts are found by id or slug instead and keyed again on import.
+ALTER TABLE post_sources MODIFY path VARCHAR(760) NOT NULL;
@@ -0,0 +1,419 @@
+use crate::models::{AuditAction, AuditEntry, Post};
+use crate::schema::{audit_log, posts, users};
+use crate::{DbConnection, User};
+use diesel::expression::dsl::max;
+use diesel::prelude::*;
+use serde::Serialize;
+use serde_json::Value;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Left out of every snapshot.
+const REDACTED_FIELDS: &[&str] = &["password_hash"];
+
+/// A table whose rows are audited, keyed by an integer id. Library calls
+/// record through `audited`, or `audited_inserts` when they add rows whose
+/// ids they do not know; the building blocks they use
+/// (`locking::update_post`, the `soft_delete_*` statements,
+/// `slugs::set_slug`) do not, so each change is recorded once.
+pub trait Audited: Serialize + Sized {
+    const TABLE: &'static str;
+
+    fn row_id(&self) -> i32;
+
+    /// The rows with `ids`, soft deleted or not.
+    fn find_all(conn: &DbConnection, ids: &[i32]) -> QueryResult<Vec<Self>>;
+
+    /// The highest id in the table, or 0 if it is empty.
+    fn last_id(conn: &DbConnection) -> QueryResult<i32>;
+
+    /// The rows with ids above `id`, soft deleted or not.
+    fn find_after(conn: &DbConnection, id: i32) -> QueryResult<Vec<Self>>;
+
+    /// Runs `change` and records how it changed the rows with `ids`, all in
+    /// one transaction. Rows that did not change are not recorded; rows that
+    /// appeared are recorded as `Create` and rows that disappeared as
+    /// `Purge`, whatever `action` says.
+    fn audited<R, E, F>(
+        conn: &DbConnection,
+        actor: Option<&User>,
+        action: AuditAction,
+        ids: &[i32],
+        change: F,
+    ) -> Result<R, E>
+    where
+        E: From<diesel::result::Error>,
+        F: FnOnce() -> Result<R, E>,
+    {
+        conn.transaction(|| {
+            let before = Self::find_all(conn, ids)?;
+            let result = change()?;
+            let after = Self::find_all(conn, ids)?;
+            record_changes(conn, actor, action, &before, &after)?;
+            Ok(result)
+        })
+    }
+
+    /// Runs `insert`, which adds rows with auto-increment ids, and records
+    /// each row above the previous highest id as `Create`, all in one
+    /// transaction.
+    fn audited_inserts<R, E, F>(
+        conn: &DbConnection,
+        actor: Option<&User>,
+        insert: F,
+    ) -> Result<R, E>
+    where
+        E: From<diesel::result::Error>,
+        F: FnOnce() -> Result<R, E>,
+    {
+        conn.transaction(|| {
+            let last = Self::last_id(conn)?;
+            let result = insert()?;
+            let created = Self::find_after(conn, last)?;
+            record_changes(conn, actor, AuditAction::Create, &[], &created)?;
+            Ok(result)
+        })
+    }
+}
+
+impl Audited for User {
+    const TABLE: &'static str = "users";
+
+    fn row_id(&self) -> i32 {
+        self.id
+    }
+
+    fn find_all(conn: &DbConnection, ids: &[i32]) -> QueryResult<Vec<Self>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        users::table.filter(users::id.eq_any(ids)).load(conn)
+    }
+
+    fn last_id(conn: &DbConnection) -> QueryResult<i32> {
+        let last = users::table
+            .select(max(users::id))
+            .first::<Option<i32>>(conn)?;
+        Ok(last.unwrap_or(0))
+    }
+
+    fn find_after(conn: &DbConnection, id: i32) -> QueryResult<Vec<Self>> {
+        users::table
+            .filter(users::id.gt(id))
+            .order(users::id)
+            .load(conn)
+    }
+}
+
+impl Audited for Post {
+    const TABLE: &'static str = "posts";
+
+    fn row_id(&self) -> i32 {
+        self.id
+    }
+
+    fn find_all(conn: &DbConnection, ids: &[i32]) -> QueryResult<Vec<Self>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        posts::table.filter(posts::id.eq_any(ids)).load(conn)
+    }
+
+    fn last_id(conn: &DbConnection) -> QueryResult<i32> {
+        let last = posts::table
+            .select(max(posts::id))
+            .first::<Option<i32>>(conn)?;
+        Ok(last.unwrap_or(0))
+    }
+
+    fn find_after(conn: &DbConnection, id: i32) -> QueryResult<Vec<Self>> {
+        posts::table
+            .filter(posts::id.gt(id))
+            .order(posts::id)
+            .load(conn)
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "audit_log"]
+struct NewAuditEntry<'a> {
+    actor_id: Option<i32>,
+    action: AuditAction,
+    table_name: &'a str,
+    row_id: i32,
+    before_json: Option<String>,
+    after_json: Option<String>,
+}
+
+/// The row as JSON, without `REDACTED_FIELDS`.
+pub fn snapshot<T: Serialize>(row: &T) -> serde_json::Result<String> {
+    let mut value = serde_json::to_value(row)?;
+    if let Value::Object(fields) = &mut value {
+        for field in REDACTED_FIELDS {
+            fields.remove(*field);
+        }
+    }
+    serde_json::to_string(&value)
+}
+
+fn snapshot_of<T: Serialize>(row: Option<&T>) -> QueryResult<Option<String>> {
+    row.map(snapshot)
+        .transpose()
+        .map_err(|e| diesel::result::Error::SerializationError(Box::new(e)))
+}
+
+fn new_entry<T: Audited>(
+    actor: Option<&User>,
+    action: AuditAction,
+    before: Option<&T>,
+    after: Option<&T>,
+) -> QueryResult<Option<NewAuditEntry<'static>>> {
+    let row_id = match after.or(before) {
+        Some(row) => row.row_id(),
+        None => return Ok(None),
+    };
+
+    Ok(Some(NewAuditEntry {
+        actor_id: actor.map(|actor| actor.id),
+        action,
+        table_name: T::TABLE,
+        row_id,
+        before_json: snapshot_of(before)?,
+        after_json: snapshot_of(after)?,
+    }))
+}
+
+/// Records one change to one row. Give `before` or `after` or both.
+pub fn record<T: Audited>(
+    conn: &DbConnection,
+    actor: Option<&User>,
+    action: AuditAction,
+    before: Option<&T>,
+    after: Option<&T>,
+) -> QueryResult<()> {
+    if let Some(entry) = new_entry(actor, action, before, after)? {
+        diesel::insert_into(audit_log::table)
+            .values(&entry)
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Pairs `before` and `after` rows by id and records the ones that differ,
+/// in one INSERT.
+pub fn record_changes<T: Audited>(
+    conn: &DbConnection,
+    actor: Option<&User>,
+    action: AuditAction,
+    before: &[T],
+    after: &[T],
+) -> QueryResult<()> {
+    let mut rows = BTreeMap::<i32, (Option<&T>, Option<&T>)>::new();
+    for row in before {
+        rows.entry(row.row_id()).or_default().0 = Some(row);
+    }
+    for row in after {
+        rows.entry(row.row_id()).or_default().1 = Some(row);
+    }
+
+    let mut entries = Vec::new();
+    for (before, after) in rows.values() {
+        let action = match (before, after) {
+            (None, _) => AuditAction::Create,
+            (_, None) => AuditAction::Purge,
+            (Some(old), Some(new)) => {
+                if snapshot_of(Some(*old))? == snapshot_of(Some(*new))? {
+                    continue;
+                }
+                action
+            }
+        };
+        entries.extend(new_entry(actor, action, *before, *after)?);
+    }
+    if !entries.is_empty() {
+        diesel::insert_into(audit_log::table)
+            .values(&entries)
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Every recorded change to one row, oldest first.
+pub fn history(conn: &DbConnection, table: &str, row_id: i32) -> QueryResult<Vec<AuditEntry>> {
+    audit_log::table
+        .filter(audit_log::table_name.eq(table))
+        .filter(audit_log::row_id.eq(row_id))
+        .order(audit_log::id)
+        .load(conn)
+}
+
+impl AuditEntry {
+    pub fn before(&self) -> Option<Value> {
+        self.before_json
+            .as_deref()
+            .and_then(|json| serde_json::from_str(json).ok())
+    }
+
+    pub fn after(&self) -> Option<Value> {
+        self.after_json
+            .as_deref()
+            .and_then(|json| serde_json::from_str(json).ok())
+    }
+
+    /// Fields whose value differs between the two snapshots, in name order.
+    /// A created or purged row lists all of its fields.
+    pub fn changed_fields(&self) -> Vec<String> {
+        let field = |value: &Option<Value>, name: &str| {
+            value.as_ref().and_then(|value| value.get(name)).cloned()
+        };
+        let (before, after) = (self.before(), self.after());
+        let names = [&before, &after]
+            .iter()
+            .filter_map(|value| value.as_ref().and_then(Value::as_object))
+            .flat_map(|fields| fields.keys().cloned())
+            .collect::<BTreeSet<_>>();
+
+        names
+            .into_iter()
+            .filter(|name| field(&before, name) != field(&after, name))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+fn entry(before: Option<&str>, after: Option<&str>) -> AuditEntry {
+    AuditEntry {
+        id: 1,
+        actor_id: Some(2),
+        action: AuditAction::Update,
+        table_name: "posts".into(),
+        row_id: 7,
+        before_json: before.map(String::from),
+        after_json: after.map(String::from),
+        created_at: chrono::NaiveDate::from_ymd(2020, 11, 14).and_hms(0, 0, 0),
+    }
+}
+
+#[test]
+fn snapshots_leave_out_password_hashes() {
+    let user = serde_json::json!({ "id": 1, "name": "Sean", "password_hash": "$argon2id$..." });
+    let json = snapshot(&user).unwrap();
+
+    assert_eq!(r#"{"id":1,"name":"Sean"}"#, json);
+}
+
+#[test]
+fn changed_fields_compare_snapshots() {
+    let update = entry(
+        Some(r#"{"id":7,"title":"Old","version":1}"#),
+        Some(r#"{"id":7,"title":"New","version":2}"#),
+    );
+    assert_eq!(vec!["title", "version"], update.changed_fields());
+
+    let created = entry(None, Some(r#"{"id":7,"title":"New"}"#));
+    assert_eq!(vec!["id", "title"], created.changed_fields());
+}
+
+#[test]
+fn actions_round_trip_through_text() {
+    for action in &[
+        AuditAction::Create,
+        AuditAction::Update,
+        AuditAction::Publish,
+        AuditAction::Delete,
+        AuditAction::Restore,
+        AuditAction::Purge,
+    ] {
+        assert_eq!(Ok(*action), action.as_str().parse());
+    }
+    assert!("drop".parse::<AuditAction>().is_err());
+}
+
+#[test]
+fn examine_sql_from_history() {
+    use diesel::debug_query;
+    use diesel::mysql::Mysql;
+
+    let query = audit_log::table
+        .filter(audit_log::table_name.eq("posts"))
+        .filter(audit_log::row_id.eq(7))
+        .order(audit_log::id);
+    let sql = "SELECT `audit_log`.`id`, `audit_log`.`actor_id`, `audit_log`.`action`, \
+               `audit_log`.`table_name`, `audit_log`.`row_id`, `audit_log`.`before_json`, \
+               `audit_log`.`after_json`, `audit_log`.`created_at` FROM `audit_log` \
+               WHERE `audit_log`.`table_name` = ? AND `audit_log`.`row_id` = ? \
+               ORDER BY `audit_log`.`id` -- binds: [\"posts\", 7]";
+    assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
+}
+
+#[test]
+fn edits_are_recorded_with_their_actor() {
+    use crate::fixtures::{seed, Profile};
+    use crate::models::PostChanges;
+    use diesel::result::Error;
+
+    let conn = crate::establish_connection();
+    conn.test_transaction::<_, Error, _>(|| {
+        let seeded = seed(&conn, Profile::Minimal).unwrap();
+        let (author, editor) = (&seeded.users["ruby"], &seeded.users["tess"]);
+        let post = crate::create_post(&conn, author, "Audited", "First").unwrap();
+        let changes = PostChanges {
+            body: Some("Second"),
+            ..PostChanges::default()
+        };
+        crate::publishing::edit_post(&conn, editor, post.id, post.version, &changes).unwrap();
+
+        let history = history(&conn, Post::TABLE, post.id)?;
+        let actions = history
+            .iter()
+            .map(|e| (e.action, e.actor_id))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (AuditAction::Create, Some(author.id)),
+                (AuditAction::Update, Some(editor.id)),
+            ],
+            actions
+        );
+        assert!(history[1].changed_fields().contains(&"body".to_string()));
+        Ok(())
+    });
+}
+
+#[test]
+fn bulk_inserts_are_recorded_as_created() {
+    use crate::fixtures::{seed, Profile};
+    use crate::user_csv::{import_users, ColumnMapping, ImportMode};
+    use diesel::result::Error;
+
+    let conn = crate::establish_connection();
+    conn.test_transaction::<_, Error, _>(|| {
+        let seeded = seed(&conn, Profile::Minimal).unwrap();
+        let admin = &seeded.users["sean"];
+        let seeded_history = history(&conn, User::TABLE, admin.id)?;
+        assert_eq!(1, seeded_history.len());
+        assert_eq!(AuditAction::Create, seeded_history[0].action);
+
+        let last = User::last_id(&conn)?;
+        let csv = "name,hair_color\nAda,Black\nGrace,\n";
+        let report = import_users(
+            &conn,
+            Some(admin),
+            csv.as_bytes(),
+            &ColumnMapping::default(),
+            ImportMode::AllOrNothing,
+        )
+        .unwrap();
+        assert_eq!(2, report.inserted);
+
+        for user in User::find_after(&conn, last)? {
+            let entries = history(&conn, User::TABLE, user.id)?;
+            let actions = entries
+                .iter()
+                .map(|e| (e.action, e.actor_id))
+                .collect::<Vec<_>>();
+            assert_eq!(vec![(AuditAction::Create, Some(admin.id))], actions);
+        }
+        Ok(())
+    });
+}
@@ -0,0 +1,357 @@
+use crate::audit::{self, Audited};
+use crate::models::AuditAction;
+use crate::policy::{authorize, Action, Forbidden};
+use crate::schema::users;
+use crate::soft_delete::active_users;
+use crate::{DbConnection, User};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use diesel::prelude::*;
+use diesel::result::DatabaseErrorKind;
+use rand::RngCore;
+
+use std::error::Error;
+use std::fmt;
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Verified against when the email is unknown, so a failed login takes as
+/// long whether or not the account exists.
+const DUMMY_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$ZHVtbXlzYWx0ZHVtbXk$0Oa3c1ZpMGxnI8QHy8qRuTT4Cqr1F7Q3lYtXAjfVhZQ";
+
+#[derive(Debug)]
+pub enum AuthError {
+    EmailTaken,
+    /// Unknown email or wrong password; deliberately not told apart.
+    InvalidCredentials,
+    WeakPassword,
+    Forbidden(Forbidden),
+    /// No active user has the given id.
+    NotFound,
+    Hash(argon2::password_hash::Error),
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::EmailTaken => write!(f, "email is already registered"),
+            AuthError::InvalidCredentials => write!(f, "invalid email or password"),
+            AuthError::WeakPassword => write!(
+                f,
+                "password must be at least {} characters",
+                MIN_PASSWORD_LENGTH
+            ),
+            AuthError::Forbidden(e) => write!(f, "{}", e),
+            AuthError::NotFound => write!(f, "no such user"),
+            AuthError::Hash(e) => write!(f, "password hashing failed: {}", e),
+            AuthError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for AuthError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AuthError::Forbidden(e) => Some(e),
+            AuthError::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The unique index on `users.email`; see the credentials migration.
+const EMAIL_INDEX: &str = "users_email_unique";
+
+impl From<diesel::result::Error> for AuthError {
+    fn from(e: diesel::result::Error) -> Self {
+        match e {
+            diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, ref info)
+                if info.message().contains(EMAIL_INDEX) =>
+            {
+                AuthError::EmailTaken
+            }
+            e => AuthError::Database(e),
+        }
+    }
+}
+
+impl From<Forbidden> for AuthError {
+    fn from(e: Forbidden) -> Self {
+        AuthError::Forbidden(e)
+    }
+}
+
+impl From<argon2::password_hash::Error> for AuthError {
+    fn from(e: argon2::password_hash::Error) -> Self {
+        AuthError::Hash(e)
+    }
+}
+
+impl crate::metrics::ErrorKind for AuthError {
+    fn kind(&self) -> &'static str {
+        match self {
+            AuthError::EmailTaken => "email_taken",
+            AuthError::InvalidCredentials => "invalid_credentials",
+            AuthError::WeakPassword => "weak_password",
+            AuthError::Forbidden(_) => "forbidden",
+            AuthError::NotFound => "not_found",
+            AuthError::Hash(_) => "hash_error",
+            AuthError::Database(e) => e.kind(),
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "users"]
+struct NewAccount<'a> {
+    name: &'a str,
+    email: &'a str,
+    password_hash: &'a str,
+}
+
+/// Emails are compared case-insensitively, so store them one way only.
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+pub fn hash_password(password: &str) -> Result<String, AuthError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(AuthError::WeakPassword);
+    }
+
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt)?;
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+    Ok(hash.to_string())
+}
+
+/// Checks `password` against a PHC hash string. The digest comparison inside
+/// `argon2` is constant-time.
+pub fn password_matches(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+pub fn register_user(
+    conn: &DbConnection,
+    name: &str,
+    email: &str,
+    password: &str,
+) -> Result<User, AuthError> {
+    let email = normalize_email(email);
+    let password_hash = hash_password(password)?;
+
+    crate::metrics::track("register_user", || {
+        conn.transaction(|| {
+            let taken = users::table
+                .filter(users::email.eq(&email))
+                .count()
+                .get_result::<i64>(conn)?;
+            if taken > 0 {
+                return Err(AuthError::EmailTaken);
+            }
+
+            // The unique index still guards against a concurrent registration.
+            diesel::insert_into(users::table)
+                .values(&NewAccount {
+                    name,
+                    email: &email,
+                    password_hash: &password_hash,
+                })
+                .execute(conn)?;
+
+            let user = users::table.filter(users::email.eq(&email)).first(conn)?;
+            // Registering is the new user acting on their own behalf.
+            audit::record(conn, Some(&user), AuditAction::Create, None, Some(&user))?;
+            Ok(user)
+        })
+    })
+}
+
+pub fn verify_credentials(
+    conn: &DbConnection,
+    email: &str,
+    password: &str,
+) -> Result<User, AuthError> {
+    let user = active_users()
+        .filter(users::email.eq(normalize_email(email)))
+        .first::<User>(conn)
+        .optional()?;
+
+    match user {
+        Some(user) => {
+            let hash = user.password_hash.as_deref().unwrap_or(DUMMY_HASH);
+            if password_matches(password, hash) && user.password_hash.is_some() {
+                Ok(user)
+            } else {
+                Err(AuthError::InvalidCredentials)
+            }
+        }
+        None => {
+            password_matches(password, DUMMY_HASH);
+            Err(AuthError::InvalidCredentials)
+        }
+    }
+}
+
+/// Changes the password of a user who can prove they know the current one.
+pub fn change_password(
+    conn: &DbConnection,
+    email: &str,
+    current_password: &str,
+    new_password: &str,
+) -> Result<User, AuthError> {
+    let user = verify_credentials(conn, email, current_password)?;
+    reset_password(conn, &user, user.id, new_password)
+}
+
+/// Sets a new password without checking the old one. Users may reset their
+/// own; anybody else's takes an admin. Existing tokens of the user are
+/// revoked.
+pub fn reset_password(
+    conn: &DbConnection,
+    actor: &User,
+    user_id: i32,
+    new_password: &str,
+) -> Result<User, AuthError> {
+    if actor.id != user_id {
+        authorize(actor, Action::ManageUsers, None)?;
+    }
+    let password_hash = hash_password(new_password)?;
+
+    User::audited(conn, Some(actor), AuditAction::Update, &[user_id], || {
+        let updated = diesel::update(active_users().filter(users::id.eq(user_id)))
+            .set((
+                users::password_hash.eq(&password_hash),
+                users::version.eq(users::version + 1),
+            ))
+            .execute(conn)?;
+        if updated == 0 {
+            return Err(AuthError::NotFound);
+        }
+        // Whoever held the old password may also hold a token.
+        crate::tokens::revoke_all_tokens(conn, user_id)?;
+
+        Ok(users::table.find(user_id).first(conn)?)
+    })
+}
+
+#[test]
+fn hash_and_verify_password() {
+    let hash = hash_password("correct horse").unwrap();
+
+    assert!(hash.starts_with("$argon2id$"));
+    assert!(password_matches("correct horse", &hash));
+    assert!(!password_matches("battery staple", &hash));
+    assert!(!password_matches("correct horse", "not a hash"));
+}
+
+#[test]
+fn salts_differ_between_hashes() {
+    assert_ne!(
+        hash_password("correct horse").unwrap(),
+        hash_password("correct horse").unwrap()
+    );
+}
+
+#[test]
+fn reject_short_passwords() {
+    match hash_password("short") {
+        Err(AuthError::WeakPassword) => {}
+        other => panic!("expected WeakPassword, got {:?}", other),
+    }
+}
+
+#[test]
+fn dummy_hash_is_well_formed() {
+    assert!(PasswordHash::new(DUMMY_HASH).is_ok());
+}
+
+#[test]
+fn register_and_login() {
+    use diesel::result::Error;
+
+    let conn = crate::establish_connection();
+    // The fixtures use example.com addresses, and may already be loaded.
+    conn.test_transaction::<_, Error, _>(|| {
+        let user = register_user(&conn, "Sean", " Sean@Example.org ", "correct horse").unwrap();
+        assert_eq!(Some("sean@example.org".to_string()), user.email);
+
+        let logged_in = verify_credentials(&conn, "sean@example.org", "correct horse").unwrap();
+        assert_eq!(user.id, logged_in.id);
+
+        match verify_credentials(&conn, "sean@example.org", "battery staple") {
+            Err(AuthError::InvalidCredentials) => {}
+            other => panic!("expected InvalidCredentials, got {:?}", other),
+        }
+        match verify_credentials(&conn, "nobody@example.org", "correct horse") {
+            Err(AuthError::InvalidCredentials) => {}
+            other => panic!("expected InvalidCredentials, got {:?}", other),
+        }
+        match register_user(&conn, "Tess", "SEAN@example.org", "another password") {
+            Err(AuthError::EmailTaken) => {}
+            other => panic!("expected EmailTaken, got {:?}", other),
+        }
+
+        Ok(())
+    });
+}
+
+#[test]
+fn change_password_requires_current_password() {
+    use diesel::result::Error;
+
+    let conn = crate::establish_connection();
+    conn.test_transaction::<_, Error, _>(|| {
+        register_user(&conn, "Tess", "tess@example.org", "correct horse").unwrap();
+
+        assert!(change_password(&conn, "tess@example.org", "wrong guess", "new password").is_err());
+        change_password(&conn, "tess@example.org", "correct horse", "new password").unwrap();
+
+        assert!(verify_credentials(&conn, "tess@example.org", "correct horse").is_err());
+        assert!(verify_credentials(&conn, "tess@example.org", "new password").is_ok());
+
+        Ok(())
+    });
+}
+
+#[test]
+fn only_admins_reset_other_passwords() {
+    use crate::models::Role;
+    use diesel::result::Error;
+
+    let conn = crate::establish_connection();
+    conn.test_transaction::<_, Error, _>(|| {
+        let tess = register_user(&conn, "Tess", "tess@example.org", "correct horse").unwrap();
+        let jim = register_user(&conn, "Jim", "jim@example.org", "correct horse").unwrap();
+
+        match reset_password(&conn, &jim, tess.id, "new password") {
+            Err(AuthError::Forbidden(forbidden)) => {
+                assert_eq!(Action::ManageUsers, forbidden.action)
+            }
+            other => panic!("expected Forbidden, got {:?}", other),
+        }
+        assert!(verify_credentials(&conn, "tess@example.org", "correct horse").is_ok());
+
+        let admin = User {
+            role: Role::Admin,
+            ..jim
+        };
+        reset_password(&conn, &admin, tess.id, "new password").unwrap();
+        assert!(verify_credentials(&conn, "tess@example.org", "new password").is_ok());
+
+        match reset_password(&conn, &admin, -1, "new password") {
+            Err(AuthError::NotFound) => {}
+            other => panic!("expected NotFound, got {:?}", other),
+        }
+
+        Ok(())
+    });
+}
@@ -0,0 +1,628 @@
+use crate::models::{ApiToken, AuditEntry, Post, PostSlug, PostSource, PostTag};
+use crate::ndjson::{dump_rows, NdjsonError, BATCH_SIZE};
+use crate::schema::{api_tokens, audit_log, post_slugs, post_sources, post_tags, posts, users};
+use crate::{DbConnection, User};
+use diesel::expression::dsl::max;
+use diesel::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+table! {
+    __diesel_schema_migrations (version) {
+        version -> VarChar,
+        run_on -> Timestamp,
+    }
+}
+
+/// First line of every archive; the number is the archive format version.
+const MAGIC: &str = "@diesel-demo-backup 1";
+
+/// Tables in an archive, parents before children so foreign keys resolve.
+pub const TABLES: &[&str] = &[
+    "users",
+    "posts",
+    "api_tokens",
+    "post_slugs",
+    "post_tags",
+    "post_sources",
+    "audit_log",
+];
+
+#[derive(Debug)]
+pub enum BackupError {
+    Io(io::Error),
+    /// The archive is not laid out as `backup` writes it.
+    Format {
+        line: usize,
+        message: String,
+    },
+    Json {
+        line: usize,
+        error: serde_json::Error,
+    },
+    Checksum {
+        table: String,
+    },
+    /// The archive was taken at a different migration than the database is at.
+    MigrationMismatch {
+        archive: String,
+        database: Option<String>,
+    },
+    NotEmpty {
+        table: &'static str,
+        rows: i64,
+    },
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackupError::Io(e) => write!(f, "{}", e),
+            BackupError::Format { line, message } => write!(f, "line {}: {}", line, message),
+            BackupError::Json { line, error } => write!(f, "line {}: {}", line, error),
+            BackupError::Checksum { table } => {
+                write!(f, "{} does not match its checksum", table)
+            }
+            BackupError::MigrationMismatch { archive, database } => write!(
+                f,
+                "archive is at migration {} but the database is at {}; run `diesel migration run` first",
+                archive,
+                database.as_deref().unwrap_or("none")
+            ),
+            BackupError::NotEmpty { table, rows } => write!(
+                f,
+                "restore needs an empty database, but {} has {} rows",
+                table, rows
+            ),
+            BackupError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for BackupError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BackupError::Io(e) => Some(e),
+            BackupError::Json { error, .. } => Some(error),
+            BackupError::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BackupError {
+    fn from(e: io::Error) -> Self {
+        BackupError::Io(e)
+    }
+}
+
+impl From<diesel::result::Error> for BackupError {
+    fn from(e: diesel::result::Error) -> Self {
+        BackupError::Database(e)
+    }
+}
+
+impl From<NdjsonError> for BackupError {
+    fn from(e: NdjsonError) -> Self {
+        match e {
+            NdjsonError::Io(e) => BackupError::Io(e),
+            NdjsonError::Json { line, error } => BackupError::Json { line, error },
+            NdjsonError::Database(e) => BackupError::Database(e),
+            e => BackupError::Format {
+                line: 0,
+                message: e.to_string(),
+            },
+        }
+    }
+}
+
+impl crate::metrics::ErrorKind for BackupError {
+    fn kind(&self) -> &'static str {
+        match self {
+            BackupError::Io(_) => "io",
+            BackupError::Format { .. } | BackupError::Json { .. } => "invalid",
+            BackupError::Checksum { .. } => "checksum",
+            BackupError::MigrationMismatch { .. } => "migration_mismatch",
+            BackupError::NotEmpty { .. } => "not_empty",
+            BackupError::Database(e) => e.kind(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct TableSummary {
+    pub table: String,
+    pub rows: usize,
+    /// SHA-256 of the table's lines, newlines included.
+    pub sha256: String,
+}
+
+/// Hashes everything written through it.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The latest migration applied to the database.
+pub fn migration_version(conn: &DbConnection) -> QueryResult<Option<String>> {
+    __diesel_schema_migrations::table
+        .select(max(__diesel_schema_migrations::version))
+        .first(conn)
+}
+
+/// Writes `@table`, the rows, then `@end` with the row count and checksum.
+fn write_section<T, K, W, F>(
+    out: &mut W,
+    table: &str,
+    first: K,
+    key_of: fn(&T) -> K,
+    fetch: F,
+) -> Result<TableSummary, BackupError>
+where
+    T: Serialize,
+    W: Write,
+    F: Fn(&K, i64) -> QueryResult<Vec<T>>,
+{
+    writeln!(out, "@table {}", table)?;
+    let mut hashing = HashingWriter {
+        inner: &mut *out,
+        hasher: Sha256::new(),
+    };
+    let rows = dump_rows(&mut hashing, BATCH_SIZE, first, key_of, fetch)?;
+    let sha256 = hex::encode(hashing.hasher.finalize());
+    writeln!(out, "@end {} {} {}", table, rows, sha256)?;
+
+    Ok(TableSummary {
+        table: table.to_string(),
+        rows,
+        sha256,
+    })
+}
+
+/// Writes every table to `out` as one archive: a header with the migration
+/// version, then a checksummed section of NDJSON rows per table.
+pub fn backup<W: Write>(conn: &DbConnection, mut out: W) -> Result<Vec<TableSummary>, BackupError> {
+    crate::metrics::track("backup", || {
+        // One snapshot across tables, so foreign keys in the archive agree.
+        conn.transaction(|| {
+            let version = migration_version(conn)?.unwrap_or_default();
+            writeln!(out, "{}", MAGIC)?;
+            writeln!(out, "@migration {}", version)?;
+            writeln!(out, "@created {}", chrono::Utc::now().to_rfc3339())?;
+
+            let summaries =
+                vec![
+                    write_section(
+                        &mut out,
+                        "users",
+                        i32::MIN,
+                        |row: &User| row.id,
+                        |after, limit| {
+                            users::table
+                                .filter(users::id.gt(*after))
+                                .order(users::id)
+                                .limit(limit)
+                                .load(conn)
+                        },
+                    )?,
+                    write_section(
+                        &mut out,
+                        "posts",
+                        i32::MIN,
+                        |row: &Post| row.id,
+                        |after, limit| {
+                            posts::table
+                                .filter(posts::id.gt(*after))
+                                .order(posts::id)
+                                .limit(limit)
+                                .load(conn)
+                        },
+                    )?,
+                    write_section(
+                        &mut out,
+                        "api_tokens",
+                        i32::MIN,
+                        |row: &ApiToken| row.id,
+                        |after, limit| {
+                            api_tokens::table
+                                .filter(api_tokens::id.gt(*after))
+                                .order(api_tokens::id)
+                                .limit(limit)
+                                .load(conn)
+                        },
+                    )?,
+                    write_section(
+                        &mut out,
+                        "post_slugs",
+                        String::new(),
+                        |row: &PostSlug| row.slug.clone(),
+                        |after, limit| {
+                            post_slugs::table
+                                .filter(post_slugs::slug.gt(after))
+                                .order(post_slugs::slug)
+                                .limit(limit)
+                                .load(conn)
+                        },
+                    )?,
+                    write_section(
+                        &mut out,
+                        "post_tags",
+                        (i32::MIN, String::new()),
+                        |row: &PostTag| (row.post_id, row.tag.clone()),
+                        |(post_id, tag), limit| {
+                            post_tags::table
+                                .filter(post_tags::post_id.gt(*post_id).or(
+                                    post_tags::post_id.eq(*post_id).and(post_tags::tag.gt(tag)),
+                                ))
+                                .order((post_tags::post_id, post_tags::tag))
+                                .limit(limit)
+                                .load(conn)
+                        },
+                    )?,
+                    write_section(
+                        &mut out,
+                        "post_sources",
+                        String::new(),
+                        |row: &PostSource| row.path.clone(),
+                        |after, limit| {
+                            post_sources::table
+                                .filter(post_sources::path.gt(after))
+                                .order(post_sources::path)
+                                .limit(limit)
+                                .load(conn)
+                        },
+                    )?,
+                    write_section(
+                        &mut out,
+                        "audit_log",
+                        i32::MIN,
+                        |row: &AuditEntry| row.id,
+                        |after, limit| {
+                            audit_log::table
+                                .filter(audit_log::id.gt(*after))
+                                .order(audit_log::id)
+                                .limit(limit)
+                                .load(conn)
+                        },
+                    )?,
+                ];
+
+            writeln!(out, "@done")?;
+            out.flush()?;
+            Ok(summaries)
+        })
+    })
+}
+
+/// Reads an archive a line at a time, counting lines for error messages.
+pub struct ArchiveReader<R> {
+    input: R,
+    line: usize,
+}
+
+impl<R: BufRead> ArchiveReader<R> {
+    pub fn new(input: R) -> Self {
+        ArchiveReader { input, line: 0 }
+    }
+
+    fn format_error(&self, message: String) -> BackupError {
+        BackupError::Format {
+            line: self.line,
+            message,
+        }
+    }
+
+    fn next_line(&mut self) -> Result<String, BackupError> {
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            return Err(self.format_error("archive ends early".to_string()));
+        }
+        self.line += 1;
+        if line.ends_with('\n') {
+            line.pop();
+        }
+        Ok(line)
+    }
+
+    /// Reads the `@`-line starting with `directive` and returns the rest.
+    fn expect(&mut self, directive: &str) -> Result<String, BackupError> {
+        let line = self.next_line()?;
+        match line.strip_prefix(directive) {
+            Some(rest) => Ok(rest.trim_start().to_string()),
+            None => Err(self.format_error(format!("expected {}, found {:?}", directive, line))),
+        }
+    }
+
+    /// Checks the format and returns the archive's migration version.
+    pub fn read_header(&mut self) -> Result<String, BackupError> {
+        let magic = self.next_line()?;
+        if magic != MAGIC {
+            return Err(self.format_error(format!("not a backup archive ({:?})", magic)));
+        }
+        let version = self.expect("@migration")?;
+        self.expect("@created")?;
+        Ok(version)
+    }
+
+    /// Reads one table's rows into `insert` in chunks, then checks them
+    /// against the `@end` line.
+    pub fn read_section<T, F>(
+        &mut self,
+        table: &str,
+        chunk_size: usize,
+        mut insert: F,
+    ) -> Result<usize, BackupError>
+    where
+        T: DeserializeOwned,
+        F: FnMut(&[T]) -> QueryResult<usize>,
+    {
+        let found = self.expect("@table")?;
+        if found != table {
+            return Err(self.format_error(format!("expected table {}, found {}", table, found)));
+        }
+
+        let mut hasher = Sha256::new();
+        let mut chunk = Vec::with_capacity(chunk_size);
+        let mut rows = 0;
+        let end = loop {
+            let line = self.next_line()?;
+            if let Some(end) = line.strip_prefix("@end ") {
+                break end.to_string();
+            }
+            hasher.update(line.as_bytes());
+            hasher.update(b"\n");
+            let row = serde_json::from_str(&line).map_err(|error| BackupError::Json {
+                line: self.line,
+                error,
+            })?;
+            chunk.push(row);
+            rows += 1;
+            if chunk.len() == chunk_size {
+                insert(&chunk)?;
+                chunk.clear();
+            }
+        };
+        if !chunk.is_empty() {
+            insert(&chunk)?;
+        }
+
+        let expected = format!("{} {} {}", table, rows, hex::encode(hasher.finalize()));
+        if end != expected {
+            return Err(BackupError::Checksum {
+                table: table.to_string(),
+            });
+        }
+        Ok(rows)
+    }
+
+    pub fn read_footer(&mut self) -> Result<(), BackupError> {
+        self.expect("@done").map(|_| ())
+    }
+}
+
+fn ensure_empty(conn: &DbConnection) -> Result<(), BackupError> {
+    let counts = [
+        ("users", users::table.count().get_result::<i64>(conn)?),
+        ("posts", posts::table.count().get_result(conn)?),
+        ("api_tokens", api_tokens::table.count().get_result(conn)?),
+        ("post_slugs", post_slugs::table.count().get_result(conn)?),
+        ("post_tags", post_tags::table.count().get_result(conn)?),
+        (
+            "post_sources",
+            post_sources::table.count().get_result(conn)?,
+        ),
+        ("audit_log", audit_log::table.count().get_result(conn)?),
+    ];
+    match counts.iter().find(|(_, rows)| *rows > 0) {
+        Some((table, rows)) => Err(BackupError::NotEmpty { table, rows: *rows }),
+        None => Ok(()),
+    }
+}
+
+/// Loads an archive written by `backup` into an empty database at the same
+/// migration, in a single transaction. Returns the rows loaded per table.
+/// The archive's audit log comes back with the rows, so nothing new is
+/// recorded.
+pub fn restore<R: BufRead>(
+    conn: &DbConnection,
+    input: R,
+) -> Result<Vec<(&'static str, usize)>, BackupError> {
+    let mut archive = ArchiveReader::new(input);
+    let version = archive.read_header()?;
+    let chunk_size = BATCH_SIZE as usize;
+
+    crate::metrics::track("restore", || {
+        conn.transaction(|| {
+            let database = migration_version(conn)?;
+            if database.as_deref() != Some(version.as_str()) {
+                return Err(BackupError::MigrationMismatch {
+                    archive: version.clone(),
+                    database,
+                });
+            }
+            ensure_empty(conn)?;
+
+            let restored = vec![
+                (
+                    "users",
+                    archive.read_section("users", chunk_size, |rows: &[User]| {
+                        diesel::insert_into(users::table).values(rows).execute(conn)
+                    })?,
+                ),
+                (
+                    "posts",
+                    archive.read_section("posts", chunk_size, |rows: &[Post]| {
+                        diesel::insert_into(posts::table).values(rows).execute(conn)
+                    })?,
+                ),
+                (
+                    "api_tokens",
+                    archive.read_section("api_tokens", chunk_size, |rows: &[ApiToken]| {
+                        diesel::insert_into(api_tokens::table)
+                            .values(rows)
+                            .execute(conn)
+                    })?,
+                ),
+                (
+                    "post_slugs",
+                    archive.read_section("post_slugs", chunk_size, |rows: &[PostSlug]| {
+                        diesel::insert_into(post_slugs::table)
+                            .values(rows)
+                            .execute(conn)
+                    })?,
+                ),
+                (
+                    "post_tags",
+                    archive.read_section("post_tags", chunk_size, |rows: &[PostTag]| {
+                        diesel::insert_into(post_tags::table)
+                            .values(rows)
+                            .execute(conn)
+                    })?,
+                ),
+                (
+                    "post_sources",
+                    archive.read_section("post_sources", chunk_size, |rows: &[PostSource]| {
+                        diesel::insert_into(post_sources::table)
+                            .values(rows)
+                            .execute(conn)
+                    })?,
+                ),
+                (
+                    "audit_log",
+                    archive.read_section("audit_log", chunk_size, |rows: &[AuditEntry]| {
+                        diesel::insert_into(audit_log::table)
+                            .values(rows)
+                            .execute(conn)
+                    })?,
+                ),
+            ];
+            archive.read_footer()?;
+            Ok(restored)
+        })
+    })
+}
+
+#[cfg(test)]
+fn archive_of(tags: &[(i32, &str)]) -> Vec<u8> {
+    let rows = tags
+        .iter()
+        .map(|(post_id, tag)| PostTag {
+            post_id: *post_id,
+            tag: tag.to_string(),
+        })
+        .collect::<Vec<_>>();
+    let mut out = Vec::new();
+    writeln!(out, "{}\n@migration 20201107101500\n@created now", MAGIC).unwrap();
+    write_section(
+        &mut out,
+        "post_tags",
+        (i32::MIN, String::new()),
+        |row: &PostTag| (row.post_id, row.tag.clone()),
+        |(post_id, tag), limit| {
+            Ok(rows
+                .iter()
+                .filter(|row| (row.post_id, &row.tag) > (*post_id, tag))
+                .take(limit as usize)
+                .map(|row| PostTag {
+                    post_id: row.post_id,
+                    tag: row.tag.clone(),
+                })
+                .collect())
+        },
+    )
+    .unwrap();
+    writeln!(out, "@done").unwrap();
+    out
+}
+
+#[test]
+fn sections_round_trip() {
+    let archive = archive_of(&[(1, "diesel"), (1, "rust"), (2, "rust")]);
+    let mut reader = ArchiveReader::new(archive.as_slice());
+    let mut loaded = Vec::new();
+
+    assert_eq!("20201107101500", reader.read_header().unwrap());
+    let rows = reader
+        .read_section("post_tags", 2, |rows: &[PostTag]| {
+            loaded.extend(rows.iter().map(|row| (row.post_id, row.tag.clone())));
+            Ok(rows.len())
+        })
+        .unwrap();
+    reader.read_footer().unwrap();
+
+    assert_eq!(3, rows);
+    assert_eq!(
+        vec![
+            (1, "diesel".to_string()),
+            (1, "rust".to_string()),
+            (2, "rust".to_string())
+        ],
+        loaded
+    );
+}
+
+#[test]
+fn tampered_rows_fail_the_checksum() {
+    let archive = String::from_utf8(archive_of(&[(1, "rust")])).unwrap();
+    let tampered = archive.replace("\"rust\"", "\"rusty\"");
+    let mut reader = ArchiveReader::new(tampered.as_bytes());
+
+    reader.read_header().unwrap();
+    match reader.read_section("post_tags", 10, |rows: &[PostTag]| Ok(rows.len())) {
+        Err(BackupError::Checksum { table }) => assert_eq!("post_tags", table),
+        other => panic!("expected Checksum, got {:?}", other),
+    }
+}
+
+#[test]
+fn truncated_archives_are_rejected() {
+    let archive = String::from_utf8(archive_of(&[(1, "rust")])).unwrap();
+    let truncated = archive.trim_end_matches("@done\n");
+    let mut reader = ArchiveReader::new(truncated.as_bytes());
+
+    reader.read_header().unwrap();
+    reader
+        .read_section("post_tags", 10, |rows: &[PostTag]| Ok(rows.len()))
+        .unwrap();
+    match reader.read_footer() {
+        Err(BackupError::Format { message, .. }) => assert_eq!("archive ends early", message),
+        other => panic!("expected Format, got {:?}", other),
+    }
+}
+
+#[test]
+fn other_files_are_not_archives() {
+    match ArchiveReader::new("{\"id\":1}\n".as_bytes()).read_header() {
+        Err(BackupError::Format { line, .. }) => assert_eq!(1, line),
+        other => panic!("expected Format, got {:?}", other),
+    }
+}
+
+#[test]
+fn examine_sql_from_migration_version() {
+    use diesel::debug_query;
+    use diesel::mysql::Mysql;
+
+    let query = __diesel_schema_migrations::table.select(max(__diesel_schema_migrations::version));
+    let sql = "SELECT max(`__diesel_schema_migrations`.`version`) \
+               FROM `__diesel_schema_migrations` -- binds: []";
+    assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
+}
@@ -4,6 +4,7 @@ extern crate diesel_demo;
 use self::diesel_demo::*;
 
 fn main() {
+    env_logger::init();
     let connect = establish_connection();
     let result = insert_default_values(&connect);
 
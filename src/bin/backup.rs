@@ -0,0 +1,23 @@
+use diesel_demo::backup::backup;
+use diesel_demo::*;
+use std::env::args;
+use std::fs::File;
+use std::io::BufWriter;
+
+fn main() {
+    env_logger::init();
+    let path = args().nth(1).expect("backup requires an archive path");
+    let file = File::create(&path).unwrap_or_else(|e| panic!("Unable to create {}: {}", path, e));
+
+    let connection = establish_connection();
+    let summaries = backup(&connection, BufWriter::new(file))
+        .unwrap_or_else(|e| panic!("Backup failed: {}", e));
+
+    for summary in &summaries {
+        println!(
+            "{:>14} {:>8} rows  {}",
+            summary.table, summary.rows, summary.sha256
+        );
+    }
+    println!("Wrote {}", path);
+}
@@ -0,0 +1,21 @@
+use chrono::{Duration, Utc};
+use diesel_demo::tokens::cleanup_tokens;
+use diesel_demo::*;
+use std::env::args;
+
+fn main() {
+    env_logger::init();
+    let days = args()
+        .nth(1)
+        .map(|days| days.parse::<i64>().expect("Invalid number of days"))
+        .unwrap_or(0);
+    let older_than = Utc::now().naive_utc() - Duration::days(days);
+
+    let connection = establish_connection();
+    let removed = cleanup_tokens(&connection, older_than).expect("Error cleaning up tokens");
+
+    println!(
+        "Removed {} tokens expired or revoked before {}",
+        removed, older_than
+    );
+}
@@ -1,21 +1,46 @@
-use diesel::prelude::*;
-
+use diesel_demo::guard::{confirm, Guard, Outcome};
+use diesel_demo::publishing::delete_posts_matching;
+use diesel_demo::tokens::user_from_env;
 use diesel_demo::*;
 use std::env::args;
+
 fn main() {
-    use schema::posts::dsl::*;
+    env_logger::init();
     let target = args().nth(1).expect("Expected a target to match against");
-    let pattern = format!("%{}%", target);
+    let max_rows = args()
+        .nth(2)
+        .map(|max| max.parse::<usize>().expect("Invalid row limit"))
+        .unwrap_or(10);
     let connection = establish_connection();
+    let actor = user_from_env(&connection)
+        .unwrap_or_else(|e| panic!("Log in first (cargo run --bin login): {}", e));
 
-    let num_deleted = diesel::delete(posts.filter(title.like(pattern)))
-        .execute(&connection)
-        .expect("Error deleting posts");
+    let preview = delete_posts_matching(&connection, &actor, &target, &Guard::dry_run(max_rows))
+        .unwrap_or_else(|e| panic!("Error previewing delete: {}", e));
+    let rows = match preview {
+        Outcome::Preview { rows, sql } => {
+            println!("{}", sql);
+            rows
+        }
+        Outcome::Executed { .. } => unreachable!("a dry run never executes"),
+    };
 
-    println!("Deleted {} posts", num_deleted);
+    if rows.is_empty() {
+        println!("No posts match {:?}", target);
+        return;
+    }
+    for post in &rows {
+        println!("  [{}] {}", post.id, post.title);
+    }
+    if !confirm(&format!("Delete {} posts?", rows.len())) {
+        println!("Nothing deleted");
+        return;
+    }
 
-    let num_deleted = diesel::delete(posts.filter(id.eq(1)))
-        .execute(&connection)
-        .expect("Error deleting post[id=1]");
-    println!("Deleted post which id = 1 , is Ok : {}", num_deleted == 1);
+    // Cap at what was previewed so rows matching since then are not touched.
+    let outcome = delete_posts_matching(&connection, &actor, &target, &Guard::execute(rows.len()))
+        .unwrap_or_else(|e| panic!("Error deleting posts: {}", e));
+    if let Outcome::Executed { affected, .. } = outcome {
+        println!("Deleted {} posts (run restore_post to undo)", affected);
+    }
 }
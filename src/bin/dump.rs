@@ -0,0 +1,20 @@
+use diesel_demo::ndjson::dump_dir;
+use diesel_demo::*;
+use std::env::args;
+use std::path::Path;
+
+fn main() {
+    env_logger::init();
+    let dir = args().nth(1).expect("dump requires an output directory");
+    // Hashes can be cracked offline, so they stay out unless asked for.
+    let password_hashes = args().any(|arg| arg == "--with-password-hashes");
+
+    let connection = establish_connection();
+    let (users, posts) = dump_dir(&connection, Path::new(&dir), password_hashes)
+        .unwrap_or_else(|e| panic!("Error dumping to {}: {}", dir, e));
+
+    println!("Dumped {} users and {} posts to {}", users, posts, dir);
+    if password_hashes {
+        println!("The dump includes password hashes; keep it private");
+    }
+}
@@ -0,0 +1,77 @@
+use diesel_demo::editing::{
+    draft_text, editor_command, is_stale, open_in_editor, parse_draft, save_draft, EditOutcome,
+};
+use diesel_demo::guard::confirm;
+use diesel_demo::publishing::find_post;
+use diesel_demo::tokens::user_from_env;
+use diesel_demo::*;
+use std::env::args;
+
+fn main() {
+    env_logger::init();
+    let id = args()
+        .nth(1)
+        .map(|id| id.parse::<i32>().expect("Invalid ID"));
+
+    let connection = establish_connection();
+    let actor = user_from_env(&connection)
+        .unwrap_or_else(|e| panic!("Log in first (cargo run --bin login): {}", e));
+    let mut original = id.map(|id| {
+        find_post(&connection, id).unwrap_or_else(|e| panic!("Unable to load post {}: {}", id, e))
+    });
+
+    let editor = editor_command();
+    let mut text = draft_text(original.as_ref());
+    loop {
+        text = open_in_editor(&editor, &text)
+            .unwrap_or_else(|e| panic!("Unable to run editor: {}", e));
+        let draft = match parse_draft(&text) {
+            Ok(draft) => draft,
+            Err(e) => {
+                eprintln!("Unable to save: {}", e);
+                if confirm("Edit again?") {
+                    continue;
+                }
+                break;
+            }
+        };
+
+        match save_draft(&connection, &actor, original.as_ref(), &draft) {
+            Ok(EditOutcome::Created(post)) => {
+                println!("Saved draft {} with id {}", post.title, post.id)
+            }
+            Ok(EditOutcome::Updated(post)) => {
+                println!("Updated {} (version {})", post.title, post.version)
+            }
+            Ok(EditOutcome::Unchanged) => println!("No changes"),
+            Err(e) if is_stale(&e) => {
+                eprintln!("Unable to save: {}", e);
+                // Saving again must be against the version that is there now,
+                // or it would fail the same way.
+                let post_id = id.expect("only existing posts can be stale");
+                let current = match find_post(&connection, post_id) {
+                    Ok(current) => current,
+                    Err(e) => {
+                        eprintln!("Unable to reload post {}: {}", post_id, e);
+                        break;
+                    }
+                };
+                eprintln!(
+                    "Your text is kept; saving it again replaces version {}",
+                    current.version
+                );
+                original = Some(current);
+                if confirm("Edit again?") {
+                    continue;
+                }
+            }
+            Err(e) => {
+                eprintln!("Unable to save: {}", e);
+                if confirm("Edit again?") {
+                    continue;
+                }
+            }
+        }
+        break;
+    }
+}
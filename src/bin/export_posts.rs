@@ -0,0 +1,25 @@
+use chrono::NaiveDateTime;
+use diesel_demo::exporter::export_posts;
+use diesel_demo::*;
+use std::env::args;
+use std::path::Path;
+
+fn main() {
+    env_logger::init();
+    let dir = args()
+        .nth(1)
+        .expect("export_posts requires an output directory");
+    let since = args().nth(2).map(|since| {
+        NaiveDateTime::parse_from_str(&since, "%Y-%m-%dT%H:%M:%S")
+            .expect("since must look like 2020-10-01T00:00:00")
+    });
+
+    let connection = establish_connection();
+    let report = export_posts(&connection, Path::new(&dir), since)
+        .unwrap_or_else(|e| panic!("Error exporting posts: {}", e));
+
+    println!(
+        "Wrote {} posts to {} ({} unchanged, {} renamed)",
+        report.written, dir, report.unchanged, report.renamed
+    );
+}
@@ -0,0 +1,31 @@
+use diesel_demo::feed::FeedConfig;
+use diesel_demo::static_site::{export_site, SiteOptions, Templates};
+use diesel_demo::*;
+use std::env::args;
+use std::path::Path;
+
+fn main() {
+    env_logger::init();
+    let out_dir = args()
+        .nth(1)
+        .expect("export_site requires an output directory");
+    let mut options = SiteOptions::default();
+    if let Some(templates) = args().nth(2) {
+        options.templates = Templates::load(Path::new(&templates))
+            .unwrap_or_else(|e| panic!("Error loading templates from {}: {}", templates, e));
+    }
+
+    let connection = establish_connection();
+    let summary = export_site(
+        &connection,
+        &FeedConfig::from_env(),
+        Path::new(&out_dir),
+        &options,
+    )
+    .unwrap_or_else(|e| panic!("Error exporting site: {}", e));
+
+    println!(
+        "Wrote {} posts and {} index pages to {}",
+        summary.posts, summary.index_pages, out_dir
+    );
+}
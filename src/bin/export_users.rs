@@ -0,0 +1,19 @@
+use diesel_demo::user_csv::export_users;
+use diesel_demo::*;
+use std::env::args;
+use std::fs::File;
+use std::io::{self, Write};
+
+fn main() {
+    env_logger::init();
+    let connection = establish_connection();
+    let output: Box<dyn Write> = match args().nth(1) {
+        Some(path) => Box::new(
+            File::create(&path).unwrap_or_else(|e| panic!("Unable to create {}: {}", path, e)),
+        ),
+        None => Box::new(io::stdout()),
+    };
+
+    let exported = export_users(&connection, output).expect("Error exporting users");
+    eprintln!("Exported {} users", exported);
+}
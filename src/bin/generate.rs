@@ -0,0 +1,43 @@
+use diesel_demo::generator::{generate, GenerateOptions};
+use diesel_demo::*;
+use std::env::args;
+use std::io::{self, Write};
+
+fn main() {
+    env_logger::init();
+    let defaults = GenerateOptions::default();
+    let arg = |n: usize, default: usize| {
+        args()
+            .nth(n)
+            .map(|arg| {
+                arg.replace('_', "")
+                    .parse()
+                    .expect("counts must be numbers")
+            })
+            .unwrap_or(default)
+    };
+    let options = GenerateOptions {
+        users: arg(1, defaults.users),
+        posts: arg(2, defaults.posts),
+        seed: arg(3, defaults.seed as usize) as u64,
+        ..defaults
+    };
+
+    let connection = establish_connection();
+    let actor = tokens::user_from_env(&connection).ok();
+    let result = generate(&connection, actor.as_ref(), &options, |progress| {
+        print!("\r{}: {}/{}", progress.table, progress.done, progress.total);
+        if progress.done == progress.total {
+            println!();
+        }
+        io::stdout().flush().ok();
+    });
+
+    match result {
+        Ok(report) => println!(
+            "Generated {} users and {} posts from seed {}",
+            report.users, report.posts, options.seed
+        ),
+        Err(e) => eprintln!("Generating failed: {}", e),
+    }
+}
@@ -0,0 +1,34 @@
+use diesel_demo::audit::history;
+use diesel_demo::*;
+use std::env::args;
+
+fn main() {
+    env_logger::init();
+    let table = args()
+        .nth(1)
+        .expect("history requires a table (users or posts)");
+    let id = args()
+        .nth(2)
+        .expect("history requires a row id")
+        .parse::<i32>()
+        .expect("Invalid ID");
+
+    let connection = establish_connection();
+    let entries = history(&connection, &table, id).expect("Error loading history");
+
+    if entries.is_empty() {
+        println!("No recorded changes to {} {}", table, id);
+    }
+    for entry in entries {
+        let actor = entry
+            .actor_id
+            .map_or_else(|| "nobody".to_string(), |id| format!("user {}", id));
+        println!(
+            "{}  {:<8} by {}: {}",
+            entry.created_at,
+            entry.action,
+            actor,
+            entry.changed_fields().join(", ")
+        );
+    }
+}
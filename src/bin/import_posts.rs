@@ -0,0 +1,32 @@
+use diesel_demo::importer::{import_dir, Change};
+use diesel_demo::tokens::user_from_env;
+use diesel_demo::*;
+use std::env::args;
+use std::path::Path;
+
+fn main() {
+    env_logger::init();
+    let dir = args()
+        .nth(1)
+        .expect("import_posts requires a directory of .md files");
+    let dry_run = args().any(|arg| arg == "--dry-run");
+
+    let connection = establish_connection();
+    let actor = user_from_env(&connection)
+        .unwrap_or_else(|e| panic!("Log in first (cargo run --bin login): {}", e));
+
+    let report = import_dir(&connection, &actor, Path::new(&dir), dry_run)
+        .unwrap_or_else(|e| panic!("Import failed, nothing was written: {}", e));
+    for (path, change) in &report.files {
+        if *change != Change::Unchanged {
+            println!("  {:?} {}", change, path);
+        }
+    }
+    println!(
+        "{}{} created, {} updated, {} unchanged",
+        if dry_run { "Dry run: " } else { "" },
+        report.created,
+        report.updated,
+        report.unchanged
+    );
+}
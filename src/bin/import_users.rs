@@ -0,0 +1,37 @@
+use diesel_demo::user_csv::{import_users, ColumnMapping, ImportMode};
+use diesel_demo::*;
+use std::env::args;
+use std::fs::File;
+
+fn main() {
+    env_logger::init();
+    let path = args().nth(1).expect("import_users requires a CSV file");
+    let mode = if args().any(|arg| arg == "--skip-bad-rows") {
+        ImportMode::SkipBadRows
+    } else {
+        ImportMode::AllOrNothing
+    };
+    let file = File::open(&path).unwrap_or_else(|e| panic!("Unable to open {}: {}", path, e));
+
+    let connection = establish_connection();
+    let actor = tokens::user_from_env(&connection).ok();
+    match import_users(
+        &connection,
+        actor.as_ref(),
+        file,
+        &ColumnMapping::default(),
+        mode,
+    ) {
+        Ok(report) => {
+            for error in &report.skipped {
+                eprintln!("Skipped {}", error);
+            }
+            println!(
+                "Imported {} users, skipped {}",
+                report.inserted,
+                report.skipped.len()
+            );
+        }
+        Err(e) => eprintln!("Import failed: {}", e),
+    }
+}
@@ -0,0 +1,18 @@
+use diesel_demo::ndjson::load_dir;
+use diesel_demo::*;
+use std::env::args;
+use std::path::Path;
+
+fn main() {
+    env_logger::init();
+    let dir = args()
+        .nth(1)
+        .expect("load requires a directory written by dump");
+
+    let connection = establish_connection();
+    let actor = tokens::user_from_env(&connection).ok();
+    let (users, posts) = load_dir(&connection, actor.as_ref(), Path::new(&dir))
+        .unwrap_or_else(|e| panic!("Load failed, nothing was written: {}", e));
+
+    println!("Loaded {} users and {} posts from {}", users, posts, dir);
+}
@@ -0,0 +1,22 @@
+use diesel_demo::auth::verify_credentials;
+use diesel_demo::tokens::issue_session;
+use diesel_demo::*;
+use std::env::args;
+use std::io::stdin;
+
+fn main() {
+    env_logger::init();
+    let email = args().nth(1).expect("login requires an email");
+
+    println!("Password:");
+    let mut password = String::new();
+    stdin().read_line(&mut password).unwrap();
+
+    let connection = establish_connection();
+    let user = verify_credentials(&connection, &email, password.trim_end())
+        .unwrap_or_else(|e| panic!("Unable to log in: {}", e));
+    let session = issue_session(&connection, user.id).expect("Error issuing session");
+
+    println!("Logged in as {} ({})", user.name, user.role);
+    println!("export API_TOKEN={}", session.token);
+}
@@ -1,24 +1,39 @@
 extern crate diesel;
 extern crate diesel_demo;
 
-use self::diesel::prelude::*;
 use self::diesel_demo::*;
+use diesel_demo::locking::UpdateError;
+use diesel_demo::publishing::{publish_post, PostError};
+use diesel_demo::slugs::find_by_slug;
+use diesel_demo::tokens::user_from_env;
 use std::env::args;
 
 fn main() {
-    use diesel_demo::schema::posts::dsl::{posts, published};
-    let id = args()
+    env_logger::init();
+    let target = args()
         .nth(1)
-        .expect("publish_post requires a post id")
-        .parse::<i32>()
-        .expect("Invalid ID");
+        .expect("publish_post requires a post id or slug");
 
     let connection = establish_connection();
+    let id = match target.parse::<i32>() {
+        Ok(id) => id,
+        Err(_) => {
+            find_by_slug(&connection, &target)
+                .expect("Error looking up slug")
+                .unwrap_or_else(|| panic!("No post with slug {:?}", target))
+                .post()
+                .id
+        }
+    };
+    let actor = user_from_env(&connection)
+        .unwrap_or_else(|e| panic!("Log in first (cargo run --bin login): {}", e));
 
-    let update_row = diesel::update(posts.find(id))
-        .set(published.eq(true))
-        .execute(&connection)
-        .expect(&format!("Unable to find post {}", id));
-
-    println!("update_row : {}", update_row);
+    match publish_post(&connection, &actor, id) {
+        Ok(post) => println!("Published post {} (version {})", post.title, post.version),
+        Err(PostError::Update(e @ UpdateError::StaleObject { .. })) => {
+            eprintln!("Conflict: {}", e)
+        }
+        Err(e @ PostError::Forbidden(_)) => eprintln!("Forbidden: {}", e),
+        Err(e) => panic!("Unable to publish post {}: {}", id, e),
+    }
 }
@@ -0,0 +1,50 @@
+use chrono::{Duration, Utc};
+use diesel_demo::guard::{confirm, Guard, Outcome};
+use diesel_demo::policy::purge_users;
+use diesel_demo::publishing::purge_posts;
+use diesel_demo::tokens::user_from_env;
+use diesel_demo::*;
+use std::env::args;
+
+fn main() {
+    env_logger::init();
+    let days = args()
+        .nth(1)
+        .expect("purge_deleted requires a number of days")
+        .parse::<i64>()
+        .expect("Invalid number of days");
+    let max_rows = args()
+        .nth(2)
+        .map(|max| max.parse::<usize>().expect("Invalid row limit"))
+        .unwrap_or(1000);
+    let older_than = Utc::now().naive_utc() - Duration::days(days);
+
+    let connection = establish_connection();
+    let actor = user_from_env(&connection)
+        .unwrap_or_else(|e| panic!("Log in first (cargo run --bin login): {}", e));
+    let posts = match purge_posts(&connection, &actor, older_than, &Guard::dry_run(max_rows)) {
+        Ok(Outcome::Preview { rows, .. }) => rows.len(),
+        Ok(Outcome::Executed { .. }) => unreachable!("a dry run never executes"),
+        Err(e) => panic!("Error previewing purge: {}", e),
+    };
+    let users = match purge_users(&connection, &actor, older_than, &Guard::dry_run(max_rows)) {
+        Ok(Outcome::Preview { rows, .. }) => rows.len(),
+        Ok(Outcome::Executed { .. }) => unreachable!("a dry run never executes"),
+        Err(e) => panic!("Error previewing purge: {}", e),
+    };
+
+    let prompt = format!(
+        "Permanently remove {} posts and {} users deleted before {}?",
+        posts, users, older_than
+    );
+    if posts + users == 0 || !confirm(&prompt) {
+        println!("Nothing purged");
+        return;
+    }
+
+    purge_posts(&connection, &actor, older_than, &Guard::execute(posts))
+        .unwrap_or_else(|e| panic!("Error purging posts: {}", e));
+    purge_users(&connection, &actor, older_than, &Guard::execute(users))
+        .unwrap_or_else(|e| panic!("Error purging users: {}", e));
+    println!("Purged {} posts and {} users", posts, users);
+}
@@ -0,0 +1,20 @@
+use diesel_demo::auth::register_user;
+use diesel_demo::*;
+use std::env::args;
+use std::io::stdin;
+
+fn main() {
+    env_logger::init();
+    let name = args().nth(1).expect("register requires a name");
+    let email = args().nth(2).expect("register requires an email");
+
+    println!("Choose a password:");
+    let mut password = String::new();
+    stdin().read_line(&mut password).unwrap();
+
+    let connection = establish_connection();
+    match register_user(&connection, &name, &email, password.trim_end()) {
+        Ok(user) => println!("Registered {} with id {}", name, user.id),
+        Err(e) => eprintln!("Unable to register {}: {}", email, e),
+    }
+}
@@ -0,0 +1,10 @@
+use diesel_demo::markdown::refresh_body_html;
+use diesel_demo::*;
+
+fn main() {
+    env_logger::init();
+    let connection = establish_connection();
+    let refreshed = refresh_body_html(&connection, 100).expect("Error rendering posts");
+
+    println!("Rendered {} posts", refreshed);
+}
@@ -0,0 +1,19 @@
+use diesel_demo::backup::restore;
+use diesel_demo::*;
+use std::env::args;
+use std::fs::File;
+use std::io::BufReader;
+
+fn main() {
+    env_logger::init();
+    let path = args().nth(1).expect("restore requires an archive path");
+    let file = File::open(&path).unwrap_or_else(|e| panic!("Unable to open {}: {}", path, e));
+
+    let connection = establish_connection();
+    let restored = restore(&connection, BufReader::new(file))
+        .unwrap_or_else(|e| panic!("Restore failed, nothing was written: {}", e));
+
+    for (table, rows) in &restored {
+        println!("{:>14} {:>8} rows", table, rows);
+    }
+}
@@ -0,0 +1,23 @@
+use diesel_demo::policy::restore_users;
+use diesel_demo::tokens::user_from_env;
+use diesel_demo::*;
+use std::env::args;
+
+fn main() {
+    env_logger::init();
+    let ids = args()
+        .skip(1)
+        .map(|arg| arg.parse::<i32>().expect("Invalid ID"))
+        .collect::<Vec<_>>();
+    if ids.is_empty() {
+        panic!("restore_user requires at least one user id");
+    }
+
+    let connection = establish_connection();
+    let actor = user_from_env(&connection)
+        .unwrap_or_else(|e| panic!("Log in first (cargo run --bin login): {}", e));
+    let restored = restore_users(&connection, &actor, &ids)
+        .unwrap_or_else(|e| panic!("Error restoring users: {}", e));
+
+    println!("Restored {} users", restored);
+}
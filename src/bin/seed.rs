@@ -0,0 +1,27 @@
+use diesel_demo::fixtures::{load, read_fixtures, Profile};
+use diesel_demo::*;
+use std::env::args;
+use std::path::Path;
+
+fn main() {
+    env_logger::init();
+    let name = args().nth(1).unwrap_or_else(|| "minimal".to_string());
+    let fixtures = match name.parse::<Profile>() {
+        Ok(profile) => profile.fixtures(),
+        Err(_) if Path::new(&name).is_file() => read_fixtures(Path::new(&name)),
+        Err(e) => panic!("{}", e),
+    }
+    .unwrap_or_else(|e| panic!("Unable to read fixtures {}: {}", name, e));
+
+    let connection = establish_connection();
+    let actor = tokens::user_from_env(&connection).ok();
+    match load(&connection, actor.as_ref(), &fixtures) {
+        Ok(seeded) => println!(
+            "Seeded {} users and {} posts from {}",
+            seeded.users.len(),
+            seeded.posts.len(),
+            name
+        ),
+        Err(e) => eprintln!("Seeding failed, nothing was written: {}", e),
+    }
+}
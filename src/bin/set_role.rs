@@ -0,0 +1,27 @@
+use diesel_demo::models::Role;
+use diesel_demo::policy::set_role;
+use diesel_demo::tokens::user_from_env;
+use diesel_demo::*;
+use std::env::args;
+
+fn main() {
+    env_logger::init();
+    let id = args()
+        .nth(1)
+        .expect("set_role requires a user id")
+        .parse::<i32>()
+        .expect("Invalid ID");
+    let role = args()
+        .nth(2)
+        .expect("set_role requires a role")
+        .parse::<Role>()
+        .unwrap_or_else(|e| panic!("{}", e));
+
+    let connection = establish_connection();
+    let actor = user_from_env(&connection)
+        .unwrap_or_else(|e| panic!("Log in first (cargo run --bin login): {}", e));
+    let updated = set_role(&connection, &actor, id, role)
+        .unwrap_or_else(|e| panic!("Error setting role: {}", e));
+
+    println!("Updated {} users to {}", updated, role);
+}
@@ -0,0 +1,19 @@
+use diesel_demo::slugs::find_by_slug;
+use diesel_demo::*;
+use std::env::args;
+
+fn main() {
+    env_logger::init();
+    let slug = args().nth(1).expect("show_post requires a slug");
+
+    let connection = establish_connection();
+    let lookup = find_by_slug(&connection, &slug)
+        .expect("Error looking up slug")
+        .unwrap_or_else(|| panic!("No post with slug {:?}", slug));
+
+    if let Some(current) = lookup.redirect_to() {
+        println!("{} has moved to {}\n", slug, current);
+    }
+    let post = lookup.post();
+    println!("{}\n\n{}", post.title, post.body);
+}
@@ -1,12 +1,14 @@
 use self::models::*;
 use diesel::prelude::*;
+use diesel_demo::soft_delete::active_posts;
 use diesel_demo::*;
 
 fn main() {
     use self::schema::posts::dsl::*;
 
+    env_logger::init();
     let connection = establish_connection();
-    let results = posts
+    let results = active_posts()
         .filter(published.eq(true))
         .limit(5)
         .load::<Post>(&connection)
@@ -14,6 +16,6 @@ fn main() {
 
     println!("Displaying {} posts", results.len());
     for post in results {
-        println!("{},{}", post.title, post.body);
+        println!("{} ({}),{}", post.title, post.slug, post.excerpt());
     }
 }
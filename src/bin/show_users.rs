@@ -1,6 +1,9 @@
+use diesel_demo::guard::{confirm, Guard, Outcome};
+use diesel_demo::tokens::user_from_env;
 use diesel_demo::*;
 
 fn main() {
+    env_logger::init();
     let users = some_users();
     for user in users {
         println!(
@@ -19,6 +22,25 @@ fn main() {
         );
     }
 
-    let delete_users_num = delete_all_users();
-    println!("delete_users_num : {}", delete_users_num);
+    let connection = establish_connection();
+    let actor = user_from_env(&connection)
+        .unwrap_or_else(|e| panic!("Log in first (cargo run --bin login): {}", e));
+    let preview = delete_all_users(&actor, &Guard::dry_run(100))
+        .unwrap_or_else(|e| panic!("Error previewing delete: {}", e));
+    let would_delete = match preview {
+        Outcome::Preview { rows, sql } => {
+            println!("{}", sql);
+            rows.len()
+        }
+        Outcome::Executed { .. } => unreachable!("a dry run never executes"),
+    };
+    if would_delete == 0 || !confirm(&format!("Delete all {} users?", would_delete)) {
+        return;
+    }
+
+    let outcome = delete_all_users(&actor, &Guard::execute(would_delete))
+        .unwrap_or_else(|e| panic!("Error deleting users: {}", e));
+    if let Outcome::Executed { affected, .. } = outcome {
+        println!("delete_users_num : {}", affected);
+    }
 }
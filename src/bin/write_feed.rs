@@ -0,0 +1,24 @@
+use diesel_demo::feed::{atom, published_entries, rss, FeedConfig, DEFAULT_FEED_LEN};
+use diesel_demo::*;
+use std::env::args;
+use std::fs;
+
+fn main() {
+    env_logger::init();
+    let format = args()
+        .nth(1)
+        .expect("write_feed requires a format (rss or atom)");
+    let path = args().nth(2).expect("write_feed requires an output path");
+
+    let connection = establish_connection();
+    let config = FeedConfig::from_env();
+    let entries = published_entries(&connection, DEFAULT_FEED_LEN).expect("Error loading posts");
+    let xml = match format.as_str() {
+        "rss" => rss(&config, &entries),
+        "atom" => atom(&config, &entries),
+        other => panic!("Unknown feed format {:?}", other),
+    };
+
+    fs::write(&path, xml).unwrap_or_else(|e| panic!("Error writing {}: {}", path, e));
+    println!("Wrote {} posts to {}", entries.len(), path);
+}
@@ -2,10 +2,14 @@ extern crate diesel;
 extern crate diesel_demo;
 
 use self::diesel_demo::*;
+use diesel_demo::tokens::user_from_env;
 use std::io::{stdin, Read};
 
 fn main() {
+    env_logger::init();
     let connection = establish_connection();
+    let author = user_from_env(&connection)
+        .unwrap_or_else(|e| panic!("Log in first (cargo run --bin login): {}", e));
 
     println!("What would you like your title to be?");
     let mut title = String::new();
@@ -18,8 +22,10 @@ fn main() {
     let mut body = String::new();
     stdin().read_to_string(&mut body).unwrap();
 
-    let post = create_post(&connection, title, &body);
-    println!("\nSaved draft {} with id {}", title, post.id);
+    match create_post(&connection, &author, title, &body) {
+        Ok(post) => println!("\nSaved draft {} with id {}", title, post.id),
+        Err(e) => eprintln!("\nUnable to save draft: {}", e),
+    }
 }
 
 #[cfg(not(windows))]
@@ -0,0 +1,153 @@
+use crate::locking::UpdateError;
+use crate::models::{Post, PostChanges};
+use crate::publishing::{edit_post, PostError};
+use crate::{DbConnection, User};
+use diesel::prelude::*;
+
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::process::Command;
+
+const TITLE_HEADER: &str = "Title:";
+
+/// A post as the writer left it in their editor.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Draft {
+    pub title: String,
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub enum EditOutcome {
+    Created(Post),
+    Updated(Post),
+    /// The draft was saved without changes; nothing was written.
+    Unchanged,
+}
+
+/// The text a post is edited as: a `Title:` line, a blank line, the body.
+pub fn draft_text(post: Option<&Post>) -> String {
+    match post {
+        Some(post) => format!("{} {}\n\n{}\n", TITLE_HEADER, post.title, post.body),
+        None => format!("{} \n\n", TITLE_HEADER),
+    }
+}
+
+pub fn parse_draft(text: &str) -> Result<Draft, String> {
+    let mut lines = text.splitn(2, '\n');
+    let header = lines.next().unwrap_or_default();
+    let title = header
+        .strip_prefix(TITLE_HEADER)
+        .ok_or_else(|| format!("the first line must start with {:?}", TITLE_HEADER))?
+        .trim();
+    let body = lines.next().unwrap_or_default();
+
+    Ok(Draft {
+        title: title.to_string(),
+        body: body.trim().to_string(),
+    })
+}
+
+/// The editor to run: `$VISUAL`, then `$EDITOR`, then `vi`. May include
+/// arguments, e.g. `code --wait`.
+pub fn editor_command() -> Vec<String> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    editor.split_whitespace().map(String::from).collect()
+}
+
+/// Lets the user edit `text` through a temporary file and returns what they
+/// saved. `editor` is the program and its arguments; see `editor_command`.
+pub fn open_in_editor(editor: &[String], text: &str) -> io::Result<String> {
+    let mut file = tempfile::Builder::new()
+        .prefix("diesel-demo-")
+        .suffix(".md")
+        .tempfile()?;
+    file.write_all(text.as_bytes())?;
+    file.flush()?;
+
+    let (program, args) = editor
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "$EDITOR is empty"))?;
+    let status = Command::new(program).args(args).arg(file.path()).status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "{} exited with {}",
+            program, status
+        )));
+    }
+
+    fs::read_to_string(file.path())
+}
+
+/// Saves `draft` as a new post, or as changes to `original`, which must be
+/// the post as it was read before editing so concurrent edits are caught.
+/// See `is_stale` for telling those apart from other errors.
+pub fn save_draft(
+    conn: &DbConnection,
+    actor: &User,
+    original: Option<&Post>,
+    draft: &Draft,
+) -> Result<EditOutcome, PostError> {
+    match original {
+        None if draft.title.is_empty() && draft.body.is_empty() => Ok(EditOutcome::Unchanged),
+        None => conn
+            .transaction(|| crate::create_post(conn, actor, &draft.title, &draft.body))
+            .map(EditOutcome::Created),
+        Some(post) => {
+            let changes = PostChanges {
+                title: Some(draft.title.as_str()).filter(|title| *title != post.title),
+                body: Some(draft.body.as_str()).filter(|body| *body != post.body.trim()),
+                published: None,
+            };
+            if changes.title.is_none() && changes.body.is_none() {
+                return Ok(EditOutcome::Unchanged);
+            }
+            edit_post(conn, actor, post.id, post.version, &changes).map(EditOutcome::Updated)
+        }
+    }
+}
+
+/// Whether saving failed because the post changed after it was read; load it
+/// again and save the draft against the new version to overwrite the change.
+pub fn is_stale(error: &PostError) -> bool {
+    matches!(error, PostError::Update(UpdateError::StaleObject { .. }))
+}
+
+#[test]
+fn drafts_round_trip() {
+    let text = "Title:  Hello, editor \n\nFirst line\n\nTitle: not a header\n\n";
+
+    assert_eq!(
+        Draft {
+            title: "Hello, editor".into(),
+            body: "First line\n\nTitle: not a header".into(),
+        },
+        parse_draft(text).unwrap()
+    );
+    assert_eq!(
+        Draft {
+            title: String::new(),
+            body: String::new(),
+        },
+        parse_draft(&draft_text(None)).unwrap()
+    );
+}
+
+#[test]
+fn drafts_need_a_title_header() {
+    assert!(parse_draft("Hello\n\nBody").is_err());
+}
+
+#[test]
+fn editor_runs_on_a_temp_file() {
+    // `true` leaves the file as it is, like quitting without saving.
+    let editor = vec!["true".to_string()];
+
+    assert_eq!(
+        "Title: Same\n\nBody\n",
+        open_in_editor(&editor, "Title: Same\n\nBody\n").unwrap()
+    );
+}
@@ -0,0 +1,255 @@
+use crate::importer::parse_source;
+use crate::models::Post;
+use crate::schema::{post_tags, posts};
+use crate::soft_delete::active_posts;
+use crate::DbConnection;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde_derive::Serialize;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Same format the importer reads.
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+#[derive(Serialize)]
+struct FrontMatter<'a> {
+    id: i32,
+    title: &'a str,
+    slug: &'a str,
+    tags: &'a [String],
+    published: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date: Option<String>,
+    created_at: String,
+    updated_at: String,
+}
+
+#[derive(Default, PartialEq, Debug)]
+pub struct ExportReport {
+    pub written: usize,
+    /// Files that already had the exported contents and were left alone.
+    pub unchanged: usize,
+    /// Files of renamed posts removed in favour of their new name.
+    pub renamed: usize,
+}
+
+/// `<id>-<slug>.md`; the id prefix keeps names unique and sorted by age,
+/// and identifies the file after the post is renamed.
+pub fn file_name(post: &Post) -> String {
+    format!("{:06}-{}.md", post.id, post.slug)
+}
+
+fn timestamp(time: NaiveDateTime) -> String {
+    time.format(TIMESTAMP_FORMAT).to_string()
+}
+
+/// The post as Markdown with YAML front matter, readable by the importer.
+pub fn render_source(post: &Post, tags: &[String]) -> String {
+    let front = FrontMatter {
+        id: post.id,
+        title: &post.title,
+        slug: &post.slug,
+        tags,
+        published: post.published,
+        date: post.published_at.map(timestamp),
+        created_at: timestamp(post.created_at),
+        updated_at: timestamp(post.updated_at),
+    };
+    let yaml = serde_yaml::to_string(&front).expect("front matter always serializes");
+
+    format!("---\n{}---\n\n{}\n", yaml, post.body)
+}
+
+/// The post id in a name `file_name` would give, e.g. not the year in
+/// `2020-10-01-notes.md`.
+fn exported_id(name: &str) -> Option<i32> {
+    let (prefix, rest) = name.split_once('-')?;
+    let id = prefix.parse::<i32>().ok()?;
+    let slug = rest.strip_suffix(".md")?;
+    Some(id).filter(|id| format!("{:06}", id) == prefix && !slug.is_empty())
+}
+
+/// Whether the file at `path` has the front matter id `id`.
+fn exported_from(path: &Path, id: i32) -> bool {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| parse_source(&path.to_string_lossy(), &contents).ok())
+        .is_some_and(|source| source.id == Some(id))
+}
+
+/// Writes each post to `out_dir`, skipping files whose contents would not
+/// change and removing files left behind by renamed posts.
+pub fn write_sources(posts: &[(Post, Vec<String>)], out_dir: &Path) -> io::Result<ExportReport> {
+    fs::create_dir_all(out_dir)?;
+    let mut by_id = HashMap::new();
+    for entry in fs::read_dir(out_dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if let Some(id) = exported_id(&name) {
+            by_id.entry(id).or_insert_with(Vec::new).push(name);
+        }
+    }
+
+    let mut report = ExportReport::default();
+    for (post, tags) in posts {
+        let name = file_name(post);
+        for stale in by_id.get(&post.id).into_iter().flatten() {
+            if *stale != name && exported_from(&out_dir.join(stale), post.id) {
+                fs::remove_file(out_dir.join(stale))?;
+                report.renamed += 1;
+            }
+        }
+
+        let path = out_dir.join(&name);
+        let contents = render_source(post, tags);
+        if fs::read_to_string(&path).ok().as_deref() == Some(contents.as_str()) {
+            report.unchanged += 1;
+        } else {
+            fs::write(&path, contents)?;
+            report.written += 1;
+        }
+    }
+    Ok(report)
+}
+
+/// Exports every post that is not deleted, or only those changed at or
+/// after `since`.
+pub fn export_posts(
+    conn: &DbConnection,
+    out_dir: &Path,
+    since: Option<NaiveDateTime>,
+) -> Result<ExportReport, Box<dyn Error>> {
+    let posts = crate::metrics::track("export_posts", || {
+        let mut query = active_posts().order(posts::id).into_boxed();
+        if let Some(since) = since {
+            query = query.filter(posts::updated_at.ge(since));
+        }
+        let posts = query.load::<Post>(conn)?;
+
+        let ids = posts.iter().map(|post| post.id).collect::<Vec<_>>();
+        let mut tags = HashMap::<i32, Vec<String>>::new();
+        for (post_id, tag) in post_tags::table
+            .filter(post_tags::post_id.eq_any(&ids))
+            .order((post_tags::post_id, post_tags::tag))
+            .load::<(i32, String)>(conn)?
+        {
+            tags.entry(post_id).or_default().push(tag);
+        }
+
+        Ok::<_, diesel::result::Error>(
+            posts
+                .into_iter()
+                .map(|post| {
+                    let post_tags = tags.remove(&post.id).unwrap_or_default();
+                    (post, post_tags)
+                })
+                .collect::<Vec<_>>(),
+        )
+    })?;
+
+    Ok(write_sources(&posts, out_dir)?)
+}
+
+#[cfg(test)]
+fn post(id: i32, slug: &str) -> Post {
+    let created = chrono::NaiveDate::from_ymd(2020, 10, 1).and_hms(9, 30, 0);
+    Post {
+        id,
+        title: "Hello: a \"quoted\" title".into(),
+        body: "# Hi\n\nThere".into(),
+        published: true,
+        deleted_at: None,
+        version: 3,
+        author_id: None,
+        slug: slug.into(),
+        body_html: None,
+        created_at: created,
+        updated_at: created,
+        published_at: Some(created),
+    }
+}
+
+#[test]
+fn exported_posts_import_unchanged() {
+    let tags = vec!["diesel".to_string(), "rust".to_string()];
+    let exported = render_source(&post(7, "hello"), &tags);
+    let source = parse_source("000007-hello.md", &exported).unwrap();
+
+    assert_eq!(Some(7), source.id);
+    assert_eq!("Hello: a \"quoted\" title", source.title);
+    assert_eq!("hello", source.slug);
+    assert_eq!(tags, source.tags);
+    assert!(source.published);
+    assert_eq!(post(7, "hello").published_at, source.date);
+    assert_eq!("# Hi\n\nThere", source.body);
+}
+
+#[test]
+fn body_whitespace_survives_a_round_trip() {
+    let mut post = post(7, "hello");
+    post.body = "    let indented = true;\n\nTrailing newline\n".into();
+    let exported = render_source(&post, &[]);
+    let source = parse_source("000007-hello.md", &exported).unwrap();
+
+    assert_eq!(post.body, source.body);
+}
+
+#[test]
+fn file_names_are_stable_across_renames() {
+    let dir = tempfile::tempdir().unwrap();
+    let posts = vec![(post(7, "hello"), vec![])];
+
+    let first = write_sources(&posts, dir.path()).unwrap();
+    assert_eq!(1, first.written);
+    assert!(dir.path().join("000007-hello.md").exists());
+
+    let again = write_sources(&posts, dir.path()).unwrap();
+    assert_eq!(1, again.unchanged);
+
+    let renamed = write_sources(&[(post(7, "goodbye"), vec![])], dir.path()).unwrap();
+    assert_eq!(1, renamed.renamed);
+    assert!(!dir.path().join("000007-hello.md").exists());
+    assert!(dir.path().join("000007-goodbye.md").exists());
+}
+
+#[test]
+fn only_exported_files_are_treated_as_stale() {
+    let dir = tempfile::tempdir().unwrap();
+    let notes = "---\ntitle: Notes\n---\nMine";
+    for name in &["2020-10-01-notes.md", "2020-notes.md", "000007-notes.md"] {
+        fs::write(dir.path().join(name), notes).unwrap();
+    }
+
+    let report = write_sources(
+        &[(post(2020, "hello"), vec![]), (post(7, "hello"), vec![])],
+        dir.path(),
+    )
+    .unwrap();
+    assert_eq!(0, report.renamed);
+    for name in &["2020-10-01-notes.md", "2020-notes.md", "000007-notes.md"] {
+        assert!(dir.path().join(name).exists(), "{} was removed", name);
+    }
+    assert_eq!(Some(7), exported_id("000007-hello.md"));
+    assert_eq!(Some(1234567), exported_id("1234567-hello.md"));
+    assert_eq!(None, exported_id("0000007-hello.md"));
+}
+
+#[test]
+fn examine_sql_from_export_posts_since() {
+    use diesel::debug_query;
+    use diesel::mysql::Mysql;
+
+    let since = chrono::NaiveDate::from_ymd(2020, 10, 1).and_hms(0, 0, 0);
+    let query = active_posts()
+        .order(posts::id)
+        .filter(posts::updated_at.ge(since))
+        .select(posts::id);
+    let sql = "SELECT `posts`.`id` FROM `posts` \
+               WHERE `posts`.`deleted_at` IS NULL AND `posts`.`updated_at` >= ? \
+               ORDER BY `posts`.`id` -- binds: [2020-10-01T00:00:00]";
+    assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
+}
@@ -0,0 +1,345 @@
+use crate::models::Post;
+use crate::schema::{posts, users};
+use crate::soft_delete::active_posts;
+use crate::DbConnection;
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use diesel::prelude::*;
+
+use std::env;
+use std::fmt::Write;
+
+/// Posts included in a feed unless asked otherwise.
+pub const DEFAULT_FEED_LEN: i64 = 20;
+
+/// Describes the site a feed belongs to.
+#[derive(Clone, Debug)]
+pub struct FeedConfig {
+    pub title: String,
+    /// Base URL of the site, e.g. `https://blog.example.com`.
+    pub site_url: String,
+    pub description: String,
+    /// Used by Atom when a post has no author.
+    pub author: String,
+}
+
+impl FeedConfig {
+    /// Reads `SITE_TITLE`, `SITE_URL`, `SITE_DESCRIPTION` and `SITE_AUTHOR`.
+    pub fn from_env() -> Self {
+        dotenv::dotenv().ok();
+
+        let var = |name, default: &str| env::var(name).unwrap_or_else(|_| default.to_string());
+        FeedConfig {
+            title: var("SITE_TITLE", "diesel-demo"),
+            site_url: var("SITE_URL", "http://localhost:8000"),
+            description: var("SITE_DESCRIPTION", "Posts written with diesel-demo"),
+            author: var("SITE_AUTHOR", "diesel-demo"),
+        }
+    }
+
+    fn base_url(&self) -> &str {
+        self.site_url.trim_end_matches('/')
+    }
+
+    /// Where readers find the post; follows the slug, so it may change.
+    pub fn permalink(&self, post: &Post) -> String {
+        format!("{}/posts/{}", self.base_url(), post.slug)
+    }
+
+    /// A stable identifier for the post, unaffected by renames.
+    pub fn entry_id(&self, post: &Post) -> String {
+        format!("{}/posts/{}", self.base_url(), post.id)
+    }
+}
+
+#[derive(Debug)]
+pub struct FeedEntry {
+    pub post: Post,
+    pub author: Option<String>,
+}
+
+impl FeedEntry {
+    fn published_at(&self) -> NaiveDateTime {
+        self.post.published_at.unwrap_or(self.post.created_at)
+    }
+}
+
+/// The most recently published posts with the names of their authors.
+/// Posts by soft deleted users show no author.
+pub fn published_entries(conn: &DbConnection, limit: i64) -> QueryResult<Vec<FeedEntry>> {
+    let rows = active_posts()
+        .left_join(
+            users::table.on(posts::author_id
+                .eq(users::id.nullable())
+                .and(users::deleted_at.is_null())),
+        )
+        .filter(posts::published.eq(true))
+        .order((posts::published_at.desc(), posts::id.desc()))
+        .limit(limit)
+        .select((posts::all_columns, users::name.nullable()))
+        .load::<(Post, Option<String>)>(conn)?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(post, author)| FeedEntry { post, author })
+        .collect())
+}
+
+/// Escapes text for XML and HTML, in element content and quoted attributes.
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Stored timestamps are UTC; see `UTC_SESSION` in lib.rs.
+fn utc(time: NaiveDateTime) -> DateTime<Utc> {
+    DateTime::from_utc(time, Utc)
+}
+
+fn rfc2822(time: NaiveDateTime) -> String {
+    utc(time).to_rfc2822()
+}
+
+fn rfc3339(time: NaiveDateTime) -> String {
+    utc(time).to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// The feed was last updated when its newest entry was.
+fn last_updated(entries: &[FeedEntry]) -> Option<NaiveDateTime> {
+    entries.iter().map(|e| e.post.updated_at).max()
+}
+
+pub fn rss(config: &FeedConfig, entries: &[FeedEntry]) -> String {
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(
+        "<rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\" \
+         xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n<channel>\n",
+    );
+    let _ = writeln!(xml, "<title>{}</title>", escape(&config.title));
+    let _ = writeln!(xml, "<link>{}</link>", escape(config.base_url()));
+    let _ = writeln!(
+        xml,
+        "<description>{}</description>",
+        escape(&config.description)
+    );
+    let _ = writeln!(
+        xml,
+        "<atom:link href=\"{}/feed.rss\" rel=\"self\" type=\"application/rss+xml\"/>",
+        escape(config.base_url())
+    );
+    if let Some(updated) = last_updated(entries) {
+        let _ = writeln!(xml, "<lastBuildDate>{}</lastBuildDate>", rfc2822(updated));
+    }
+
+    for entry in entries {
+        let post = &entry.post;
+        xml.push_str("<item>\n");
+        let _ = writeln!(xml, "<title>{}</title>", escape(&post.title));
+        let _ = writeln!(xml, "<link>{}</link>", escape(&config.permalink(post)));
+        let _ = writeln!(
+            xml,
+            "<guid isPermaLink=\"false\">{}</guid>",
+            escape(&config.entry_id(post))
+        );
+        let _ = writeln!(xml, "<pubDate>{}</pubDate>", rfc2822(entry.published_at()));
+        if let Some(author) = &entry.author {
+            let _ = writeln!(xml, "<dc:creator>{}</dc:creator>", escape(author));
+        }
+        let _ = writeln!(xml, "<description>{}</description>", escape(&post.html()));
+        xml.push_str("</item>\n");
+    }
+
+    xml.push_str("</channel>\n</rss>\n");
+    xml
+}
+
+pub fn atom(config: &FeedConfig, entries: &[FeedEntry]) -> String {
+    let updated = last_updated(entries).unwrap_or_else(|| chrono::Utc::now().naive_utc());
+
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    let _ = writeln!(xml, "<id>{}/</id>", escape(config.base_url()));
+    let _ = writeln!(xml, "<title>{}</title>", escape(&config.title));
+    let _ = writeln!(xml, "<subtitle>{}</subtitle>", escape(&config.description));
+    let _ = writeln!(xml, "<updated>{}</updated>", rfc3339(updated));
+    let _ = writeln!(xml, "<link href=\"{}/\"/>", escape(config.base_url()));
+    let _ = writeln!(
+        xml,
+        "<link href=\"{}/feed.atom\" rel=\"self\"/>",
+        escape(config.base_url())
+    );
+    let _ = writeln!(
+        xml,
+        "<author><name>{}</name></author>",
+        escape(&config.author)
+    );
+
+    for entry in entries {
+        let post = &entry.post;
+        xml.push_str("<entry>\n");
+        let _ = writeln!(xml, "<id>{}</id>", escape(&config.entry_id(post)));
+        let _ = writeln!(xml, "<title>{}</title>", escape(&post.title));
+        let _ = writeln!(xml, "<link href=\"{}\"/>", escape(&config.permalink(post)));
+        let _ = writeln!(
+            xml,
+            "<published>{}</published>",
+            rfc3339(entry.published_at())
+        );
+        let _ = writeln!(xml, "<updated>{}</updated>", rfc3339(post.updated_at));
+        if let Some(author) = &entry.author {
+            let _ = writeln!(xml, "<author><name>{}</name></author>", escape(author));
+        }
+        let _ = writeln!(xml, "<summary>{}</summary>", escape(&post.excerpt()));
+        let _ = writeln!(
+            xml,
+            "<content type=\"html\">{}</content>",
+            escape(&post.html())
+        );
+        xml.push_str("</entry>\n");
+    }
+
+    xml.push_str("</feed>\n");
+    xml
+}
+
+#[cfg(test)]
+fn config() -> FeedConfig {
+    FeedConfig {
+        title: "Rust & Diesel".into(),
+        site_url: "https://blog.example.com/".into(),
+        description: "Notes on <ORMs>".into(),
+        author: "Editors".into(),
+    }
+}
+
+#[cfg(test)]
+fn entry(id: i32, title: &str, author: Option<&str>) -> FeedEntry {
+    let created = chrono::NaiveDate::from_ymd(2020, 10, 1).and_hms(9, 30, 0);
+    FeedEntry {
+        post: Post {
+            id,
+            title: title.into(),
+            body: "Hello <script>alert(1)</script> *world*".into(),
+            published: true,
+            deleted_at: None,
+            version: 0,
+            author_id: None,
+            slug: crate::slugs::slugify(title),
+            body_html: None,
+            created_at: created,
+            updated_at: created + chrono::Duration::days(i64::from(id)),
+            published_at: Some(created + chrono::Duration::hours(1)),
+        },
+        author: author.map(String::from),
+    }
+}
+
+/// Parses `xml`, failing on anything not well formed, and returns the names
+/// of its elements in document order.
+#[cfg(test)]
+fn element_names(xml: &str) -> Vec<String> {
+    use quick_xml::events::Event;
+    use quick_xml::Reader;
+
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().check_end_names = true;
+    let mut names = Vec::new();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) => {
+                names.push(String::from_utf8(e.name().as_ref().to_vec()).unwrap())
+            }
+            Ok(Event::Text(t)) => {
+                t.unescape().expect("text must only use known entities");
+            }
+            Ok(Event::Eof) => return names,
+            Ok(_) => {}
+            Err(e) => panic!("invalid XML at {}: {}", reader.buffer_position(), e),
+        }
+    }
+}
+
+#[test]
+fn rss_is_well_formed() {
+    let entries = vec![
+        entry(1, "First", Some("Sean")),
+        entry(2, "Tom & Jerry", None),
+    ];
+    let xml = rss(&config(), &entries);
+    let names = element_names(&xml);
+
+    assert_eq!("rss", names[0]);
+    assert_eq!(2, names.iter().filter(|n| *n == "item").count());
+    assert_eq!(1, names.iter().filter(|n| *n == "dc:creator").count());
+    assert!(xml.contains("<title>Rust &amp; Diesel</title>"));
+    assert!(xml.contains("<link>https://blog.example.com/posts/tom-jerry</link>"));
+    assert!(xml.contains("<pubDate>Thu, 01 Oct 2020 10:30:00 +0000</pubDate>"));
+    assert!(xml.contains("<lastBuildDate>Sat, 03 Oct 2020 09:30:00 +0000</lastBuildDate>"));
+}
+
+#[test]
+fn atom_is_well_formed() {
+    let entries = vec![entry(1, "First", Some("Sean"))];
+    let xml = atom(&config(), &entries);
+    let names = element_names(&xml);
+
+    assert_eq!("feed", names[0]);
+    for required in &["id", "title", "updated", "author", "entry", "content"] {
+        assert!(
+            names.iter().any(|n| n == required),
+            "missing <{}>",
+            required
+        );
+    }
+    assert!(xml.contains("<id>https://blog.example.com/posts/1</id>"));
+    assert!(xml.contains("<published>2020-10-01T10:30:00Z</published>"));
+    assert!(xml.contains("<updated>2020-10-02T09:30:00Z</updated>"));
+}
+
+#[test]
+fn bodies_are_sanitised_then_escaped() {
+    let xml = rss(&config(), &[entry(1, "First", None)]);
+
+    assert!(xml.contains("&lt;em&gt;world&lt;/em&gt;"));
+    assert!(!xml.contains("alert"));
+}
+
+#[test]
+fn empty_feeds_are_valid() {
+    assert_eq!("rss", element_names(&rss(&config(), &[]))[0]);
+    assert_eq!("feed", element_names(&atom(&config(), &[]))[0]);
+}
+
+#[test]
+fn examine_sql_from_published_entries() {
+    use diesel::debug_query;
+    use diesel::mysql::Mysql;
+
+    let query = active_posts()
+        .left_join(
+            users::table.on(posts::author_id
+                .eq(users::id.nullable())
+                .and(users::deleted_at.is_null())),
+        )
+        .filter(posts::published.eq(true))
+        .order((posts::published_at.desc(), posts::id.desc()))
+        .limit(DEFAULT_FEED_LEN)
+        .select((posts::id, users::name.nullable()));
+    let sql = "SELECT `posts`.`id`, `users`.`name` FROM (`posts` LEFT OUTER JOIN `users` \
+               ON `posts`.`author_id` = `users`.`id` AND `users`.`deleted_at` IS NULL) \
+               WHERE `posts`.`deleted_at` IS NULL AND `posts`.`published` = ? \
+               ORDER BY `posts`.`published_at` DESC, `posts`.`id` DESC LIMIT ? \
+               -- binds: [true, 20]";
+    assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
+}
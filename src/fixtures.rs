@@ -0,0 +1,478 @@
+use crate::auth::{hash_password, normalize_email, AuthError, MIN_PASSWORD_LENGTH};
+use crate::generator::{fake_user, GenerateOptions, Generator};
+use crate::importer::parse_date;
+use crate::models::{AuditAction, HairColor, NewPost, Post, Role};
+use crate::schema::{posts, users};
+use crate::tags::set_tags;
+use crate::validation::Validate;
+use crate::{audit, markdown, slugs, DbConnection, User, UserForm};
+use diesel::prelude::*;
+use serde_derive::Deserialize;
+
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Users and posts to insert, with posts pointing at their author by key
+/// instead of by id.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Fixtures {
+    #[serde(default)]
+    pub users: Vec<UserFixture>,
+    #[serde(default)]
+    pub posts: Vec<PostFixture>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct UserFixture {
+    /// Referenced by `PostFixture::author`; not stored.
+    pub key: String,
+    pub name: String,
+    pub hair_color: Option<HairColor>,
+    pub email: Option<String>,
+    /// Hashed on load; users without one cannot log in.
+    pub password: Option<String>,
+    #[serde(default = "default_role")]
+    pub role: Role,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PostFixture {
+    pub key: String,
+    /// The `key` of a user in the same fixtures.
+    pub author: String,
+    pub title: String,
+    pub body: String,
+    #[serde(default)]
+    pub published: bool,
+    /// Publication date, as in imported front matter; defaults to now.
+    pub date: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+fn default_role() -> Role {
+    Role::Reader
+}
+
+/// Named fixture sets for `cargo run --bin seed`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Profile {
+    /// One user per role and one post; what tests start from.
+    Minimal,
+    /// A handful of authors, posts, tags and a draft.
+    Demo,
+    /// Generated users and posts for timing queries.
+    LoadTest,
+}
+
+/// Size of the `load-test` profile; `generator` makes anything bigger.
+const LOAD_TEST_SEED: u64 = 0;
+const LOAD_TEST_USERS: usize = 200;
+const LOAD_TEST_POSTS: usize = 2_000;
+
+impl Profile {
+    pub const NAMES: &'static [&'static str] = &["minimal", "demo", "load-test"];
+
+    pub fn fixtures(self) -> Result<Fixtures, FixtureError> {
+        match self {
+            Profile::Minimal => parse_yaml(include_str!("../fixtures/minimal.yaml")),
+            Profile::Demo => parse_yaml(include_str!("../fixtures/demo.yaml")),
+            Profile::LoadTest => Ok(load_test_fixtures()),
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "minimal" => Ok(Profile::Minimal),
+            "demo" => Ok(Profile::Demo),
+            "load-test" => Ok(Profile::LoadTest),
+            other => Err(format!(
+                "unknown profile {:?} (expected one of {})",
+                other,
+                Profile::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// Generated users and posts, the same on every run.
+fn load_test_fixtures() -> Fixtures {
+    let options = GenerateOptions {
+        seed: LOAD_TEST_SEED,
+        users: LOAD_TEST_USERS,
+        posts: LOAD_TEST_POSTS,
+        ..GenerateOptions::default()
+    };
+    let mut generator = Generator::new(options.seed);
+
+    let mut authors = Vec::new();
+    let users = (0..options.users)
+        .map(|n| {
+            let user = fake_user(&mut generator, &options, n);
+            let key = format!("user-{}", n + 1);
+            if user.role == Role::Author {
+                authors.push(key.clone());
+            }
+            UserFixture {
+                key,
+                name: user.name,
+                hair_color: user.hair_color,
+                email: None,
+                password: None,
+                role: user.role,
+            }
+        })
+        .collect();
+    let posts = (0..options.posts)
+        .map(|n| {
+            let post = generator.post(authors.len(), options.publish_ratio);
+            PostFixture {
+                key: format!("post-{}", n + 1),
+                author: authors[post.author].clone(),
+                title: post.title,
+                body: post.body,
+                published: post.published,
+                date: post
+                    .published_at
+                    .map(|date| date.format("%Y-%m-%dT%H:%M:%S").to_string()),
+                tags: Vec::new(),
+            }
+        })
+        .collect();
+    Fixtures { users, posts }
+}
+
+#[derive(Debug)]
+pub enum FixtureError {
+    Io(io::Error),
+    Parse(String),
+    /// Problems with single fixtures, as (key, message).
+    Invalid(Vec<(String, String)>),
+    Auth(AuthError),
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for FixtureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FixtureError::Io(e) => write!(f, "{}", e),
+            FixtureError::Parse(message) => write!(f, "{}", message),
+            FixtureError::Invalid(errors) => {
+                let errors = errors
+                    .iter()
+                    .map(|(key, message)| format!("{}: {}", key, message))
+                    .collect::<Vec<_>>();
+                write!(f, "{}", errors.join("; "))
+            }
+            FixtureError::Auth(e) => write!(f, "{}", e),
+            FixtureError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for FixtureError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FixtureError::Io(e) => Some(e),
+            FixtureError::Auth(e) => Some(e),
+            FixtureError::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FixtureError {
+    fn from(e: io::Error) -> Self {
+        FixtureError::Io(e)
+    }
+}
+
+impl From<AuthError> for FixtureError {
+    fn from(e: AuthError) -> Self {
+        FixtureError::Auth(e)
+    }
+}
+
+impl From<diesel::result::Error> for FixtureError {
+    fn from(e: diesel::result::Error) -> Self {
+        FixtureError::Database(e)
+    }
+}
+
+impl crate::metrics::ErrorKind for FixtureError {
+    fn kind(&self) -> &'static str {
+        match self {
+            FixtureError::Io(_) => "io",
+            FixtureError::Parse(_) | FixtureError::Invalid(_) => "invalid",
+            FixtureError::Auth(e) => e.kind(),
+            FixtureError::Database(e) => e.kind(),
+        }
+    }
+}
+
+fn parse_yaml(contents: &str) -> Result<Fixtures, FixtureError> {
+    serde_yaml::from_str(contents).map_err(|e| FixtureError::Parse(e.to_string()))
+}
+
+/// Reads fixtures from a `.json` file, or YAML for any other extension.
+pub fn read_fixtures(path: &Path) -> Result<Fixtures, FixtureError> {
+    let contents = fs::read_to_string(path)?;
+    if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str(&contents).map_err(|e| FixtureError::Parse(e.to_string()))
+    } else {
+        parse_yaml(&contents)
+    }
+}
+
+/// Finds duplicate keys, dangling author references and values the
+/// database would reject, without touching the database.
+pub fn check(fixtures: &Fixtures) -> Result<(), FixtureError> {
+    let mut errors = Vec::new();
+
+    let mut user_keys = HashSet::new();
+    for user in &fixtures.users {
+        if !user_keys.insert(user.key.as_str()) {
+            errors.push((user.key.clone(), "duplicate user key".to_string()));
+        }
+        let form = UserForm {
+            name: &user.name,
+            hair_color: user.hair_color.clone(),
+        };
+        if let Err(e) = form.validate() {
+            errors.push((user.key.clone(), e.to_string()));
+        }
+        if user
+            .password
+            .as_ref()
+            .is_some_and(|password| password.chars().count() < MIN_PASSWORD_LENGTH)
+        {
+            errors.push((user.key.clone(), AuthError::WeakPassword.to_string()));
+        }
+    }
+
+    let mut post_keys = HashSet::new();
+    for post in &fixtures.posts {
+        if !post_keys.insert(post.key.as_str()) {
+            errors.push((post.key.clone(), "duplicate post key".to_string()));
+        }
+        if !user_keys.contains(post.author.as_str()) {
+            errors.push((
+                post.key.clone(),
+                format!("no user with key {:?}", post.author),
+            ));
+        }
+        let new_post = NewPost {
+            title: &post.title,
+            body: &post.body,
+            author_id: None,
+        };
+        if let Err(e) = new_post.validate() {
+            errors.push((post.key.clone(), e.to_string()));
+        }
+        if let Some(Err(e)) = post.date.as_deref().map(parse_date) {
+            errors.push((post.key.clone(), e));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(FixtureError::Invalid(errors))
+    }
+}
+
+/// The rows created by `load`, by fixture key.
+#[derive(Default, Debug)]
+pub struct Seeded {
+    pub users: BTreeMap<String, User>,
+    pub posts: BTreeMap<String, Post>,
+}
+
+fn insert_user(
+    conn: &DbConnection,
+    actor: Option<&User>,
+    fixture: &UserFixture,
+) -> Result<User, FixtureError> {
+    let form = UserForm {
+        name: &fixture.name,
+        hair_color: fixture.hair_color.clone(),
+    }
+    .validate()
+    .map_err(|e| FixtureError::Invalid(vec![(fixture.key.clone(), e.to_string())]))?;
+    let password_hash = fixture.password.as_deref().map(hash_password).transpose()?;
+
+    diesel::insert_into(users::table)
+        .values((
+            &form,
+            users::email.eq(fixture.email.as_deref().map(normalize_email)),
+            users::password_hash.eq(password_hash),
+            users::role.eq(fixture.role),
+        ))
+        .execute(conn)?;
+    let user = users::table.order(users::id.desc()).first(conn)?;
+    audit::record(conn, actor, AuditAction::Create, None, Some(&user))?;
+    Ok(user)
+}
+
+fn insert_post(
+    conn: &DbConnection,
+    actor: Option<&User>,
+    fixture: &PostFixture,
+    author: &User,
+) -> Result<Post, FixtureError> {
+    let invalid = |message: String| FixtureError::Invalid(vec![(fixture.key.clone(), message)]);
+    let new_post = NewPost {
+        title: &fixture.title,
+        body: &fixture.body,
+        author_id: Some(author.id),
+    }
+    .validate()
+    .map_err(|e| invalid(e.to_string()))?;
+    let date = fixture
+        .date
+        .as_deref()
+        .map(parse_date)
+        .transpose()
+        .map_err(invalid)?;
+
+    let slug = slugs::unique_slug(conn, new_post.title, None)?;
+    diesel::insert_into(posts::table)
+        .values((
+            &new_post,
+            posts::slug.eq(&slug),
+            posts::body_html.eq(markdown::render_html(new_post.body)),
+            posts::published.eq(fixture.published),
+        ))
+        .execute(conn)?;
+    let post = posts::table.order(posts::id.desc()).first::<Post>(conn)?;
+
+    if fixture.published {
+        let stamp = diesel::update(posts::table.find(post.id));
+        match date {
+            Some(date) => stamp.set(posts::published_at.eq(date)).execute(conn)?,
+            None => stamp
+                .set(posts::published_at.eq(diesel::dsl::now.nullable()))
+                .execute(conn)?,
+        };
+    }
+    set_tags(conn, post.id, &fixture.tags)?;
+    let post = posts::table.find(post.id).first(conn)?;
+    audit::record(conn, actor, AuditAction::Create, None, Some(&post))?;
+    Ok(post)
+}
+
+/// Inserts every user, then every post with its author resolved, in one
+/// transaction, recording each as created by `actor`. Fixtures are checked
+/// first, so nothing is written if any of them is invalid.
+pub fn load(
+    conn: &DbConnection,
+    actor: Option<&User>,
+    fixtures: &Fixtures,
+) -> Result<Seeded, FixtureError> {
+    check(fixtures)?;
+
+    crate::metrics::track("load_fixtures", || {
+        conn.transaction(|| {
+            let mut seeded = Seeded::default();
+            for fixture in &fixtures.users {
+                let user = insert_user(conn, actor, fixture)?;
+                seeded.users.insert(fixture.key.clone(), user);
+            }
+            for fixture in &fixtures.posts {
+                let post = insert_post(conn, actor, fixture, &seeded.users[&fixture.author])?;
+                seeded.posts.insert(fixture.key.clone(), post);
+            }
+            Ok(seeded)
+        })
+    })
+}
+
+/// Loads the fixtures of a profile with no actor, for tests; see `Profile`.
+/// Emails are left out: they are unique, and `cargo run --bin seed` may
+/// already have loaded the same profile.
+pub fn seed(conn: &DbConnection, profile: Profile) -> Result<Seeded, FixtureError> {
+    let mut fixtures = profile.fixtures()?;
+    for user in &mut fixtures.users {
+        user.email = None;
+    }
+    load(conn, None, &fixtures)
+}
+
+#[test]
+fn bundled_profiles_are_valid() {
+    for name in Profile::NAMES {
+        let profile = name.parse::<Profile>().unwrap();
+        check(&profile.fixtures().unwrap()).unwrap();
+    }
+}
+
+#[test]
+fn demo_profile_resolves_authors() {
+    let fixtures = Profile::Demo.fixtures().unwrap();
+    let ruby = fixtures.users.iter().find(|u| u.key == "ruby").unwrap();
+
+    assert_eq!(Role::Author, ruby.role);
+    assert_eq!(Some(HairColor::Red), ruby.hair_color);
+    assert!(fixtures
+        .posts
+        .iter()
+        .any(|p| p.author == "ruby" && !p.published));
+}
+
+#[test]
+fn json_fixtures_are_read() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("fixtures.json");
+    fs::write(
+        &path,
+        r#"{ "users": [{ "key": "sean", "name": "Sean" }],
+             "posts": [{ "key": "hi", "author": "sean", "title": "Hi", "body": "Hello" }] }"#,
+    )
+    .unwrap();
+
+    let fixtures = read_fixtures(&path).unwrap();
+    assert_eq!(Role::Reader, fixtures.users[0].role);
+    check(&fixtures).unwrap();
+}
+
+#[test]
+fn broken_references_are_reported() {
+    let fixtures = parse_yaml(
+        "users:\n  - { key: sean, name: Sean, password: short }\n  - { key: sean, name: ' ' }\n\
+         posts:\n  - { key: hi, author: tess, title: Hi, body: Hello, date: yesterday }\n",
+    )
+    .unwrap();
+
+    match check(&fixtures) {
+        Err(FixtureError::Invalid(errors)) => {
+            let keys = errors
+                .iter()
+                .map(|(key, _)| key.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(vec!["sean", "sean", "sean", "hi", "hi"], keys);
+            assert!(errors[3].1.contains("\"tess\""));
+        }
+        other => panic!("expected Invalid, got {:?}", other),
+    }
+}
+
+#[test]
+fn unknown_profiles_are_rejected() {
+    assert_eq!(Ok(Profile::LoadTest), "load-test".parse());
+    assert!("huge"
+        .parse::<Profile>()
+        .unwrap_err()
+        .contains("minimal, demo, load-test"));
+}
@@ -0,0 +1,562 @@
+use crate::audit::Audited;
+use crate::markdown::render_html;
+use crate::models::{HairColor, Post, Role};
+use crate::schema::{posts, users};
+use crate::slugs::{claim_slugs, slugify};
+use crate::{insert_chunked, DbConnection, User};
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use diesel::expression::dsl::max;
+use diesel::prelude::*;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+/// Generated timestamps fall in the `SPAN_DAYS` before this, so the same
+/// seed gives the same rows whenever it is run.
+fn until() -> NaiveDateTime {
+    NaiveDate::from_ymd(2020, 12, 1).and_hms(0, 0, 0)
+}
+
+const SPAN_DAYS: i64 = 3 * 365;
+
+const FIRST_NAMES: &[&str] = &[
+    "Ada", "Alan", "Ava", "Barbara", "Ben", "Chen", "Dana", "Elif", "Emma", "Felix", "Grace",
+    "Hana", "Ivan", "Jim", "Kai", "Lena", "Liam", "Maya", "Noah", "Olga", "Omar", "Priya", "Ravi",
+    "Ruby", "Sean", "Sofia", "Tess", "Yuki", "Zoe", "Zhang",
+];
+
+const LAST_NAMES: &[&str] = &[
+    "Anders",
+    "Baker",
+    "Costa",
+    "Dubois",
+    "Evans",
+    "Fischer",
+    "Garcia",
+    "Hopper",
+    "Ito",
+    "Jensen",
+    "Kowalski",
+    "Lovelace",
+    "Martin",
+    "Nakamura",
+    "Okafor",
+    "Patel",
+    "Quinn",
+    "Rossi",
+    "Schmidt",
+    "Turing",
+    "Underwood",
+    "Varga",
+    "Wang",
+    "Xu",
+    "Young",
+    "Zimmermann",
+];
+
+/// Colours with how often they occur, out of the sum of the weights; the
+/// remaining `NO_HAIR_COLOR` weight leaves the column NULL.
+const HAIR_COLORS: &[(HairColor, u32)] = &[
+    (HairColor::Brown, 40),
+    (HairColor::Black, 30),
+    (HairColor::Blonde, 15),
+    (HairColor::Red, 5),
+    (HairColor::Grey, 5),
+    (HairColor::White, 2),
+];
+const NO_HAIR_COLOR: u32 = 20;
+
+const TOPICS: &[&str] = &[
+    "Diesel",
+    "MySQL",
+    "migrations",
+    "transactions",
+    "indexes",
+    "joins",
+    "Rust",
+    "traits",
+    "macros",
+    "lifetimes",
+    "connection pools",
+    "batch inserts",
+    "soft deletes",
+    "locking",
+    "Markdown",
+    "slugs",
+    "feeds",
+    "testing",
+];
+
+const TITLE_PATTERNS: &[&str] = &[
+    "Getting started with {}",
+    "Why {} matter",
+    "{} in practice",
+    "A closer look at {}",
+    "Notes on {}",
+    "What I learned about {}",
+    "{} and {}",
+    "Debugging {}",
+];
+
+const WORDS: &[&str] = &[
+    "query",
+    "table",
+    "row",
+    "column",
+    "schema",
+    "index",
+    "insert",
+    "update",
+    "select",
+    "result",
+    "connection",
+    "type",
+    "macro",
+    "trait",
+    "struct",
+    "field",
+    "value",
+    "batch",
+    "error",
+    "test",
+    "the",
+    "a",
+    "with",
+    "without",
+    "every",
+    "each",
+    "some",
+    "quickly",
+    "safely",
+    "then",
+    "when",
+    "because",
+    "and",
+    "or",
+    "is",
+    "are",
+    "was",
+    "runs",
+    "returns",
+    "checks",
+    "keeps",
+    "writes",
+];
+
+/// A user as generated, before it has an id.
+#[derive(Insertable, Clone, PartialEq, Debug)]
+#[table_name = "users"]
+pub struct FakeUser {
+    pub name: String,
+    pub hair_color: Option<HairColor>,
+    pub role: Role,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+/// A post as generated; `author` indexes the authors generated so far.
+#[derive(Clone, PartialEq, Debug)]
+pub struct FakePost {
+    pub author: usize,
+    pub title: String,
+    pub body: String,
+    pub published: bool,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub published_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "posts"]
+struct NewFakePost<'a> {
+    title: &'a str,
+    body: &'a str,
+    published: bool,
+    author_id: i32,
+    slug: String,
+    body_html: String,
+    created_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
+    published_at: Option<NaiveDateTime>,
+}
+
+/// Produces the same users and posts, in the same order, for the same seed.
+pub struct Generator {
+    rng: ChaCha8Rng,
+}
+
+impl Generator {
+    pub fn new(seed: u64) -> Self {
+        Generator {
+            rng: ChaCha8Rng::seed_from_u64(seed),
+        }
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.rng.gen_range(0..items.len())]
+    }
+
+    /// A moment in the generated time span, uniformly spread.
+    fn timestamp(&mut self) -> NaiveDateTime {
+        until() - Duration::seconds(self.rng.gen_range(0..SPAN_DAYS * 86_400))
+    }
+
+    /// Somewhere between `from` and `limit` later, but not past the span.
+    fn later(&mut self, from: NaiveDateTime, limit: Duration) -> NaiveDateTime {
+        let room = (until() - from).min(limit).num_seconds().max(0);
+        from + Duration::seconds(self.rng.gen_range(0..=room))
+    }
+
+    fn hair_color(&mut self) -> Option<HairColor> {
+        let total = HAIR_COLORS.iter().map(|(_, weight)| weight).sum::<u32>() + NO_HAIR_COLOR;
+        let mut roll = self.rng.gen_range(0..total);
+        for (color, weight) in HAIR_COLORS {
+            if roll < *weight {
+                return Some(color.clone());
+            }
+            roll -= weight;
+        }
+        None
+    }
+
+    /// A user who writes posts with probability `author_ratio`.
+    pub fn user(&mut self, author_ratio: f64) -> FakeUser {
+        let name = format!("{} {}", self.pick(FIRST_NAMES), self.pick(LAST_NAMES));
+        let hair_color = self.hair_color();
+        let role = if self.rng.gen_bool(author_ratio) {
+            Role::Author
+        } else {
+            Role::Reader
+        };
+        let created_at = self.timestamp();
+        let updated_at = self.later(created_at, Duration::days(90));
+
+        FakeUser {
+            name,
+            hair_color,
+            role,
+            created_at,
+            updated_at,
+        }
+    }
+
+    fn title(&mut self) -> String {
+        let pattern = *self.pick(TITLE_PATTERNS);
+        let mut title = String::new();
+        let mut parts = pattern.split("{}");
+        title.push_str(parts.next().unwrap_or_default());
+        for part in parts {
+            let topic = *self.pick(TOPICS);
+            title.push_str(topic);
+            title.push_str(part);
+        }
+        let mut chars = title.chars();
+        match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect(),
+            None => title,
+        }
+    }
+
+    fn sentence(&mut self) -> String {
+        let len = self.rng.gen_range(5..15);
+        let words = (0..len).map(|_| *self.pick(WORDS)).collect::<Vec<_>>();
+        let sentence = words.join(" ");
+        let mut chars = sentence.chars();
+        match chars.next() {
+            Some(first) => format!("{}{}.", first.to_uppercase(), chars.as_str()),
+            None => sentence,
+        }
+    }
+
+    fn paragraph(&mut self) -> String {
+        let len = self.rng.gen_range(2..6);
+        (0..len)
+            .map(|_| self.sentence())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// A few paragraphs, sometimes with a heading, a list or a code block.
+    fn body(&mut self) -> String {
+        let mut blocks = Vec::new();
+        for n in 0..self.rng.gen_range(1..6) {
+            match self.rng.gen_range(0..10) {
+                0 if n > 0 => blocks.push(format!("## {}", self.pick(TOPICS))),
+                1 => {
+                    let items = (0..self.rng.gen_range(2..5))
+                        .map(|_| format!("- {}", self.pick(WORDS)))
+                        .collect::<Vec<_>>();
+                    blocks.push(items.join("\n"));
+                }
+                2 => blocks.push(format!(
+                    "```rust\nlet {} = {}::table.load(&conn)?;\n```",
+                    self.pick(WORDS),
+                    self.pick(&["users", "posts"])
+                )),
+                _ => {}
+            }
+            blocks.push(self.paragraph());
+        }
+        blocks.join("\n\n")
+    }
+
+    /// A post by one of `authors` authors, published with probability
+    /// `publish_ratio`. Earlier authors are picked more often.
+    pub fn post(&mut self, authors: usize, publish_ratio: f64) -> FakePost {
+        let skew = self.rng.gen::<f64>();
+        let author = ((skew * skew * authors as f64) as usize).min(authors.saturating_sub(1));
+        let title = self.title();
+        let body = self.body();
+        let published = self.rng.gen_bool(publish_ratio);
+        let created_at = self.timestamp();
+        let published_at = if published {
+            Some(self.later(created_at, Duration::days(7)))
+        } else {
+            None
+        };
+        let updated_at = self.later(published_at.unwrap_or(created_at), Duration::days(30));
+
+        FakePost {
+            author,
+            title,
+            body,
+            published,
+            created_at,
+            updated_at,
+            published_at,
+        }
+    }
+}
+
+pub struct GenerateOptions {
+    pub seed: u64,
+    pub users: usize,
+    pub posts: usize,
+    /// Share of users who get the author role; posts are spread over them.
+    pub author_ratio: f64,
+    pub publish_ratio: f64,
+    pub chunk_size: usize,
+}
+
+impl Default for GenerateOptions {
+    fn default() -> Self {
+        GenerateOptions {
+            seed: 0,
+            users: 1_000,
+            posts: 10_000,
+            author_ratio: 0.1,
+            publish_ratio: 0.8,
+            chunk_size: crate::user_csv::CHUNK_SIZE,
+        }
+    }
+}
+
+/// Reported after every chunk.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Progress {
+    pub table: &'static str,
+    pub done: usize,
+    pub total: usize,
+}
+
+#[derive(Default, PartialEq, Debug)]
+pub struct GenerateReport {
+    pub users: usize,
+    pub posts: usize,
+}
+
+/// The `n`th user `options` describes. The first user is always an author
+/// so that posts have someone to belong to.
+pub fn fake_user(generator: &mut Generator, options: &GenerateOptions, n: usize) -> FakeUser {
+    let mut user = generator.user(options.author_ratio);
+    if n == 0 {
+        user.role = Role::Author;
+    }
+    user
+}
+
+/// Inserts `options.users` users and then `options.posts` posts, one
+/// multi-row INSERT per chunk, calling `progress` after each. Chunks are
+/// committed as they go, so an interrupted run leaves the rows it finished.
+/// Posts belong to the authors among the new users; with no users they fail
+/// with `NotFound`. Every row is recorded as created by `actor`.
+pub fn generate<F>(
+    conn: &DbConnection,
+    actor: Option<&User>,
+    options: &GenerateOptions,
+    mut progress: F,
+) -> QueryResult<GenerateReport>
+where
+    F: FnMut(Progress),
+{
+    let mut generator = Generator::new(options.seed);
+
+    crate::metrics::track("generate", || {
+        let mut report = GenerateReport::default();
+
+        let last_user = users::table
+            .select(max(users::id))
+            .first::<Option<i32>>(conn)?;
+        let fakes = (0..options.users).map(|n| fake_user(&mut generator, options, n));
+        report.users = insert_chunked(
+            fakes,
+            options.chunk_size,
+            |chunk| {
+                User::audited_inserts(conn, actor, || {
+                    diesel::insert_into(users::table)
+                        .values(chunk)
+                        .execute(conn)
+                })
+            },
+            |done| {
+                progress(Progress {
+                    table: "users",
+                    done,
+                    total: options.users,
+                })
+            },
+        )?;
+
+        if options.posts == 0 {
+            return Ok(report);
+        }
+        let authors = users::table
+            .filter(users::id.gt(last_user.unwrap_or(0)))
+            .filter(users::role.eq(Role::Author))
+            .select(users::id)
+            .order(users::id)
+            .load::<i32>(conn)?;
+        if authors.is_empty() {
+            return Err(diesel::result::Error::NotFound);
+        }
+        // Numbering slugs after the last post makes collisions rare, so
+        // `claim_slugs` can check a whole chunk at once.
+        let last_post = posts::table
+            .select(max(posts::id))
+            .first::<Option<i32>>(conn)?;
+        let mut number = last_post.unwrap_or(0) as usize;
+
+        let fakes =
+            (0..options.posts).map(|_| generator.post(authors.len(), options.publish_ratio));
+        report.posts = insert_chunked(
+            fakes,
+            options.chunk_size,
+            |chunk: &[FakePost]| {
+                let wanted = chunk
+                    .iter()
+                    .map(|post| {
+                        number += 1;
+                        format!("{}-{}", slugify(&post.title), number)
+                    })
+                    .collect();
+                let rows = chunk
+                    .iter()
+                    .zip(claim_slugs(conn, wanted)?)
+                    .map(|(post, slug)| NewFakePost {
+                        title: &post.title,
+                        body: &post.body,
+                        published: post.published,
+                        author_id: authors[post.author],
+                        slug,
+                        body_html: render_html(&post.body),
+                        created_at: post.created_at,
+                        updated_at: post.updated_at,
+                        published_at: post.published_at,
+                    })
+                    .collect::<Vec<_>>();
+                Post::audited_inserts(conn, actor, || {
+                    diesel::insert_into(posts::table)
+                        .values(&rows)
+                        .execute(conn)
+                })
+            },
+            |done| {
+                progress(Progress {
+                    table: "posts",
+                    done,
+                    total: options.posts,
+                })
+            },
+        )?;
+        Ok(report)
+    })
+}
+
+#[test]
+fn same_seed_same_rows() {
+    let mut first = Generator::new(42);
+    let mut second = Generator::new(42);
+    let mut other = Generator::new(43);
+
+    let users = (0..20).map(|_| first.user(0.1)).collect::<Vec<_>>();
+    assert_eq!(users, (0..20).map(|_| second.user(0.1)).collect::<Vec<_>>());
+    assert_ne!(users, (0..20).map(|_| other.user(0.1)).collect::<Vec<_>>());
+    assert_eq!(first.post(5, 0.5), second.post(5, 0.5));
+}
+
+#[test]
+fn timestamps_are_ordered_and_in_range() {
+    let mut generator = Generator::new(7);
+    let earliest = until() - Duration::days(SPAN_DAYS);
+
+    for _ in 0..500 {
+        let user = generator.user(0.1);
+        assert!(earliest <= user.created_at && user.created_at <= user.updated_at);
+        assert!(user.updated_at <= until());
+
+        let post = generator.post(3, 0.5);
+        assert!(post.author < 3);
+        assert_eq!(post.published, post.published_at.is_some());
+        if let Some(published_at) = post.published_at {
+            assert!(post.created_at <= published_at && published_at <= post.updated_at);
+        }
+        assert!(post.updated_at <= until());
+    }
+}
+
+#[test]
+fn publish_ratio_is_roughly_kept() {
+    let mut generator = Generator::new(1);
+    let published = (0..2_000)
+        .filter(|_| generator.post(10, 0.8).published)
+        .count();
+
+    assert!(
+        (1_500..1_700).contains(&published),
+        "{} published",
+        published
+    );
+}
+
+#[test]
+fn posts_are_valid_markdown_posts() {
+    use crate::models::NewPost;
+    use crate::validation::Validate;
+
+    let mut generator = Generator::new(3);
+    for _ in 0..100 {
+        let post = generator.post(1, 1.0);
+        let new_post = NewPost {
+            title: &post.title,
+            body: &post.body,
+            author_id: None,
+        };
+        new_post.validate().unwrap();
+        assert!(render_html(&post.body).contains("<p>"));
+    }
+}
+
+#[test]
+fn first_user_is_an_author() {
+    let options = GenerateOptions {
+        users: 10,
+        author_ratio: 0.0,
+        ..GenerateOptions::default()
+    };
+    let mut generator = Generator::new(0);
+    let users = (0..10)
+        .map(|n| fake_user(&mut generator, &options, n))
+        .collect::<Vec<_>>();
+
+    assert_eq!(Role::Author, users[0].role);
+    assert!(users[1..].iter().all(|user| user.role == Role::Reader));
+}
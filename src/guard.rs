@@ -0,0 +1,161 @@
+use crate::DbConnection;
+use diesel::debug_query;
+use diesel::mysql::Mysql;
+use diesel::prelude::*;
+use diesel::query_builder::QueryFragment;
+use diesel::query_dsl::methods::ExecuteDsl;
+use diesel::query_dsl::LoadQuery;
+
+use std::error::Error;
+use std::fmt;
+use std::io::{stdin, stdout, Write};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mode {
+    DryRun,
+    Execute,
+}
+
+/// How a destructive operation may run. There is no unbounded guard: every
+/// caller has to say how many rows it is prepared to lose.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Guard {
+    pub mode: Mode,
+    pub max_rows: usize,
+}
+
+impl Guard {
+    pub fn dry_run(max_rows: usize) -> Guard {
+        Guard {
+            mode: Mode::DryRun,
+            max_rows,
+        }
+    }
+
+    pub fn execute(max_rows: usize) -> Guard {
+        Guard {
+            mode: Mode::Execute,
+            max_rows,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum Outcome<T> {
+    /// Nothing was changed; `rows` are the rows the statement would affect.
+    Preview {
+        rows: Vec<T>,
+        sql: String,
+    },
+    Executed {
+        affected: usize,
+        sql: String,
+    },
+}
+
+impl<T> Outcome<T> {
+    pub fn sql(&self) -> &str {
+        match self {
+            Outcome::Preview { sql, .. } | Outcome::Executed { sql, .. } => sql,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum GuardError {
+    TooManyRows { matched: usize, max_rows: usize },
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for GuardError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GuardError::TooManyRows { matched, max_rows } => write!(
+                f,
+                "refusing to touch {} rows, the limit is {}",
+                matched, max_rows
+            ),
+            GuardError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for GuardError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GuardError::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<diesel::result::Error> for GuardError {
+    fn from(e: diesel::result::Error) -> Self {
+        GuardError::Database(e)
+    }
+}
+
+/// Runs `statement` under `guard`. `matching` must select the rows the
+/// statement is going to affect; it is used for the preview and for the
+/// row-count ceiling, which is checked again against the real affected count
+/// so a concurrent insert cannot push the statement past the limit.
+pub fn guarded<T, S, Q>(
+    conn: &DbConnection,
+    guard: &Guard,
+    matching: S,
+    statement: Q,
+) -> Result<Outcome<T>, GuardError>
+where
+    S: LoadQuery<DbConnection, T>,
+    Q: ExecuteDsl<DbConnection> + QueryFragment<Mysql>,
+{
+    let sql = debug_query::<Mysql, _>(&statement).to_string();
+
+    conn.transaction(|| {
+        let rows = matching.load::<T>(conn)?;
+        check_ceiling(rows.len(), guard.max_rows)?;
+
+        match guard.mode {
+            Mode::DryRun => Ok(Outcome::Preview { rows, sql }),
+            Mode::Execute => {
+                let affected = ExecuteDsl::execute(statement, conn)?;
+                check_ceiling(affected, guard.max_rows)?;
+                Ok(Outcome::Executed { affected, sql })
+            }
+        }
+    })
+}
+
+fn check_ceiling(matched: usize, max_rows: usize) -> Result<(), GuardError> {
+    if matched > max_rows {
+        Err(GuardError::TooManyRows { matched, max_rows })
+    } else {
+        Ok(())
+    }
+}
+
+/// Asks a yes/no question on the terminal, defaulting to no.
+pub fn confirm(prompt: &str) -> bool {
+    print!("{} [y/N] ", prompt);
+    stdout().flush().unwrap();
+
+    let mut answer = String::new();
+    stdin().read_line(&mut answer).unwrap();
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+#[test]
+fn check_ceiling_allows_the_limit() {
+    assert!(check_ceiling(3, 3).is_ok());
+    assert!(check_ceiling(0, 0).is_ok());
+}
+
+#[test]
+fn check_ceiling_rejects_more_than_the_limit() {
+    match check_ceiling(4, 3) {
+        Err(GuardError::TooManyRows { matched, max_rows }) => {
+            assert_eq!((4, 3), (matched, max_rows));
+        }
+        other => panic!("expected TooManyRows, got {:?}", other),
+    }
+}
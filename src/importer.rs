@@ -0,0 +1,683 @@
+use crate::audit::Audited;
+use crate::models::{AuditAction, NewPost, Post, PostChanges};
+use crate::policy::{authorize, can, Action};
+use crate::publishing::{edit_post, publish_post, PostError};
+use crate::schema::{post_sources, posts};
+use crate::slugs::{set_slug, slug_matches_title, slugify};
+use crate::soft_delete::active_posts;
+use crate::tags::{normalize_tags, set_tags, tags_for, MAX_TAG_LEN};
+use crate::validation::Validate;
+use crate::{DbConnection, User};
+use chrono::{NaiveDate, NaiveDateTime};
+use diesel::prelude::*;
+use serde_derive::Deserialize;
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FrontMatter {
+    /// Written by `exporter`; matched before the slug when present.
+    id: Option<i32>,
+    title: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    published: bool,
+    date: Option<String>,
+    slug: Option<String>,
+    // Written by `exporter` for reference; the database keeps its own.
+    #[allow(dead_code)]
+    created_at: Option<String>,
+    #[allow(dead_code)]
+    updated_at: Option<String>,
+}
+
+/// A post as written in a Markdown file.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SourcePost {
+    /// Relative to the import root, with `/` separators.
+    pub path: String,
+    pub id: Option<i32>,
+    pub title: String,
+    pub body: String,
+    pub tags: Vec<String>,
+    pub published: bool,
+    pub date: Option<NaiveDateTime>,
+    /// Given in the front matter or derived from the title.
+    pub slug: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Change {
+    Created,
+    Updated,
+    Unchanged,
+}
+
+#[derive(Default, PartialEq, Debug)]
+pub struct ImportReport {
+    pub created: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    /// Every file with what happened, or would happen in a dry run, to it.
+    pub files: Vec<(String, Change)>,
+}
+
+impl ImportReport {
+    fn record(&mut self, path: &str, change: Change) {
+        match change {
+            Change::Created => self.created += 1,
+            Change::Updated => self.updated += 1,
+            Change::Unchanged => self.unchanged += 1,
+        }
+        self.files.push((path.to_string(), change));
+    }
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Io(io::Error),
+    /// Files that could not be parsed, with the reason; nothing was written.
+    Invalid(Vec<(String, String)>),
+    Post {
+        path: String,
+        error: PostError,
+    },
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImportError::Io(e) => write!(f, "{}", e),
+            ImportError::Invalid(files) => {
+                write!(f, "{} invalid files:", files.len())?;
+                for (path, message) in files {
+                    write!(f, "\n  {}: {}", path, message)?;
+                }
+                Ok(())
+            }
+            ImportError::Post { path, error } => write!(f, "{}: {}", path, error),
+            ImportError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for ImportError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ImportError::Io(e) => Some(e),
+            ImportError::Post { error, .. } => Some(error),
+            ImportError::Database(e) => Some(e),
+            ImportError::Invalid(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ImportError {
+    fn from(e: io::Error) -> Self {
+        ImportError::Io(e)
+    }
+}
+
+impl From<diesel::result::Error> for ImportError {
+    fn from(e: diesel::result::Error) -> Self {
+        ImportError::Database(e)
+    }
+}
+
+impl crate::metrics::ErrorKind for ImportError {
+    fn kind(&self) -> &'static str {
+        match self {
+            ImportError::Io(_) => "io",
+            ImportError::Invalid(_) => "invalid",
+            ImportError::Post { error, .. } => error.kind(),
+            ImportError::Database(e) => e.kind(),
+        }
+    }
+}
+
+pub(crate) fn parse_date(date: &str) -> Result<NaiveDateTime, String> {
+    let date = date.trim();
+    for format in &["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M"] {
+        if let Ok(parsed) = NaiveDateTime::parse_from_str(date, format) {
+            return Ok(parsed);
+        }
+    }
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map(|day| day.and_hms(0, 0, 0))
+        .map_err(|_| format!("date {:?} is not YYYY-MM-DD[THH:MM[:SS]]", date))
+}
+
+/// Splits `---`-delimited YAML front matter from the Markdown body. The
+/// blank line after the front matter and the final newline of the file, as
+/// `exporter` writes them, are not part of the body.
+fn split_front_matter(contents: &str) -> Result<(&str, &str), String> {
+    let contents = contents.trim_start_matches('\u{feff}');
+    let rest = contents
+        .strip_prefix("---\n")
+        .or_else(|| contents.strip_prefix("---\r\n"))
+        .ok_or_else(|| "missing front matter (the file must start with ---)".to_string())?;
+
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if matches!(line.trim_end(), "---" | "...") {
+            let body = &rest[offset + line.len()..];
+            let body = body
+                .strip_prefix('\n')
+                .or_else(|| body.strip_prefix("\r\n"))
+                .unwrap_or(body);
+            let body = body
+                .strip_suffix("\r\n")
+                .or_else(|| body.strip_suffix('\n'))
+                .unwrap_or(body);
+            return Ok((&rest[..offset], body));
+        }
+        offset += line.len();
+    }
+    Err("front matter is not closed with ---".to_string())
+}
+
+pub fn parse_source(path: &str, contents: &str) -> Result<SourcePost, String> {
+    let (yaml, body) = split_front_matter(contents)?;
+    let front = serde_yaml::from_str::<FrontMatter>(yaml).map_err(|e| e.to_string())?;
+
+    let post = NewPost {
+        title: &front.title,
+        body,
+        author_id: None,
+    }
+    .validate()
+    .map_err(|e| e.to_string())?;
+    let tags = normalize_tags(&front.tags);
+    if let Some(tag) = tags.iter().find(|tag| tag.chars().count() > MAX_TAG_LEN) {
+        return Err(format!(
+            "tag {:?} is longer than {} characters",
+            tag, MAX_TAG_LEN
+        ));
+    }
+
+    Ok(SourcePost {
+        path: path.to_string(),
+        id: front.id,
+        slug: front
+            .slug
+            .as_deref()
+            .map_or_else(|| slugify(post.title), slugify),
+        title: post.title.to_string(),
+        body: post.body.to_string(),
+        tags,
+        published: front.published,
+        date: front.date.as_deref().map(parse_date).transpose()?,
+    })
+}
+
+fn collect_markdown(root: &Path, dir: &Path, files: &mut Vec<String>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_markdown(root, &path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "md") {
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            let parts = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>();
+            files.push(parts.join("/"));
+        }
+    }
+    Ok(())
+}
+
+/// Parses every `.md` file under `root`, in path order. Fails with every
+/// invalid file if any cannot be parsed.
+pub fn read_sources(root: &Path) -> Result<Vec<SourcePost>, ImportError> {
+    let mut paths = Vec::new();
+    collect_markdown(root, root, &mut paths)?;
+    paths.sort();
+
+    let mut sources = Vec::new();
+    let mut invalid = Vec::new();
+    for path in paths {
+        let contents = fs::read_to_string(root.join(&path))?;
+        match parse_source(&path, &contents) {
+            Ok(source) => sources.push(source),
+            Err(message) => invalid.push((path, message)),
+        }
+    }
+
+    if invalid.is_empty() {
+        Ok(sources)
+    } else {
+        Err(ImportError::Invalid(invalid))
+    }
+}
+
+/// What `post_sources` knows a file by: `root`, canonicalised, joined with
+/// the file's path below it.
+fn source_key(root: &Path, source: &SourcePost) -> String {
+    root.join(&source.path).to_string_lossy().into_owned()
+}
+
+/// The post the file at `key` was imported as before, or else the post with
+/// the id or slug from its front matter.
+fn find_existing(conn: &DbConnection, key: &str, source: &SourcePost) -> QueryResult<Option<Post>> {
+    let by_path = post_sources::table
+        .inner_join(posts::table)
+        .filter(post_sources::path.eq(key))
+        .filter(posts::deleted_at.is_null())
+        .select(posts::all_columns)
+        .first::<Post>(conn)
+        .optional()?;
+    if by_path.is_some() {
+        return Ok(by_path);
+    }
+    if let Some(id) = source.id {
+        let by_id = active_posts().find(id).first(conn).optional()?;
+        if by_id.is_some() {
+            return Ok(by_id);
+        }
+    }
+    active_posts()
+        .filter(posts::slug.eq(&source.slug))
+        .first(conn)
+        .optional()
+}
+
+/// The changes needed to make `post` match `source`.
+fn changes_for<'a>(post: &Post, source: &'a SourcePost) -> PostChanges<'a> {
+    PostChanges {
+        title: Some(source.title.as_str()).filter(|title| *title != post.title),
+        body: Some(source.body.as_str()).filter(|body| *body != post.body),
+        published: Some(source.published).filter(|published| *published != post.published),
+    }
+}
+
+fn publish_date_differs(post: &Post, source: &SourcePost) -> bool {
+    source.published && source.date.is_some() && source.date != post.published_at
+}
+
+fn classify(
+    conn: &DbConnection,
+    existing: Option<&Post>,
+    source: &SourcePost,
+) -> QueryResult<Change> {
+    let post = match existing {
+        Some(post) => post,
+        None => return Ok(Change::Created),
+    };
+    let changes = changes_for(post, source);
+    let changed = changes.title.is_some()
+        || changes.body.is_some()
+        || changes.published.is_some()
+        || publish_date_differs(post, source)
+        || tags_for(conn, post.id)? != source.tags;
+
+    Ok(if changed {
+        Change::Updated
+    } else {
+        Change::Unchanged
+    })
+}
+
+fn apply(
+    conn: &DbConnection,
+    actor: &User,
+    existing: Option<Post>,
+    key: &str,
+    source: &SourcePost,
+) -> Result<(), PostError> {
+    let created = existing.is_none();
+    let post = match existing {
+        Some(post) => {
+            // `edit_post` checks this too, but tags, the date and the source
+            // are changed here without it.
+            authorize(actor, Action::Edit, Some(&post))?;
+            let changes = changes_for(&post, source);
+            if changes.title.is_some() || changes.body.is_some() || changes.published.is_some() {
+                edit_post(conn, actor, post.id, post.version, &changes)?
+            } else {
+                post
+            }
+        }
+        None => {
+            let post = crate::create_post(conn, actor, &source.title, &source.body)?;
+            if source.published {
+                publish_post(conn, actor, post.id)?
+            } else {
+                post
+            }
+        }
+    };
+
+    // `create_post` takes the slug from the title and `publish_post` stamps
+    // the current time, but the file may ask for others.
+    let slug =
+        Some(source.slug.as_str()).filter(|slug| created && !slug_matches_title(&post.slug, slug));
+    let date = Some(source.date).filter(|_| publish_date_differs(&post, source));
+    if date.is_some() {
+        authorize(actor, Action::Publish, Some(&post))?;
+    }
+    if slug.is_some() || date.is_some() {
+        Post::audited::<_, diesel::result::Error, _>(
+            conn,
+            Some(actor),
+            AuditAction::Update,
+            &[post.id],
+            || {
+                if let Some(slug) = slug {
+                    set_slug(conn, &post, slug)?;
+                }
+                diesel::update(posts::table.find(post.id))
+                    .set((
+                        posts::published_at.eq(date.unwrap_or(post.published_at)),
+                        posts::version.eq(posts::version + 1),
+                    ))
+                    .execute(conn)
+            },
+        )?;
+    }
+    set_tags(conn, post.id, &source.tags)?;
+    remember_source(conn, key, post.id)?;
+    Ok(())
+}
+
+fn remember_source(conn: &DbConnection, key: &str, post_id: i32) -> QueryResult<()> {
+    diesel::replace_into(post_sources::table)
+        .values((
+            post_sources::path.eq(key),
+            post_sources::post_id.eq(post_id),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Creates or updates a post for every Markdown file under `root`, as
+/// `actor`. All files are written in one transaction; with `dry_run` nothing
+/// is written and the report says what would have happened.
+pub fn import_dir(
+    conn: &DbConnection,
+    actor: &User,
+    root: &Path,
+    dry_run: bool,
+) -> Result<ImportReport, ImportError> {
+    let root = root.canonicalize()?;
+    let sources = read_sources(&root)?;
+
+    crate::metrics::track("import_dir", || {
+        conn.transaction(|| {
+            let mut report = ImportReport::default();
+            for source in &sources {
+                let key = source_key(&root, source);
+                let existing = find_existing(conn, &key, source)?;
+                let change = classify(conn, existing.as_ref(), source)?;
+                if !dry_run {
+                    let result = match (change, existing) {
+                        // Possibly matched by slug; remember the file for next
+                        // time if the post is the actor's to edit.
+                        (Change::Unchanged, Some(post)) => {
+                            if can(actor, Action::Edit, Some(&post)) {
+                                remember_source(conn, &key, post.id).map_err(PostError::from)
+                            } else {
+                                Ok(())
+                            }
+                        }
+                        (_, existing) => apply(conn, actor, existing, &key, source),
+                    };
+                    result.map_err(|error| ImportError::Post {
+                        path: source.path.clone(),
+                        error,
+                    })?;
+                }
+                report.record(&source.path, change);
+            }
+            Ok(report)
+        })
+    })
+}
+
+#[test]
+fn parses_front_matter() {
+    let source = parse_source(
+        "2020/hello.md",
+        "---\ntitle: Hello, Wörld\ntags: [Rust, diesel]\npublished: true\ndate: 2020-10-01\n---\n\n# Hi\n",
+    )
+    .unwrap();
+
+    assert_eq!(
+        SourcePost {
+            path: "2020/hello.md".into(),
+            id: None,
+            title: "Hello, Wörld".into(),
+            body: "# Hi".into(),
+            tags: vec!["diesel".into(), "rust".into()],
+            published: true,
+            date: Some(NaiveDate::from_ymd(2020, 10, 1).and_hms(0, 0, 0)),
+            slug: "hello-world".into(),
+        },
+        source
+    );
+}
+
+#[test]
+fn front_matter_defaults_to_a_draft() {
+    let source = parse_source("a.md", "---\ntitle: Draft\nslug: My Slug\n---\nBody").unwrap();
+
+    assert!(!source.published);
+    assert!(source.tags.is_empty());
+    assert_eq!(None, source.date);
+    assert_eq!("my-slug", source.slug);
+}
+
+#[test]
+fn invalid_files_are_explained() {
+    let err = |contents| parse_source("a.md", contents).unwrap_err();
+
+    assert!(err("# No front matter").contains("missing front matter"));
+    assert!(err("---\ntitle: Open\n").contains("not closed"));
+    assert!(err("---\ntitle: Hi\ndate: yesterday\n---\nBody").contains("date"));
+    assert!(err("---\ntitle: Hi\nauthor: me\n---\nBody").contains("author"));
+    assert_eq!("body is required", err("---\ntitle: Hi\n---\n  \n"));
+}
+
+#[test]
+fn read_sources_walks_directories() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir(dir.path().join("2020")).unwrap();
+    fs::write(dir.path().join("2020/b.md"), "---\ntitle: B\n---\nB").unwrap();
+    fs::write(dir.path().join("a.md"), "---\ntitle: A\n---\nA").unwrap();
+    fs::write(dir.path().join("notes.txt"), "ignored").unwrap();
+
+    let paths = read_sources(dir.path())
+        .unwrap()
+        .into_iter()
+        .map(|s| s.path)
+        .collect::<Vec<_>>();
+    assert_eq!(vec!["2020/b.md", "a.md"], paths);
+
+    fs::write(dir.path().join("c.md"), "no front matter").unwrap();
+    match read_sources(dir.path()) {
+        Err(ImportError::Invalid(files)) => assert_eq!("c.md", files[0].0),
+        other => panic!("expected Invalid, got {:?}", other),
+    }
+}
+
+#[test]
+fn import_is_idempotent() {
+    use crate::fixtures::{seed, Profile};
+    use diesel::result::Error;
+
+    let conn = crate::establish_connection();
+    conn.test_transaction::<_, Error, _>(|| {
+        let seeded = seed(&conn, Profile::Minimal).unwrap();
+        let editor = &seeded.users["tess"];
+        // Existing posts are also matched by slug, so the title carries the
+        // fresh editor id to stay clear of posts in a development database.
+        let title = format!("Import test {}", editor.id);
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("import-test.md");
+        let source = format!("---\ntitle: {}\ntags: [rust]\n---\nOne", title);
+        fs::write(&file, source).unwrap();
+
+        let dry = import_dir(&conn, editor, dir.path(), true).unwrap();
+        assert_eq!(1, dry.created);
+        let source = &read_sources(dir.path()).unwrap()[0];
+        let key = source_key(&dir.path().canonicalize().unwrap(), source);
+        assert!(find_existing(&conn, &key, source)?.is_none());
+
+        assert_eq!(
+            1,
+            import_dir(&conn, editor, dir.path(), false)
+                .unwrap()
+                .created
+        );
+        assert_eq!(
+            1,
+            import_dir(&conn, editor, dir.path(), false)
+                .unwrap()
+                .unchanged
+        );
+
+        let published = format!("---\ntitle: {}\npublished: true\n---\nTwo", title);
+        fs::write(&file, published).unwrap();
+        assert_eq!(
+            1,
+            import_dir(&conn, editor, dir.path(), false)
+                .unwrap()
+                .updated
+        );
+        let post = crate::slugs::find_by_slug(&conn, &slugify(&title))?.unwrap();
+        assert!(post.post().published);
+        assert_eq!("Two", post.post().body);
+        assert!(tags_for(&conn, post.post().id)?.is_empty());
+
+        let dated = format!(
+            "---\ntitle: {}\npublished: true\ndate: 2020-10-01\n---\nTwo",
+            title
+        );
+        fs::write(&file, dated).unwrap();
+        import_dir(&conn, editor, dir.path(), false).unwrap();
+        let redated = active_posts().find(post.post().id).first::<Post>(&conn)?;
+        assert_eq!(post.post().version + 1, redated.version);
+        let history = crate::audit::history(&conn, "posts", redated.id)?;
+        let last = history.last().unwrap();
+        assert_eq!(AuditAction::Update, last.action);
+        assert!(last.changed_fields().contains(&"published_at".to_string()));
+
+        Ok(())
+    });
+}
+
+#[test]
+fn new_posts_keep_their_front_matter_slug() {
+    use crate::fixtures::{seed, Profile};
+    use diesel::result::Error;
+
+    let conn = crate::establish_connection();
+    conn.test_transaction::<_, Error, _>(|| {
+        let seeded = seed(&conn, Profile::Minimal).unwrap();
+        let author = &seeded.users["ruby"];
+        let slug = format!("short-{}", author.id);
+        let source = format!("---\ntitle: A long title\nslug: {}\n---\nBody", slug);
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("renamed.md"), &source).unwrap();
+
+        assert_eq!(
+            1,
+            import_dir(&conn, author, dir.path(), false)
+                .unwrap()
+                .created
+        );
+        let post = crate::slugs::find_by_slug(&conn, &slug)?.unwrap();
+        assert!(post.redirect_to().is_none());
+        assert_eq!("A long title", post.post().title);
+
+        let other = tempfile::tempdir().unwrap();
+        fs::write(other.path().join("moved.md"), &source).unwrap();
+        assert_eq!(
+            1,
+            import_dir(&conn, author, other.path(), false)
+                .unwrap()
+                .unchanged
+        );
+
+        Ok(())
+    });
+}
+
+#[test]
+fn readers_cannot_retag_posts() {
+    use crate::fixtures::{seed, Profile};
+    use diesel::result::Error;
+
+    let conn = crate::establish_connection();
+    conn.test_transaction::<_, Error, _>(|| {
+        let seeded = seed(&conn, Profile::Minimal).unwrap();
+        let (reader, post) = (&seeded.users["jim"], &seeded.posts["welcome"]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let retagged = crate::exporter::render_source(post, &["hijacked".to_string()]);
+        fs::write(dir.path().join("welcome.md"), retagged).unwrap();
+
+        match import_dir(&conn, reader, dir.path(), false) {
+            Err(ImportError::Post {
+                error: PostError::Forbidden(forbidden),
+                ..
+            }) => assert_eq!(Action::Edit, forbidden.action),
+            other => panic!("expected Forbidden, got {:?}", other),
+        }
+        assert!(!tags_for(&conn, post.id)?.contains(&"hijacked".to_string()));
+
+        Ok(())
+    });
+}
+
+#[test]
+fn same_names_under_other_roots_are_other_posts() {
+    use crate::fixtures::{seed, Profile};
+    use diesel::result::Error;
+
+    let conn = crate::establish_connection();
+    conn.test_transaction::<_, Error, _>(|| {
+        let seeded = seed(&conn, Profile::Minimal).unwrap();
+        let editor = &seeded.users["tess"];
+        let title = format!("First root {}", editor.id);
+
+        let (first, second) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        fs::write(
+            first.path().join("a.md"),
+            format!("---\ntitle: {}\n---\nOne", title),
+        )
+        .unwrap();
+        fs::write(
+            second.path().join("a.md"),
+            format!("---\ntitle: Second root {}\n---\nTwo", editor.id),
+        )
+        .unwrap();
+
+        let imported = import_dir(&conn, editor, first.path(), false).unwrap();
+        assert_eq!(1, imported.created);
+        let imported = import_dir(&conn, editor, second.path(), false).unwrap();
+        assert_eq!(1, imported.created);
+
+        let first_key = source_key(
+            &first.path().canonicalize().unwrap(),
+            &read_sources(first.path()).unwrap()[0],
+        );
+        let post = post_sources::table
+            .inner_join(posts::table)
+            .filter(post_sources::path.eq(first_key))
+            .select(posts::all_columns)
+            .first::<Post>(&conn)?;
+        assert_eq!(title, post.title);
+
+        Ok(())
+    });
+}
@@ -2,64 +2,316 @@
 extern crate diesel;
 extern crate dotenv;
 
+pub mod audit;
+pub mod auth;
+pub mod backup;
+pub mod editing;
+pub mod exporter;
+pub mod feed;
+pub mod fixtures;
+pub mod generator;
+pub mod guard;
+pub mod importer;
+pub mod locking;
+pub mod logging;
+pub mod markdown;
+pub mod metrics;
 pub mod models;
+pub mod ndjson;
+pub mod policy;
+pub mod publishing;
+pub mod retry;
 pub mod schema;
-
-use self::models::{NewPost, Post};
+pub mod slugs;
+pub mod soft_delete;
+pub mod static_site;
+pub mod tags;
+pub mod tokens;
+pub mod user_csv;
+pub mod validation;
+
+use self::audit::Audited;
+use self::models::{AuditAction, HairColor, NewPost, Post, Role};
+use self::retry::{transaction_with_retry, RetryPolicy};
+use self::validation::Validate;
+use diesel::connection::SimpleConnection;
 use diesel::debug_query;
 use diesel::insert_into;
 use diesel::mysql::Mysql;
 use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool};
 
 use chrono::NaiveDateTime;
 use schema::users;
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 
 use dotenv::dotenv;
 use std::env;
 use std::error::Error;
 
-#[derive(QueryableByName, Queryable, PartialEq, Debug)]
+#[derive(QueryableByName, Queryable, Insertable, Serialize, Deserialize, PartialEq, Debug)]
 #[table_name = "users"]
 pub struct User {
     pub id: i32,
     pub name: String,
-    pub hair_color: Option<String>,
+    pub hair_color: Option<HairColor>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub deleted_at: Option<NaiveDateTime>,
+    pub version: i32,
+    pub email: Option<String>,
+    pub password_hash: Option<String>,
+    pub role: Role,
 }
 
-#[derive(Deserialize, Insertable)]
+#[derive(Deserialize, Insertable, Debug)]
 #[table_name = "users"]
 pub struct UserForm<'a> {
     name: &'a str,
-    hair_color: Option<&'a str>,
+    hair_color: Option<HairColor>,
+}
+
+impl<'a> UserForm<'a> {
+    pub fn new(name: &'a str, hair_color: Option<HairColor>) -> Self {
+        UserForm { name, hair_color }
+    }
+}
+
+/// Fields of a user that may change; `None` leaves a field untouched.
+#[derive(AsChangeset, Default)]
+#[table_name = "users"]
+pub struct UserChanges<'a> {
+    pub name: Option<&'a str>,
+    pub hair_color: Option<Option<HairColor>>,
+}
+
+/// The connection every library function takes: a `DbConnection` that
+/// logs its statements under `logging::TARGET`.
+pub type DbConnection = logging::LoggingConnection;
+
+pub fn establish_connection() -> DbConnection {
+    establish_logging_connection(logging::LogConfig::default())
+}
+
+pub type MysqlPool = Pool<ConnectionManager<DbConnection>>;
+
+/// Timestamps are stored and compared in UTC. Sessions run in UTC so that
+/// `CURRENT_TIMESTAMP` and column defaults agree with `Utc::now()`.
+const UTC_SESSION: &str = "SET time_zone = '+00:00'";
+
+#[derive(Debug)]
+struct UtcSession;
+
+impl CustomizeConnection<DbConnection, diesel::r2d2::Error> for UtcSession {
+    fn on_acquire(&self, conn: &mut DbConnection) -> Result<(), diesel::r2d2::Error> {
+        conn.batch_execute(UTC_SESSION)
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
 }
 
-pub fn establish_connection() -> MysqlConnection {
+pub fn establish_pool() -> MysqlPool {
     dotenv().ok();
 
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    MysqlConnection::establish(&database_url)
-        .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
+    let pool = Pool::builder()
+        .connection_customizer(Box::new(UtcSession))
+        .build(ConnectionManager::new(database_url.as_str()))
+        .unwrap_or_else(|_| panic!("Error creating pool for {}", database_url));
+    metrics::observe_pool(&pool);
+    pool
 }
 
-pub fn create_post(conn: &MysqlConnection, title: &str, body: &str) -> Post {
+pub fn establish_logging_connection(config: logging::LogConfig) -> logging::LoggingConnection {
+    dotenv().ok();
+
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let inner = MysqlConnection::establish(&database_url)
+        .unwrap_or_else(|_| panic!("Error connecting to {}", database_url));
+    let conn = logging::LoggingConnection::new(inner, config);
+    conn.batch_execute(UTC_SESSION)
+        .unwrap_or_else(|e| panic!("Error setting the session time zone: {}", e));
+    conn
+}
+
+/// Saves a draft written by `author`, who must be allowed to create posts.
+/// The title and body are validated before anything is sent to the database.
+pub fn create_post(
+    conn: &DbConnection,
+    author: &User,
+    title: &str,
+    body: &str,
+) -> Result<Post, publishing::PostError> {
     use schema::posts;
-    let new_post = NewPost { title, body };
+    policy::authorize(author, policy::Action::Create, None)?;
+    let new_post = NewPost {
+        title,
+        body,
+        author_id: Some(author.id),
+    }
+    .validate()?;
+
+    Ok(metrics::track("create_post", || {
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            let slug = slugs::unique_slug(conn, new_post.title, None)?;
+            let body_html = markdown::render_html(new_post.body);
+            diesel::insert_into(posts::table)
+                .values((
+                    &new_post,
+                    posts::slug.eq(&slug),
+                    posts::body_html.eq(&body_html),
+                ))
+                .execute(conn)?;
+
+            let post = posts::table.order(posts::id.desc()).first(conn)?;
+            audit::record(conn, Some(author), AuditAction::Create, None, Some(&post))?;
+            Ok(post)
+        })
+    })?)
+}
+
+/// Hands `rows` to `insert` `chunk_size` at a time, one multi-row INSERT per
+/// chunk, and calls `progress` with the running total after each.
+pub(crate) fn insert_chunked<T, I, F, P>(
+    rows: I,
+    chunk_size: usize,
+    mut insert: F,
+    mut progress: P,
+) -> QueryResult<usize>
+where
+    I: IntoIterator<Item = T>,
+    F: FnMut(&[T]) -> QueryResult<usize>,
+    P: FnMut(usize),
+{
+    let chunk_size = chunk_size.max(1);
+    let mut rows = rows.into_iter().peekable();
+    let mut inserted = 0;
+    while rows.peek().is_some() {
+        let chunk = rows.by_ref().take(chunk_size).collect::<Vec<_>>();
+        inserted += insert(&chunk)?;
+        progress(inserted);
+    }
+    Ok(inserted)
+}
+
+#[test]
+fn inserts_in_chunks_with_progress() {
+    let mut chunks = Vec::new();
+    let mut progress = Vec::new();
+    let inserted = insert_chunked(
+        1..=5,
+        2,
+        |chunk| {
+            chunks.push(chunk.to_vec());
+            Ok(chunk.len())
+        },
+        |done| progress.push(done),
+    )
+    .unwrap();
+
+    assert_eq!(5, inserted);
+    assert_eq!(vec![vec![1, 2], vec![3, 4], vec![5]], chunks);
+    assert_eq!(vec![2, 4, 5], progress);
+}
+
+/// Who the demos below record in the audit log: the user logged in with
+/// `API_TOKEN`, if any.
+fn demo_actor(conn: &DbConnection) -> Option<User> {
+    tokens::user_from_env(conn).ok()
+}
+
+/// Inserts each of `names` with its own single-column INSERT.
+pub fn insert_names(
+    conn: &DbConnection,
+    actor: Option<&User>,
+    names: &[&str],
+) -> QueryResult<usize> {
+    use schema::users::dsl::*;
+
+    User::audited_inserts(conn, actor, || {
+        names
+            .iter()
+            .map(|user_name| insert_into(users).values(name.eq(user_name)).execute(conn))
+            .sum()
+    })
+}
+
+/// Inserts `forms` as `(name, hair_color)` tuples in one multi-row INSERT.
+pub fn insert_tuples(
+    conn: &DbConnection,
+    actor: Option<&User>,
+    forms: &[UserForm],
+) -> QueryResult<usize> {
+    use schema::users::dsl::*;
+
+    let values = forms
+        .iter()
+        .map(|form| (name.eq(form.name), hair_color.eq(&form.hair_color)))
+        .collect::<Vec<_>>();
+    User::audited_inserts(conn, actor, || {
+        insert_into(users).values(&values).execute(conn)
+    })
+}
+
+/// Inserts `forms` as `Insertable` structs in one multi-row INSERT.
+pub fn insert_forms(
+    conn: &DbConnection,
+    actor: Option<&User>,
+    forms: &[UserForm],
+) -> QueryResult<usize> {
+    User::audited_inserts(conn, actor, || {
+        insert_into(users::table).values(forms).execute(conn)
+    })
+}
+
+/// Writes `(id, name)` rows in one REPLACE, deleting and reinserting the
+/// users whose ids are taken.
+pub fn replace_names(
+    conn: &DbConnection,
+    actor: Option<&User>,
+    rows: &[(i32, &str)],
+) -> QueryResult<usize> {
+    use schema::users::dsl::*;
+
+    let ids = rows.iter().map(|&(user_id, _)| user_id).collect::<Vec<_>>();
+    let values = rows
+        .iter()
+        .map(|&(user_id, user_name)| (id.eq(user_id), name.eq(user_name)))
+        .collect::<Vec<_>>();
+    User::audited(conn, actor, AuditAction::Update, &ids, || {
+        diesel::replace_into(users).values(&values).execute(conn)
+    })
+}
 
-    diesel::insert_into(posts::table)
-        .values(&new_post)
-        .execute(conn)
-        .expect("Error saving new post");
+/// Writes `(id, name)` rows in one INSERT IGNORE, skipping the ids that are
+/// taken.
+pub fn insert_or_ignore_names(
+    conn: &DbConnection,
+    actor: Option<&User>,
+    rows: &[(i32, &str)],
+) -> QueryResult<usize> {
+    use schema::users::dsl::*;
 
-    posts::table.order(posts::id.desc()).first(conn).unwrap()
+    let ids = rows.iter().map(|&(user_id, _)| user_id).collect::<Vec<_>>();
+    let values = rows
+        .iter()
+        .map(|&(user_id, user_name)| (id.eq(user_id), name.eq(user_name)))
+        .collect::<Vec<_>>();
+    User::audited(conn, actor, AuditAction::Create, &ids, || {
+        diesel::insert_or_ignore_into(users)
+            .values(&values)
+            .execute(conn)
+    })
 }
 
-pub fn insert_default_values(conn: &MysqlConnection) -> QueryResult<usize> {
+pub fn insert_default_values(conn: &DbConnection) -> QueryResult<usize> {
     use schema::users::dsl::*;
 
-    insert_into(users).default_values().execute(conn)
+    let actor = demo_actor(conn);
+    metrics::track("insert_default_values", || {
+        User::audited_inserts(conn, actor.as_ref(), || {
+            insert_into(users).default_values().execute(conn)
+        })
+    })
 }
 
 #[test]
@@ -71,10 +323,11 @@ fn examine_sql_from_insert_default_values() {
     assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
 }
 
-pub fn insert_single_column(conn: &MysqlConnection) -> QueryResult<usize> {
-    use schema::users::dsl::*;
-
-    insert_into(users).values(name.eq("Sean")).execute(conn)
+pub fn insert_single_column(conn: &DbConnection) -> QueryResult<usize> {
+    let actor = demo_actor(conn);
+    metrics::track("insert_single_column", || {
+        insert_names(conn, actor.as_ref(), &["Sean"])
+    })
 }
 
 #[test]
@@ -87,31 +340,41 @@ fn examine_sql_from_insert_single_column() {
     assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
 }
 
-pub fn insert_multiple_columns(conn: &MysqlConnection) -> QueryResult<usize> {
+pub fn insert_multiple_columns(conn: &DbConnection) -> QueryResult<usize> {
     use schema::users::dsl::*;
 
-    insert_into(users)
-        .values((name.eq("Tess"), hair_color.eq("Brown")))
-        .execute(conn)
+    let actor = demo_actor(conn);
+    metrics::track("insert_multiple_columns", || {
+        User::audited_inserts(conn, actor.as_ref(), || {
+            insert_into(users)
+                .values((name.eq("Tess"), hair_color.eq(HairColor::Brown)))
+                .execute(conn)
+        })
+    })
 }
 
 #[test]
 fn examine_sql_from_insert_multiple_columns() {
     use schema::users::dsl::*;
 
-    let query = insert_into(users).values((name.eq("Tess"), hair_color.eq("Brown")));
+    let query = insert_into(users).values((name.eq("Tess"), hair_color.eq(HairColor::Brown)));
     let sql = "INSERT INTO `users` (`name`, `hair_color`) VALUES (?, ?) \
-               -- binds: [\"Tess\", \"Brown\"]";
+               -- binds: [\"Tess\", Brown]";
     assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
 }
 
-pub fn insert_insertable_struct(conn: &MysqlConnection) -> Result<(), Box<dyn Error>> {
+pub fn insert_insertable_struct(conn: &DbConnection) -> Result<(), Box<dyn Error>> {
     use schema::users::dsl::*;
 
     let json = r#"{ "name": "Sean", "hair_color": "Black" }"#;
-    let user_form = serde_json::from_str::<UserForm>(json)?;
+    let user_form = serde_json::from_str::<UserForm>(json)?.validate()?;
 
-    insert_into(users).values(&user_form).execute(conn)?;
+    let actor = demo_actor(conn);
+    metrics::track("insert_insertable_struct", || {
+        User::audited_inserts(conn, actor.as_ref(), || {
+            insert_into(users).values(&user_form).execute(conn)
+        })
+    })?;
 
     Ok(())
 }
@@ -124,17 +387,22 @@ fn examine_sql_from_insertable_struct() {
     let user_form = serde_json::from_str::<UserForm>(json).unwrap();
     let query = insert_into(users).values(&user_form);
     let sql = "INSERT INTO `users` (`name`, `hair_color`) VALUES (?, ?) \
-               -- binds: [\"Sean\", \"Black\"]";
+               -- binds: [\"Sean\", Black]";
     assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
 }
 
-pub fn insert_insertable_struct_option(conn: &MysqlConnection) -> Result<(), Box<dyn Error>> {
+pub fn insert_insertable_struct_option(conn: &DbConnection) -> Result<(), Box<dyn Error>> {
     use schema::users::dsl::*;
 
     let json = r#"{ "name": "Ruby", "hair_color": null }"#;
-    let user_form = serde_json::from_str::<UserForm>(json)?;
+    let user_form = serde_json::from_str::<UserForm>(json)?.validate()?;
 
-    insert_into(users).values(&user_form).execute(conn)?;
+    let actor = demo_actor(conn);
+    metrics::track("insert_insertable_struct_option", || {
+        User::audited_inserts(conn, actor.as_ref(), || {
+            insert_into(users).values(&user_form).execute(conn)
+        })
+    })?;
 
     Ok(())
 }
@@ -151,12 +419,17 @@ fn examine_sql_from_insertable_struct_option() {
     assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
 }
 
-pub fn insert_single_column_batch(conn: &MysqlConnection) -> QueryResult<usize> {
+pub fn insert_single_column_batch(conn: &DbConnection) -> QueryResult<usize> {
     use schema::users::dsl::*;
 
-    insert_into(users)
-        .values(&vec![name.eq("Sean"), name.eq("Tess")])
-        .execute(conn)
+    let actor = demo_actor(conn);
+    metrics::track("insert_single_column_batch", || {
+        User::audited_inserts(conn, actor.as_ref(), || {
+            insert_into(users)
+                .values(&vec![name.eq("Sean"), name.eq("Tess")])
+                .execute(conn)
+        })
+    })
 }
 
 #[test]
@@ -170,12 +443,17 @@ fn examine_sql_from_insert_single_column_batch() {
     assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
 }
 
-pub fn insert_single_column_batch_with_default(conn: &MysqlConnection) -> QueryResult<usize> {
+pub fn insert_single_column_batch_with_default(conn: &DbConnection) -> QueryResult<usize> {
     use schema::users::dsl::*;
 
-    insert_into(users)
-        .values(&vec![Some(name.eq("Sean")), None])
-        .execute(conn)
+    let actor = demo_actor(conn);
+    metrics::track("insert_single_column_batch_with_default", || {
+        User::audited_inserts(conn, actor.as_ref(), || {
+            insert_into(users)
+                .values(&vec![Some(name.eq("Sean")), None])
+                .execute(conn)
+        })
+    })
 }
 
 #[test]
@@ -189,15 +467,22 @@ fn examine_sql_from_insert_single_column_batch_with_default() {
     assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
 }
 
-pub fn insert_tuple_batch(conn: &MysqlConnection) -> QueryResult<usize> {
-    use schema::users::dsl::*;
-
-    insert_into(users)
-        .values(&vec![
-            (name.eq("Sean"), hair_color.eq("Black")),
-            (name.eq("Tess"), hair_color.eq("Brown")),
-        ])
-        .execute(conn)
+pub fn insert_tuple_batch(conn: &DbConnection) -> QueryResult<usize> {
+    let actor = demo_actor(conn);
+    metrics::track("insert_tuple_batch", || {
+        let outcome =
+            transaction_with_retry(conn, "insert_tuple_batch", &RetryPolicy::default(), || {
+                insert_tuples(
+                    conn,
+                    actor.as_ref(),
+                    &[
+                        UserForm::new("Sean", Some(HairColor::Black)),
+                        UserForm::new("Tess", Some(HairColor::Brown)),
+                    ],
+                )
+            });
+        outcome.result
+    })
 }
 
 #[test]
@@ -205,25 +490,38 @@ fn examine_sql_from_insert_tuple_batch() {
     use schema::users::dsl::*;
 
     let values = vec![
-        (name.eq("Sean"), hair_color.eq("Black")),
-        (name.eq("Tess"), hair_color.eq("Brown")),
+        (name.eq("Sean"), hair_color.eq(HairColor::Black)),
+        (name.eq("Tess"), hair_color.eq(HairColor::Brown)),
     ];
     let query = insert_into(users).values(&values);
     let sql = "INSERT INTO `users` (`name`, `hair_color`) \
                VALUES (?, ?), (?, ?) \
-               -- binds: [\"Sean\", \"Black\", \"Tess\", \"Brown\"]";
+               -- binds: [\"Sean\", Black, \"Tess\", Brown]";
     assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
 }
 
-pub fn insert_tuple_batch_with_default(conn: &MysqlConnection) -> QueryResult<usize> {
+pub fn insert_tuple_batch_with_default(conn: &DbConnection) -> QueryResult<usize> {
     use schema::users::dsl::*;
 
-    insert_into(users)
-        .values(&vec![
-            (name.eq("Sean"), Some(hair_color.eq("Black"))),
-            (name.eq("Ruby"), None),
-        ])
-        .execute(conn)
+    let actor = demo_actor(conn);
+    metrics::track("insert_tuple_batch_with_default", || {
+        let outcome = transaction_with_retry(
+            conn,
+            "insert_tuple_batch_with_default",
+            &RetryPolicy::default(),
+            || {
+                User::audited_inserts(conn, actor.as_ref(), || {
+                    insert_into(users)
+                        .values(&vec![
+                            (name.eq("Sean"), Some(hair_color.eq(HairColor::Black))),
+                            (name.eq("Ruby"), None),
+                        ])
+                        .execute(conn)
+                })
+            },
+        );
+        outcome.result
+    })
 }
 
 #[test]
@@ -231,26 +529,30 @@ fn examine_sql_from_insert_tuple_batch_with_default() {
     use schema::users::dsl::*;
 
     let values = vec![
-        (name.eq("Sean"), Some(hair_color.eq("Black"))),
+        (name.eq("Sean"), Some(hair_color.eq(HairColor::Black))),
         (name.eq("Ruby"), None),
     ];
     let query = insert_into(users).values(&values);
     let sql = "INSERT INTO `users` (`name`, `hair_color`) \
                VALUES (?, ?), (?, DEFAULT) \
-               -- binds: [\"Sean\", \"Black\", \"Ruby\"]";
+               -- binds: [\"Sean\", Black, \"Ruby\"]";
     assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
 }
 
-pub fn insert_insertable_struct_batch(conn: &MysqlConnection) -> Result<(), Box<dyn Error>> {
-    use schema::users::dsl::*;
-
+pub fn insert_insertable_struct_batch(conn: &DbConnection) -> Result<(), Box<dyn Error>> {
     let json = r#"[
         { "name": "Sean", "hair_color": "Black" },
         { "name": "Tess", "hair_color": "Brown" }
     ]"#;
-    let user_form = serde_json::from_str::<Vec<UserForm>>(json)?;
+    let user_form = serde_json::from_str::<Vec<UserForm>>(json)?
+        .into_iter()
+        .map(Validate::validate)
+        .collect::<Result<Vec<_>, _>>()?;
 
-    insert_into(users).values(&user_form).execute(conn)?;
+    let actor = demo_actor(conn);
+    metrics::track("insert_insertable_struct_batch", || {
+        insert_forms(conn, actor.as_ref(), &user_form)
+    })?;
 
     Ok(())
 }
@@ -267,7 +569,7 @@ fn examine_sql_from_insertable_struct_batch() {
     let query = insert_into(users).values(&user_form);
     let sql = "INSERT INTO `users` (`name`, `hair_color`) \
                VALUES (?, ?), (?, ?) \
-               -- binds: [\"Sean\", \"Black\", \"Tess\", \"Brown\"]";
+               -- binds: [\"Sean\", Black, \"Tess\", Brown]";
     assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
 }
 
@@ -306,6 +608,11 @@ fn insert_get_results_batch() {
                 hair_color: None,
                 created_at: now,
                 updated_at: now,
+                deleted_at: None,
+                version: 0,
+                email: None,
+                password_hash: None,
+                role: Role::Reader,
             },
             User {
                 id: 2,
@@ -313,6 +620,11 @@ fn insert_get_results_batch() {
                 hair_color: None,
                 created_at: now,
                 updated_at: now,
+                deleted_at: None,
+                version: 0,
+                email: None,
+                password_hash: None,
+                role: Role::Reader,
             },
         ];
         assert_eq!(expected_users, inserted_users);
@@ -336,7 +648,9 @@ fn examine_sql_from_insert_get_results_batch() {
     let load_query = users.order(id.desc());
     let load_sql = "SELECT `users`.`id`, `users`.`name`, \
                     `users`.`hair_color`, `users`.`created_at`, \
-                    `users`.`updated_at` \
+                    `users`.`updated_at`, `users`.`deleted_at`, \
+                    `users`.`version`, `users`.`email`, `users`.`password_hash`, \
+                    `users`.`role` \
                     FROM `users` \
                     ORDER BY `users`.`id` DESC \
                     -- binds: []";
@@ -368,6 +682,11 @@ fn insert_get_result() {
             hair_color: None,
             created_at: now,
             updated_at: now,
+            deleted_at: None,
+            version: 0,
+            email: None,
+            password_hash: None,
+            role: Role::Reader,
         };
         assert_eq!(expected_user, inserted_user);
 
@@ -388,21 +707,28 @@ fn examine_sql_from_insert_get_result() {
     let load_query = users.order(id.desc());
     let load_sql = "SELECT `users`.`id`, `users`.`name`, \
                     `users`.`hair_color`, `users`.`created_at`, \
-                    `users`.`updated_at` \
+                    `users`.`updated_at`, `users`.`deleted_at`, \
+                    `users`.`version`, `users`.`email`, `users`.`password_hash`, \
+                    `users`.`role` \
                     FROM `users` \
                     ORDER BY `users`.`id` DESC \
                     -- binds: []";
     assert_eq!(load_sql, debug_query::<Mysql, _>(&load_query).to_string());
 }
 
-pub fn explicit_returning(conn: &MysqlConnection) -> QueryResult<i32> {
+pub fn explicit_returning(conn: &DbConnection) -> QueryResult<i32> {
     use diesel::result::Error;
     use schema::users::dsl::*;
 
-    conn.transaction::<_, Error, _>(|| {
-        insert_into(users).values(name.eq("Ruby")).execute(conn)?;
+    let actor = demo_actor(conn);
+    metrics::track("explicit_returning", || {
+        metrics::track_transaction("explicit_returning", || {
+            User::audited_inserts::<_, Error, _>(conn, actor.as_ref(), || {
+                insert_into(users).values(name.eq("Ruby")).execute(conn)?;
 
-        users.select(id).order(id.desc()).first(conn)
+                users.select(id).order(id.desc()).first(conn)
+            })
+        })
     })
 }
 
@@ -425,11 +751,15 @@ pub fn some_users() -> Vec<User> {
     let connection = establish_connection();
 
     use self::schema::users::dsl::*;
-    let all_name = users.select(name).load::<String>(&connection).unwrap();
+    use self::soft_delete::active_users;
+    let all_name = active_users()
+        .select(name)
+        .load::<String>(&connection)
+        .unwrap();
 
     println!("all_name : {:?}", all_name);
 
-    let distinct_name = users
+    let distinct_name = active_users()
         .select(name)
         .distinct()
         .load::<String>(&connection)
@@ -437,13 +767,16 @@ pub fn some_users() -> Vec<User> {
 
     println!("distinct_name : {:?}", distinct_name);
 
-    let count = users.count().execute(&connection).unwrap();
+    let count = active_users().count().execute(&connection).unwrap();
     println!("there are {} users ?", count);
 
-    let count2: i64 = users.count().get_result::<i64>(&connection).unwrap();
+    let count2: i64 = active_users()
+        .count()
+        .get_result::<i64>(&connection)
+        .unwrap();
     println!("there are {} users !", count2);
 
-    users
+    active_users()
         .order((created_at.desc(), id.desc()))
         .filter(name.eq("Ruby"))
         .limit(5)
@@ -454,66 +787,123 @@ pub fn some_users() -> Vec<User> {
 pub fn all_users() -> QueryResult<Vec<User>> {
     use diesel::sql_query;
     let connection = establish_connection();
-    let users = sql_query("SELECT * FROM users ORDER BY id").load(&connection);
-    users
+    metrics::track("all_users", || {
+        sql_query("SELECT * FROM users WHERE deleted_at IS NULL ORDER BY id").load(&connection)
+    })
 }
 
-pub fn delete_all_users() -> usize {
-    use schema::users::dsl::*;
+/// Soft deletes every user; see `policy::restore_users` to undo it. Only
+/// admins may.
+pub fn delete_all_users(
+    actor: &User,
+    guard: &guard::Guard,
+) -> Result<guard::Outcome<User>, policy::PolicyError> {
+    policy::authorize(actor, policy::Action::ManageUsers, None)?;
+
     let connection = establish_connection();
-    let delete_user_num = diesel::delete(users).execute(&connection).unwrap();
-    delete_user_num
+    metrics::track("delete_all_users", || {
+        connection.transaction(|| {
+            let ids = soft_delete::active_users()
+                .select(users::id)
+                .load::<i32>(&connection)?;
+            User::audited(&connection, Some(actor), AuditAction::Delete, &ids, || {
+                Ok(soft_delete::soft_delete_all_users(&connection, guard)?)
+            })
+        })
+    })
 }
 
+/// Recorded in the audit log as the user logged in with `API_TOKEN`, if any.
 pub fn update_users() -> QueryResult<usize> {
     use schema::users::dsl::*;
+    use soft_delete::active_users;
     let connection = establish_connection();
-
-    let updated_row = diesel::update(users.filter(name.eq("Rust")))
-        .set((name.eq("Ruby"), hair_color.eq(Some("yellow"))))
-        .execute(&connection);
+    let actor = demo_actor(&connection);
+
+    let updated_row = metrics::track("update_users", || {
+        connection.transaction(|| {
+            let ids = active_users()
+                .filter(name.eq("Rust"))
+                .select(id)
+                .load::<i32>(&connection)?;
+            User::audited(
+                &connection,
+                actor.as_ref(),
+                AuditAction::Update,
+                &ids,
+                || {
+                    diesel::update(active_users().filter(name.eq("Rust")))
+                        .set((
+                            name.eq("Ruby"),
+                            hair_color.eq(Some(HairColor::Yellow)),
+                            version.eq(version + 1),
+                        ))
+                        .execute(&connection)
+                },
+            )
+        })
+    });
 
     println!("update Ruby to Rust, updated_row : {:?}", updated_row);
 
-    let updated_row = diesel::update(users.filter(id.eq(1)))
-        .set(name.eq("James"))
-        .execute(&connection);
-
-    updated_row
+    metrics::track("update_users", || {
+        User::audited(
+            &connection,
+            actor.as_ref(),
+            AuditAction::Update,
+            &[1],
+            || {
+                diesel::update(active_users().filter(id.eq(1)))
+                    .set((name.eq("James"), version.eq(version + 1)))
+                    .execute(&connection)
+            },
+        )
+    })
 }
 
+/// Recorded in the audit log as the user logged in with `API_TOKEN`, if any.
 pub fn replace_into_users() {
     use self::schema::users::dsl::*;
     let connection = establish_connection();
+    let actor = demo_actor(&connection);
+
+    let mut attempts = 0;
+    metrics::track("replace_into_users", || {
+        let outcome = transaction_with_retry(
+            &connection,
+            "replace_into_users",
+            &RetryPolicy::default(),
+            || {
+                replace_names(&connection, actor.as_ref(), &[(1, "Sean2"), (2, "Tess2")])?;
+                replace_names(&connection, actor.as_ref(), &[(1, "Jim")])
+            },
+        );
+        attempts = outcome.attempts;
+        outcome.result
+    })
+    .unwrap();
+    println!("replace_into_users attempts : {}", attempts);
 
-    diesel::replace_into(users)
-        .values(&vec![
-            (id.eq(1), name.eq("Sean2")),
-            (id.eq(2), name.eq("Tess2")),
-        ])
-        .execute(&connection)
-        .unwrap();
-
-    diesel::replace_into(users)
-        .values((id.eq(1), name.eq("Jim")))
-        .execute(&connection)
-        .unwrap();
     let names = users.select(name).order(id).load::<String>(&connection);
 
     println!("{:?}", names);
 
-    diesel::insert_or_ignore_into(users)
-        .values((id.eq(1), name.eq("Jim")))
-        .execute(&connection)
-        .unwrap();
-
-    diesel::insert_or_ignore_into(users)
-        .values(&vec![
-            (id.eq(1), name.eq("Sean")),
-            (id.eq(2), name.eq("Tess")),
-        ])
-        .execute(&connection)
-        .unwrap();
+    let mut attempts = 0;
+    metrics::track("insert_or_ignore_into_users", || {
+        let outcome = transaction_with_retry(
+            &connection,
+            "insert_or_ignore_into_users",
+            &RetryPolicy::default(),
+            || {
+                insert_or_ignore_names(&connection, actor.as_ref(), &[(1, "Jim")])?;
+                insert_or_ignore_names(&connection, actor.as_ref(), &[(1, "Sean"), (2, "Tess")])
+            },
+        );
+        attempts = outcome.attempts;
+        outcome.result
+    })
+    .unwrap();
+    println!("insert_or_ignore_into_users attempts : {}", attempts);
 
     let names = users
         .select(name)
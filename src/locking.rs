@@ -0,0 +1,210 @@
+use crate::models::{Post, PostChanges};
+use crate::soft_delete::{active_posts, active_users};
+use crate::{DbConnection, User, UserChanges};
+use diesel::prelude::*;
+
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum UpdateError {
+    /// The row exists but somebody else changed it after `expected` was read.
+    StaleObject {
+        table: &'static str,
+        id: i32,
+        expected: i32,
+        actual: i32,
+    },
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpdateError::StaleObject {
+                table,
+                id,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{}[id={}] was changed by someone else (version {} is now {})",
+                table, id, expected, actual
+            ),
+            UpdateError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for UpdateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            UpdateError::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<diesel::result::Error> for UpdateError {
+    fn from(e: diesel::result::Error) -> Self {
+        UpdateError::Database(e)
+    }
+}
+
+impl crate::metrics::ErrorKind for UpdateError {
+    fn kind(&self) -> &'static str {
+        match self {
+            UpdateError::StaleObject { .. } => "stale_object",
+            UpdateError::Database(e) => e.kind(),
+        }
+    }
+}
+
+/// Applies `changes` to the post only if it is still at `expected_version`,
+/// returning the post with its bumped version. A new body also refreshes the
+/// cached `body_html`, and publishing stamps `published_at` the first time.
+pub(crate) fn update_post(
+    conn: &DbConnection,
+    post_id: i32,
+    expected_version: i32,
+    changes: &PostChanges,
+) -> Result<Post, UpdateError> {
+    use crate::schema::posts::dsl::*;
+
+    let html = changes
+        .body
+        .map(|new_body| body_html.eq(crate::markdown::render_html(new_body)));
+    conn.transaction(|| {
+        let updated = diesel::update(
+            active_posts()
+                .filter(id.eq(post_id))
+                .filter(version.eq(expected_version)),
+        )
+        .set((changes, version.eq(version + 1), html))
+        .execute(conn)?;
+
+        if updated == 0 {
+            let actual = active_posts()
+                .find(post_id)
+                .select(version)
+                .first::<i32>(conn)?;
+            return Err(UpdateError::StaleObject {
+                table: "posts",
+                id: post_id,
+                expected: expected_version,
+                actual,
+            });
+        }
+        if changes.published == Some(true) {
+            diesel::update(posts.find(post_id).filter(published_at.is_null()))
+                .set(published_at.eq(diesel::dsl::now.nullable()))
+                .execute(conn)?;
+        }
+
+        Ok(posts.find(post_id).first(conn)?)
+    })
+}
+
+/// Applies `changes` to the user only if it is still at `expected_version`,
+/// returning the user with its bumped version.
+pub(crate) fn update_user(
+    conn: &DbConnection,
+    user_id: i32,
+    expected_version: i32,
+    changes: &UserChanges,
+) -> Result<User, UpdateError> {
+    use crate::schema::users::dsl::*;
+
+    conn.transaction(|| {
+        let updated = diesel::update(
+            active_users()
+                .filter(id.eq(user_id))
+                .filter(version.eq(expected_version)),
+        )
+        .set((changes, version.eq(version + 1)))
+        .execute(conn)?;
+
+        if updated == 0 {
+            let actual = active_users()
+                .find(user_id)
+                .select(version)
+                .first::<i32>(conn)?;
+            return Err(UpdateError::StaleObject {
+                table: "users",
+                id: user_id,
+                expected: expected_version,
+                actual,
+            });
+        }
+
+        Ok(users.find(user_id).first(conn)?)
+    })
+}
+
+#[test]
+fn examine_sql_from_update_post() {
+    use crate::schema::posts::dsl::*;
+    use diesel::debug_query;
+    use diesel::mysql::Mysql;
+
+    let changes = PostChanges {
+        title: Some("Rust"),
+        ..PostChanges::default()
+    };
+    let query = diesel::update(active_posts().filter(id.eq(1)).filter(version.eq(3)))
+        .set((&changes, version.eq(version + 1)));
+    let sql = "UPDATE `posts` SET `title` = ?, `version` = (`posts`.`version` + ?) \
+               WHERE `posts`.`deleted_at` IS NULL AND `posts`.`id` = ? \
+               AND `posts`.`version` = ? \
+               -- binds: [\"Rust\", 1, 1, 3]";
+    assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
+}
+
+#[test]
+fn examine_sql_from_update_user() {
+    use crate::schema::users::dsl::*;
+    use diesel::debug_query;
+    use diesel::mysql::Mysql;
+
+    let changes = UserChanges {
+        hair_color: Some(None),
+        ..UserChanges::default()
+    };
+    let query = diesel::update(active_users().filter(id.eq(2)).filter(version.eq(0)))
+        .set((&changes, version.eq(version + 1)));
+    let sql = "UPDATE `users` SET `hair_color` = ?, `version` = (`users`.`version` + ?) \
+               WHERE `users`.`deleted_at` IS NULL AND `users`.`id` = ? \
+               AND `users`.`version` = ? \
+               -- binds: [None, 1, 2, 0]";
+    assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
+}
+
+#[test]
+fn stale_update_is_rejected() {
+    use diesel::result::Error;
+
+    let conn = crate::establish_connection();
+    conn.test_transaction::<_, Error, _>(|| {
+        let seeded = crate::fixtures::seed(&conn, crate::fixtures::Profile::Minimal).unwrap();
+        let post =
+            crate::create_post(&conn, &seeded.users["ruby"], "Optimistic", "locking").unwrap();
+        let first = PostChanges {
+            title: Some("First editor"),
+            ..PostChanges::default()
+        };
+        let second = PostChanges {
+            title: Some("Second editor"),
+            ..PostChanges::default()
+        };
+
+        let updated = update_post(&conn, post.id, post.version, &first).unwrap();
+        assert_eq!(post.version + 1, updated.version);
+
+        match update_post(&conn, post.id, post.version, &second) {
+            Err(UpdateError::StaleObject { actual, .. }) => assert_eq!(updated.version, actual),
+            other => panic!("expected StaleObject, got {:?}", other.map(|p| p.title)),
+        }
+
+        Ok(())
+    });
+}
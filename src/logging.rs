@@ -0,0 +1,373 @@
+use diesel::connection::{AnsiTransactionManager, SimpleConnection};
+use diesel::debug_query;
+use diesel::deserialize::{Queryable, QueryableByName};
+use diesel::mysql::Mysql;
+use diesel::prelude::*;
+use diesel::query_builder::{AsQuery, QueryFragment, QueryId};
+use diesel::sql_types::HasSqlType;
+use log::Level;
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+thread_local! {
+    static LABEL: Cell<&'static str> = const { Cell::new("-") };
+}
+
+/// Log target used for every statement, so SQL logging can be filtered on
+/// its own (e.g. `RUST_LOG=diesel_demo::sql=debug`).
+pub const TARGET: &str = "diesel_demo::sql";
+
+const REDACTED: &str = "[REDACTED]";
+
+#[derive(Clone, Debug)]
+pub struct LogConfig {
+    /// Statements taking at least this long are logged as warnings.
+    pub slow_query: Duration,
+    /// Binds of statements touching any of these columns are not logged.
+    pub redacted_columns: Vec<String>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            slow_query: Duration::from_millis(100),
+            redacted_columns: vec!["password_hash".to_string()],
+        }
+    }
+}
+
+impl LogConfig {
+    pub fn slow_query(mut self, threshold: Duration) -> Self {
+        self.slow_query = threshold;
+        self
+    }
+
+    pub fn redact(mut self, column: &str) -> Self {
+        self.redacted_columns.push(column.to_string());
+        self
+    }
+
+    /// Binds are positional, so a statement mentioning a redacted column has
+    /// all of its binds hidden rather than guessing which one is sensitive.
+    fn redacts(&self, sql: &str) -> bool {
+        self.redacted_columns
+            .iter()
+            .any(|column| sql.contains(&format!("`{}`", column)))
+    }
+}
+
+/// Attributes statements run on this thread to a label until dropped, when
+/// the previous label comes back, even if the operation panicked.
+#[must_use]
+pub struct LabelGuard {
+    previous: &'static str,
+}
+
+impl Drop for LabelGuard {
+    fn drop(&mut self) {
+        LABEL.with(|label| label.set(self.previous));
+    }
+}
+
+pub fn label(label: &'static str) -> LabelGuard {
+    LabelGuard {
+        previous: LABEL.with(|current| current.replace(label)),
+    }
+}
+
+/// Runs `f`, attributing every statement it issues to `label`.
+pub fn labelled<T, F>(label: &'static str, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let _label = self::label(label);
+    f()
+}
+
+pub fn current_label() -> &'static str {
+    LABEL.with(Cell::get)
+}
+
+/// Receives the level and message of every statement logged.
+pub type Sink = Box<dyn Fn(Level, &str) + Send>;
+
+fn log_entry(level: Level, message: &str) {
+    log::log!(target: TARGET, level, "{}", message);
+}
+
+/// A `MysqlConnection` that logs every statement it runs with its binds,
+/// duration, row count and the label of the operation that issued it.
+pub struct LoggingConnection {
+    inner: MysqlConnection,
+    config: LogConfig,
+    sink: Sink,
+}
+
+impl LoggingConnection {
+    /// Logs through the `log` crate under `TARGET`.
+    pub fn new(inner: MysqlConnection, config: LogConfig) -> Self {
+        LoggingConnection {
+            inner,
+            config,
+            sink: Box::new(log_entry),
+        }
+    }
+
+    /// Sends entries to `sink` instead of the `log` crate.
+    pub fn with_sink(mut self, sink: Sink) -> Self {
+        self.sink = sink;
+        self
+    }
+
+    pub fn into_inner(self) -> MysqlConnection {
+        self.inner
+    }
+
+    fn instrument<T, F>(&self, statement: String, f: F, rows: fn(&T) -> usize) -> QueryResult<T>
+    where
+        F: FnOnce() -> QueryResult<T>,
+    {
+        let start = Instant::now();
+        let result = f();
+        let outcome = result.as_ref().map(rows).map_err(ToString::to_string);
+        let (level, message) = entry(&self.config, &statement, start.elapsed(), outcome);
+        (self.sink)(level, &message);
+        result
+    }
+}
+
+/// The level and message logged for one statement: its rows affected or
+/// returned on success, its error otherwise.
+fn entry(
+    config: &LogConfig,
+    statement: &str,
+    elapsed: Duration,
+    outcome: Result<usize, String>,
+) -> (Level, String) {
+    let (sql, binds) = split_binds(statement);
+    let binds = if config.redacts(sql) { REDACTED } else { binds };
+
+    match outcome {
+        Ok(rows) => (
+            level_for(elapsed, config.slow_query),
+            format!(
+                "label={} duration_ms={:.3} rows={} sql={:?} binds={}",
+                current_label(),
+                as_millis(elapsed),
+                rows,
+                sql,
+                binds
+            ),
+        ),
+        Err(e) => (
+            Level::Error,
+            format!(
+                "label={} duration_ms={:.3} sql={:?} binds={} error={:?}",
+                current_label(),
+                as_millis(elapsed),
+                sql,
+                binds,
+                e
+            ),
+        ),
+    }
+}
+
+impl SimpleConnection for LoggingConnection {
+    fn batch_execute(&self, query: &str) -> QueryResult<()> {
+        self.instrument(
+            format!("{} -- binds: []", query),
+            || self.inner.batch_execute(query),
+            |_| 0,
+        )
+    }
+}
+
+impl Connection for LoggingConnection {
+    type Backend = Mysql;
+    type TransactionManager = AnsiTransactionManager;
+
+    fn establish(database_url: &str) -> ConnectionResult<Self> {
+        MysqlConnection::establish(database_url)
+            .map(|inner| LoggingConnection::new(inner, LogConfig::default()))
+    }
+
+    fn execute(&self, query: &str) -> QueryResult<usize> {
+        self.instrument(
+            format!("{} -- binds: []", query),
+            || self.inner.execute(query),
+            |count| *count,
+        )
+    }
+
+    fn query_by_index<T, U>(&self, source: T) -> QueryResult<Vec<U>>
+    where
+        T: AsQuery,
+        T::Query: QueryFragment<Mysql> + QueryId,
+        Mysql: HasSqlType<T::SqlType>,
+        U: Queryable<T::SqlType, Mysql>,
+    {
+        let query = source.as_query();
+        let statement = debug_query::<Mysql, _>(&query).to_string();
+        self.instrument(statement, || self.inner.query_by_index(query), Vec::len)
+    }
+
+    fn query_by_name<T, U>(&self, source: &T) -> QueryResult<Vec<U>>
+    where
+        T: QueryFragment<Mysql> + QueryId,
+        U: QueryableByName<Mysql>,
+    {
+        let statement = debug_query::<Mysql, _>(source).to_string();
+        self.instrument(statement, || self.inner.query_by_name(source), Vec::len)
+    }
+
+    fn execute_returning_count<T>(&self, source: &T) -> QueryResult<usize>
+    where
+        T: QueryFragment<Mysql> + QueryId,
+    {
+        let statement = debug_query::<Mysql, _>(source).to_string();
+        self.instrument(
+            statement,
+            || self.inner.execute_returning_count(source),
+            |count| *count,
+        )
+    }
+
+    fn transaction_manager(&self) -> &Self::TransactionManager {
+        self.inner.transaction_manager()
+    }
+}
+
+fn split_binds(statement: &str) -> (&str, &str) {
+    match statement.rfind(" -- binds: ") {
+        Some(at) => (&statement[..at], &statement[at + " -- binds: ".len()..]),
+        None => (statement, "[]"),
+    }
+}
+
+fn level_for(elapsed: Duration, slow_query: Duration) -> Level {
+    if elapsed >= slow_query {
+        Level::Warn
+    } else {
+        Level::Debug
+    }
+}
+
+fn as_millis(elapsed: Duration) -> f64 {
+    elapsed.as_secs_f64() * 1000.0
+}
+
+#[test]
+fn split_binds_from_debug_query() {
+    use crate::schema::users::dsl::*;
+
+    let query = users.filter(name.eq("Sean")).select(id);
+    let statement = debug_query::<Mysql, _>(&query).to_string();
+    assert_eq!(
+        (
+            "SELECT `users`.`id` FROM `users` WHERE `users`.`name` = ?",
+            "[\"Sean\"]"
+        ),
+        split_binds(&statement)
+    );
+}
+
+#[test]
+fn redacts_statements_touching_redacted_columns() {
+    let config = LogConfig::default().redact("hair_color");
+
+    assert!(config.redacts("UPDATE `users` SET `hair_color` = ?"));
+    assert!(!config.redacts("UPDATE `users` SET `name` = ?"));
+}
+
+#[test]
+fn slow_queries_escalate_to_warnings() {
+    let threshold = Duration::from_millis(100);
+
+    assert_eq!(
+        Level::Debug,
+        level_for(Duration::from_millis(99), threshold)
+    );
+    assert_eq!(
+        Level::Warn,
+        level_for(Duration::from_millis(100), threshold)
+    );
+}
+
+#[test]
+fn entries_carry_label_rows_and_binds() {
+    let statement =
+        "SELECT `users`.`id` FROM `users` WHERE `users`.`name` = ? -- binds: [\"Sean\"]";
+    let (level, message) = labelled("show_users", || {
+        entry(
+            &LogConfig::default(),
+            statement,
+            Duration::from_millis(2),
+            Ok(3),
+        )
+    });
+
+    assert_eq!(Level::Debug, level);
+    assert_eq!(
+        "label=show_users duration_ms=2.000 rows=3 \
+         sql=\"SELECT `users`.`id` FROM `users` WHERE `users`.`name` = ?\" binds=[\"Sean\"]",
+        message
+    );
+}
+
+#[test]
+fn entries_redact_binds_and_report_errors() {
+    let statement = "UPDATE `users` SET `password_hash` = ? -- binds: [\"$argon2id$...\"]";
+    let (level, message) = entry(
+        &LogConfig::default(),
+        statement,
+        Duration::from_millis(1),
+        Err("Lock wait timeout exceeded".to_string()),
+    );
+
+    assert_eq!(Level::Error, level);
+    assert!(message.contains("binds=[REDACTED]"));
+    assert!(!message.contains("argon2id"));
+    assert!(message.ends_with("error=\"Lock wait timeout exceeded\""));
+}
+
+#[test]
+fn labels_are_restored_after_a_panic() {
+    let _outer = label("outer");
+    let panicked = std::panic::catch_unwind(|| labelled("inner", || panic!("boom")));
+
+    assert!(panicked.is_err());
+    assert_eq!("outer", current_label());
+}
+
+#[test]
+fn logging_connection_logs_labelled_queries() {
+    use crate::schema::users::dsl::*;
+    use diesel::result::Error;
+    use std::sync::{Arc, Mutex};
+
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let captured = Arc::clone(&lines);
+    let conn = crate::establish_logging_connection(LogConfig::default()).with_sink(Box::new(
+        move |_, message| captured.lock().unwrap().push(message.to_string()),
+    ));
+    conn.test_transaction::<_, Error, _>(|| {
+        labelled("set_password", || {
+            diesel::update(users)
+                .filter(id.eq(-1))
+                .set(password_hash.eq("secret"))
+                .execute(&conn)
+        })?;
+        Ok(())
+    });
+
+    let lines = lines.lock().unwrap();
+    let update = lines
+        .iter()
+        .find(|line| line.starts_with("label=set_password "))
+        .expect("the update was logged");
+    assert!(update.contains("rows=0"));
+    assert!(update.contains("binds=[REDACTED]"));
+    assert!(!update.contains("secret"));
+}
@@ -0,0 +1,164 @@
+use crate::audit::Audited;
+use crate::models::{AuditAction, Post};
+use crate::schema::posts;
+use crate::DbConnection;
+use ammonia::Builder;
+use diesel::prelude::*;
+use pulldown_cmark::{html, Event, Options, Parser, Tag, TagEnd};
+
+use std::collections::HashSet;
+
+/// Default length of `excerpt`, in characters.
+pub const EXCERPT_CHARS: usize = 200;
+
+fn options() -> Options {
+    Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_FOOTNOTES
+}
+
+/// Renders Markdown to HTML that is safe to embed in a page: raw HTML in the
+/// body is kept only if ammonia allows it, so no scripts, event handlers or
+/// `javascript:` links survive.
+pub fn render_html(markdown: &str) -> String {
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, Parser::new_ext(markdown, options()));
+
+    Builder::default()
+        .link_rel(Some("nofollow noopener noreferrer"))
+        .clean(&unsafe_html)
+        .to_string()
+}
+
+/// The text of a Markdown body without markup, whitespace collapsed.
+pub fn plain_text(markdown: &str) -> String {
+    let mut text = String::new();
+    for event in Parser::new_ext(markdown, options()) {
+        match event {
+            Event::Text(t) | Event::Code(t) => text.push_str(&t),
+            Event::SoftBreak | Event::HardBreak => text.push(' '),
+            Event::Start(Tag::Item)
+            | Event::End(TagEnd::Paragraph)
+            | Event::End(TagEnd::Heading(_)) => text.push(' '),
+            _ => {}
+        }
+    }
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The first `max_chars` characters of the body's text, cut at a word
+/// boundary and marked with an ellipsis when anything was left out.
+pub fn excerpt(markdown: &str, max_chars: usize) -> String {
+    let text = plain_text(markdown);
+    if text.chars().count() <= max_chars {
+        return text;
+    }
+
+    let cut = text
+        .char_indices()
+        .nth(max_chars)
+        .map_or(text.len(), |(i, _)| i);
+    let kept = match text[..cut].rfind(' ') {
+        Some(space) if space > 0 => &text[..space],
+        _ => &text[..cut],
+    };
+    format!(
+        "{}…",
+        kept.trim_end_matches(|c: char| c.is_ascii_punctuation())
+    )
+}
+
+/// Fills in `body_html` for posts written before it was cached, in batches,
+/// recording the changes with no actor. `updated_at` is left as it was.
+pub fn refresh_body_html(conn: &DbConnection, batch_size: i64) -> QueryResult<usize> {
+    let mut refreshed = 0;
+    let mut seen = HashSet::new();
+    loop {
+        let batch = posts::table
+            .filter(posts::body_html.is_null())
+            .select((posts::id, posts::body))
+            .order(posts::id)
+            .limit(batch_size)
+            .load::<(i32, String)>(conn)?;
+        // Stop rather than spin if a row cannot be updated for some reason.
+        if batch.is_empty() || !batch.iter().all(|(id, _)| seen.insert(*id)) {
+            return Ok(refreshed);
+        }
+
+        let ids = batch.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        Post::audited(conn, None, AuditAction::Update, &ids, || {
+            for (id, body) in &batch {
+                // Filling a cache is not an edit; setting `updated_at` to
+                // itself keeps `ON UPDATE CURRENT_TIMESTAMP` from bumping it.
+                refreshed += diesel::update(posts::table.find(id))
+                    .set((
+                        posts::body_html.eq(render_html(body)),
+                        posts::updated_at.eq(posts::updated_at),
+                    ))
+                    .execute(conn)?;
+            }
+            Ok::<_, diesel::result::Error>(())
+        })?;
+    }
+}
+
+#[test]
+fn renders_markdown() {
+    assert_eq!(
+        "<h1>Diesel</h1>\n<p>A <em>safe</em>, <code>extensible</code> ORM</p>\n",
+        render_html("# Diesel\n\nA *safe*, `extensible` ORM")
+    );
+}
+
+#[test]
+fn strips_scripts_and_handlers() {
+    let html = render_html(
+        "<script>alert(1)</script>\n\n<img src=\"x.png\" onerror=\"alert(2)\">\n\n\
+         [click](javascript:alert(3))",
+    );
+
+    assert!(!html.contains("<script"));
+    assert!(!html.contains("onerror"));
+    assert!(!html.contains("javascript:"));
+    assert!(html.contains("<img src=\"x.png\">"));
+}
+
+#[test]
+fn links_are_marked_nofollow() {
+    assert_eq!(
+        "<p><a href=\"https://diesel.rs\" rel=\"nofollow noopener noreferrer\">Diesel</a></p>\n",
+        render_html("[Diesel](https://diesel.rs)")
+    );
+}
+
+#[test]
+fn excerpt_drops_markup() {
+    assert_eq!(
+        "Diesel A safe ORM for Rust",
+        excerpt(
+            "# Diesel\n\nA **safe** ORM\nfor [Rust](https://rust-lang.org)",
+            EXCERPT_CHARS
+        )
+    );
+}
+
+#[test]
+fn excerpt_cuts_at_a_word_boundary() {
+    assert_eq!(
+        "Crème brûlée…",
+        excerpt("Crème brûlée, with extra sugar", 15)
+    );
+    assert_eq!("Supercalifragi…", excerpt("Supercalifragilistic", 14));
+}
+
+#[test]
+fn examine_sql_from_refresh_body_html() {
+    use diesel::debug_query;
+    use diesel::mysql::Mysql;
+
+    let query = diesel::update(posts::table.find(1)).set((
+        posts::body_html.eq("<p>Hi</p>\n"),
+        posts::updated_at.eq(posts::updated_at),
+    ));
+    let sql = "UPDATE `posts` SET `body_html` = ?, `updated_at` = `posts`.`updated_at` \
+               WHERE `posts`.`id` = ? -- binds: [\"<p>Hi</p>\\n\", 1]";
+    assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
+}
@@ -0,0 +1,372 @@
+use diesel::r2d2::{ManageConnection, Pool};
+use diesel::result::{DatabaseErrorKind, Error};
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+
+const PREFIX: &str = "diesel_demo";
+
+/// Upper bounds, in seconds, of the latency histogram buckets.
+const BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+#[derive(Clone, Default, Debug)]
+pub struct Histogram {
+    counts: [u64; 12],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        for (bucket, count) in BUCKETS.iter().zip(self.counts.iter_mut()) {
+            if seconds <= *bucket {
+                *count += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (bucket, count) in BUCKETS.iter().zip(self.counts.iter()) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, labels, bucket, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}le=\"+Inf\"}} {}",
+            name, labels, self.count
+        );
+        let labels = labels.trim_end_matches(',');
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct PoolGauges {
+    pub max_size: u32,
+    pub connections: u32,
+    pub idle_connections: u32,
+}
+
+#[derive(Default, Debug)]
+pub struct Registry {
+    operations: BTreeMap<&'static str, u64>,
+    errors: BTreeMap<(&'static str, &'static str), u64>,
+    operation_durations: BTreeMap<&'static str, Histogram>,
+    transaction_durations: BTreeMap<&'static str, Histogram>,
+    pool: Option<PoolGauges>,
+}
+
+impl Registry {
+    pub fn record_operation(
+        &mut self,
+        operation: &'static str,
+        elapsed: Duration,
+        error: Option<&'static str>,
+    ) {
+        *self.operations.entry(operation).or_insert(0) += 1;
+        self.operation_durations
+            .entry(operation)
+            .or_default()
+            .observe(elapsed);
+        if let Some(kind) = error {
+            *self.errors.entry((operation, kind)).or_insert(0) += 1;
+        }
+    }
+
+    pub fn record_transaction(&mut self, operation: &'static str, elapsed: Duration) {
+        self.transaction_durations
+            .entry(operation)
+            .or_default()
+            .observe(elapsed);
+    }
+
+    pub fn record_pool(&mut self, gauges: PoolGauges) {
+        self.pool = Some(gauges);
+    }
+
+    /// Renders everything recorded so far in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "operations_total",
+            "counter",
+            "Database operations run, by operation; one may run several queries.",
+        );
+        for (operation, count) in &self.operations {
+            let _ = writeln!(
+                out,
+                "{}_operations_total{{operation=\"{}\"}} {}",
+                PREFIX, operation, count
+            );
+        }
+
+        header(
+            &mut out,
+            "operation_errors_total",
+            "counter",
+            "Failed operations, by operation and error kind.",
+        );
+        for ((operation, kind), count) in &self.errors {
+            let _ = writeln!(
+                out,
+                "{}_operation_errors_total{{operation=\"{}\",kind=\"{}\"}} {}",
+                PREFIX, operation, kind, count
+            );
+        }
+
+        header(
+            &mut out,
+            "operation_duration_seconds",
+            "histogram",
+            "Operation latency, including every query it runs.",
+        );
+        for (operation, histogram) in &self.operation_durations {
+            histogram.render(
+                &mut out,
+                &format!("{}_operation_duration_seconds", PREFIX),
+                &format!("operation=\"{}\",", operation),
+            );
+        }
+
+        header(
+            &mut out,
+            "transaction_duration_seconds",
+            "histogram",
+            "Time from BEGIN to COMMIT or ROLLBACK, by operation.",
+        );
+        for (operation, histogram) in &self.transaction_durations {
+            histogram.render(
+                &mut out,
+                &format!("{}_transaction_duration_seconds", PREFIX),
+                &format!("operation=\"{}\",", operation),
+            );
+        }
+
+        if let Some(pool) = self.pool {
+            let gauges = [
+                (
+                    "pool_max_size",
+                    "Maximum connections in the pool.",
+                    pool.max_size,
+                ),
+                (
+                    "pool_connections",
+                    "Connections currently open.",
+                    pool.connections,
+                ),
+                (
+                    "pool_idle_connections",
+                    "Open connections not checked out.",
+                    pool.idle_connections,
+                ),
+                (
+                    "pool_in_use_connections",
+                    "Connections checked out; at max_size the pool is saturated.",
+                    pool.connections - pool.idle_connections,
+                ),
+            ];
+            for (name, help, value) in gauges.iter() {
+                header(&mut out, name, "gauge", help);
+                let _ = writeln!(out, "{}_{} {}", PREFIX, name, value);
+            }
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+    let _ = writeln!(out, "# TYPE {}_{} {}", PREFIX, name, kind);
+}
+
+/// A short, label-safe name for the kind of a failure.
+pub trait ErrorKind {
+    fn kind(&self) -> &'static str;
+}
+
+impl ErrorKind for Error {
+    fn kind(&self) -> &'static str {
+        match self {
+            Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => "unique_violation",
+            Error::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => {
+                "foreign_key_violation"
+            }
+            Error::DatabaseError(DatabaseErrorKind::UnableToSendCommand, _) => {
+                "unable_to_send_command"
+            }
+            Error::DatabaseError(DatabaseErrorKind::SerializationFailure, _) => {
+                "serialization_failure"
+            }
+            Error::DatabaseError(_, _) => "database_error",
+            Error::NotFound => "not_found",
+            Error::QueryBuilderError(_) => "query_builder_error",
+            Error::DeserializationError(_) => "deserialization_error",
+            Error::SerializationError(_) => "serialization_error",
+            Error::RollbackTransaction => "rollback_transaction",
+            Error::AlreadyInTransaction => "already_in_transaction",
+            _ => "other",
+        }
+    }
+}
+
+impl ErrorKind for Box<dyn std::error::Error> {
+    fn kind(&self) -> &'static str {
+        match self.downcast_ref::<Error>() {
+            Some(e) => e.kind(),
+            None => "other",
+        }
+    }
+}
+
+impl ErrorKind for crate::guard::GuardError {
+    fn kind(&self) -> &'static str {
+        match self {
+            crate::guard::GuardError::TooManyRows { .. } => "too_many_rows",
+            crate::guard::GuardError::Database(e) => e.kind(),
+        }
+    }
+}
+
+pub fn registry() -> MutexGuard<'static, Registry> {
+    static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+    REGISTRY
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Runs one database operation, counting it under `operation`. Statements it
+/// runs are logged with `operation` as their label.
+pub fn track<T, E, F>(operation: &'static str, f: F) -> Result<T, E>
+where
+    E: ErrorKind,
+    F: FnOnce() -> Result<T, E>,
+{
+    let _label = crate::logging::label(operation);
+    let start = Instant::now();
+    let result = f();
+    let error = result.as_ref().err().map(ErrorKind::kind);
+    registry().record_operation(operation, start.elapsed(), error);
+    result
+}
+
+/// Records how long the transaction run by `f` took, under `operation`.
+/// Operations are counted by the `track` around it, not here.
+pub fn track_transaction<T, F>(operation: &'static str, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let start = Instant::now();
+    let result = f();
+    registry().record_transaction(operation, start.elapsed());
+    result
+}
+
+type PoolSampler = Box<dyn Fn() -> PoolGauges + Send>;
+
+fn pool_sampler() -> MutexGuard<'static, Option<PoolSampler>> {
+    static SAMPLER: OnceLock<Mutex<Option<PoolSampler>>> = OnceLock::new();
+    SAMPLER
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Reports `pool` in every later `render()`, sampled as it is rendered so
+/// the gauges show saturation at scrape time.
+pub fn observe_pool<M: ManageConnection>(pool: &Pool<M>) {
+    let pool = pool.clone();
+    *pool_sampler() = Some(Box::new(move || {
+        let state = pool.state();
+        PoolGauges {
+            max_size: pool.max_size(),
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+        }
+    }));
+}
+
+pub fn render() -> String {
+    let gauges = pool_sampler().as_ref().map(|sample| sample());
+    let mut registry = registry();
+    if let Some(gauges) = gauges {
+        registry.record_pool(gauges);
+    }
+    registry.render()
+}
+
+#[test]
+fn render_counts_operations_and_errors() {
+    let mut registry = Registry::default();
+    registry.record_operation("create_post", Duration::from_millis(2), None);
+    registry.record_operation("create_post", Duration::from_millis(3), Some("not_found"));
+
+    let text = registry.render();
+    assert!(text.contains("# TYPE diesel_demo_operations_total counter\n"));
+    assert!(text.contains("diesel_demo_operations_total{operation=\"create_post\"} 2\n"));
+    assert!(text.contains(
+        "diesel_demo_operation_errors_total{operation=\"create_post\",kind=\"not_found\"} 1\n"
+    ));
+}
+
+#[test]
+fn render_cumulative_histogram_buckets() {
+    let mut registry = Registry::default();
+    registry.record_transaction("explicit_returning", Duration::from_millis(20));
+
+    let text = registry.render();
+    let name = "diesel_demo_transaction_duration_seconds";
+    assert!(text.contains(&format!(
+        "{}_bucket{{operation=\"explicit_returning\",le=\"0.01\"}} 0\n",
+        name
+    )));
+    assert!(text.contains(&format!(
+        "{}_bucket{{operation=\"explicit_returning\",le=\"0.025\"}} 1\n",
+        name
+    )));
+    assert!(text.contains(&format!(
+        "{}_bucket{{operation=\"explicit_returning\",le=\"+Inf\"}} 1\n",
+        name
+    )));
+    assert!(text.contains(&format!(
+        "{}_count{{operation=\"explicit_returning\"}} 1\n",
+        name
+    )));
+}
+
+#[test]
+fn classify_diesel_errors() {
+    assert_eq!("not_found", Error::NotFound.kind());
+    assert_eq!("rollback_transaction", Error::RollbackTransaction.kind());
+
+    let boxed: Box<dyn std::error::Error> = Box::new(Error::NotFound);
+    assert_eq!("not_found", boxed.kind());
+}
+
+#[test]
+fn render_pool_saturation() {
+    let mut registry = Registry::default();
+    registry.record_pool(PoolGauges {
+        max_size: 10,
+        connections: 10,
+        idle_connections: 0,
+    });
+
+    let text = registry.render();
+    assert!(text.contains("diesel_demo_pool_max_size 10\n"));
+    assert!(text.contains("diesel_demo_pool_in_use_connections 10\n"));
+}
@@ -1,17 +1,358 @@
-use super::schema::posts;
+use super::schema::{api_tokens, audit_log, post_slugs, post_sources, post_tags, posts};
+use chrono::NaiveDateTime;
+use diesel::deserialize::{self, FromSql};
+use diesel::mysql::Mysql;
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Text;
 use diesel::Queryable;
+use serde_derive::{Deserialize, Serialize};
 
-#[derive(Queryable)]
+use std::borrow::Cow;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+#[derive(Queryable, Insertable, Serialize, Deserialize, Debug)]
+#[table_name = "posts"]
 pub struct Post {
     pub id: i32,
     pub title: String,
     pub body: String,
     pub published: bool,
+    pub deleted_at: Option<NaiveDateTime>,
+    pub version: i32,
+    pub author_id: Option<i32>,
+    pub slug: String,
+    pub body_html: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    /// When the post was first published; kept if it is later unpublished.
+    pub published_at: Option<NaiveDateTime>,
 }
 
-#[derive(Insertable)]
+impl Post {
+    /// The body as sanitised HTML, rendering it if it has not been cached.
+    pub fn html(&self) -> Cow<'_, str> {
+        match &self.body_html {
+            Some(html) => Cow::Borrowed(html),
+            None => Cow::Owned(crate::markdown::render_html(&self.body)),
+        }
+    }
+
+    pub fn excerpt(&self) -> String {
+        crate::markdown::excerpt(&self.body, crate::markdown::EXCERPT_CHARS)
+    }
+}
+
+#[derive(Insertable, Debug)]
 #[table_name = "posts"]
 pub struct NewPost<'a> {
     pub title: &'a str,
     pub body: &'a str,
+    pub author_id: Option<i32>,
+}
+
+/// Fields of a post an editor may change; `None` leaves a field untouched.
+#[derive(AsChangeset, Clone, Copy, Default, Debug)]
+#[table_name = "posts"]
+pub struct PostChanges<'a> {
+    pub title: Option<&'a str>,
+    pub body: Option<&'a str>,
+    pub published: Option<bool>,
+}
+
+#[derive(Queryable, Insertable, Serialize, Deserialize, PartialEq, Debug)]
+#[table_name = "api_tokens"]
+pub struct ApiToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub token_hash: String,
+    pub scopes: String,
+    pub expires_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+impl ApiToken {
+    /// Scopes are stored space separated, like OAuth scopes.
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.split_whitespace().any(|s| s == scope)
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "api_tokens"]
+pub struct NewApiToken<'a> {
+    pub user_id: i32,
+    pub token_hash: &'a str,
+    pub scopes: &'a str,
+    pub expires_at: NaiveDateTime,
+}
+
+/// A slug a post used to have; see `slugs`.
+#[derive(Queryable, Insertable, Serialize, Deserialize, PartialEq, Debug)]
+#[table_name = "post_slugs"]
+pub struct PostSlug {
+    pub slug: String,
+    pub post_id: i32,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Insertable, Serialize, Deserialize, PartialEq, Debug)]
+#[table_name = "post_tags"]
+pub struct PostTag {
+    pub post_id: i32,
+    pub tag: String,
+}
+
+/// The file a post was imported from; see `importer`.
+#[derive(Queryable, Insertable, Serialize, Deserialize, PartialEq, Debug)]
+#[table_name = "post_sources"]
+pub struct PostSource {
+    pub path: String,
+    pub post_id: i32,
+    pub imported_at: NaiveDateTime,
+}
+
+/// What a user may do; see `policy` for the rules. Stored as lowercase text.
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[sql_type = "Text"]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    Editor,
+    Author,
+    Reader,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Editor => "editor",
+            Role::Author => "author",
+            Role::Reader => "reader",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "admin" => Ok(Role::Admin),
+            "editor" => Ok(Role::Editor),
+            "author" => Ok(Role::Author),
+            "reader" => Ok(Role::Reader),
+            other => Err(format!("unknown role {:?}", other)),
+        }
+    }
+}
+
+impl ToSql<Text, Mysql> for Role {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Mysql>) -> serialize::Result {
+        ToSql::<Text, Mysql>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Text, Mysql> for Role {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        let role = <String as FromSql<Text, Mysql>>::from_sql(bytes)?;
+        Ok(role.parse()?)
+    }
+}
+
+/// One change to one row; see `audit`.
+#[derive(Queryable, Insertable, Serialize, Deserialize, PartialEq, Debug)]
+#[table_name = "audit_log"]
+pub struct AuditEntry {
+    pub id: i32,
+    pub actor_id: Option<i32>,
+    pub action: AuditAction,
+    pub table_name: String,
+    pub row_id: i32,
+    pub before_json: Option<String>,
+    pub after_json: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+/// What an audited change did to a row. Stored as lowercase text.
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[sql_type = "Text"]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    Create,
+    Update,
+    Publish,
+    /// Soft delete; the row is still there.
+    Delete,
+    Restore,
+    /// Permanent delete.
+    Purge,
+}
+
+impl AuditAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditAction::Create => "create",
+            AuditAction::Update => "update",
+            AuditAction::Publish => "publish",
+            AuditAction::Delete => "delete",
+            AuditAction::Restore => "restore",
+            AuditAction::Purge => "purge",
+        }
+    }
+}
+
+impl fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for AuditAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "create" => Ok(AuditAction::Create),
+            "update" => Ok(AuditAction::Update),
+            "publish" => Ok(AuditAction::Publish),
+            "delete" => Ok(AuditAction::Delete),
+            "restore" => Ok(AuditAction::Restore),
+            "purge" => Ok(AuditAction::Purge),
+            other => Err(format!("unknown audit action {:?}", other)),
+        }
+    }
+}
+
+impl ToSql<Text, Mysql> for AuditAction {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Mysql>) -> serialize::Result {
+        ToSql::<Text, Mysql>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Text, Mysql> for AuditAction {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        let action = <String as FromSql<Text, Mysql>>::from_sql(bytes)?;
+        Ok(action.parse()?)
+    }
+}
+
+/// A user's hair colour, stored as lowercase text. Values written before the
+/// column was normalised and still not recognised are kept as `Other`.
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[sql_type = "Text"]
+#[serde(from = "String", into = "String")]
+pub enum HairColor {
+    Black,
+    Blonde,
+    Brown,
+    Grey,
+    Red,
+    White,
+    Yellow,
+    Other(String),
+}
+
+impl HairColor {
+    /// The stored names of every recognised colour.
+    pub const NAMES: &'static [&'static str] =
+        &["black", "blonde", "brown", "grey", "red", "white", "yellow"];
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            HairColor::Black => "black",
+            HairColor::Blonde => "blonde",
+            HairColor::Brown => "brown",
+            HairColor::Grey => "grey",
+            HairColor::Red => "red",
+            HairColor::White => "white",
+            HairColor::Yellow => "yellow",
+            HairColor::Other(raw) => raw,
+        }
+    }
+}
+
+impl fmt::Display for HairColor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Accepts any case, surrounding whitespace and the common alternative
+/// spellings, but only recognised colours.
+impl FromStr for HairColor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "black" => Ok(HairColor::Black),
+            "blonde" | "blond" => Ok(HairColor::Blonde),
+            "brown" => Ok(HairColor::Brown),
+            "grey" | "gray" => Ok(HairColor::Grey),
+            "red" => Ok(HairColor::Red),
+            "white" => Ok(HairColor::White),
+            "yellow" => Ok(HairColor::Yellow),
+            _ => Err(format!("unknown hair colour {:?}", s)),
+        }
+    }
+}
+
+/// Never fails: anything unrecognised becomes `Other`.
+impl From<String> for HairColor {
+    fn from(s: String) -> Self {
+        s.parse().unwrap_or(HairColor::Other(s))
+    }
+}
+
+impl From<HairColor> for String {
+    fn from(color: HairColor) -> Self {
+        match color {
+            HairColor::Other(raw) => raw,
+            color => color.as_str().to_string(),
+        }
+    }
+}
+
+impl ToSql<Text, Mysql> for HairColor {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Mysql>) -> serialize::Result {
+        ToSql::<Text, Mysql>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Text, Mysql> for HairColor {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        let color = <String as FromSql<Text, Mysql>>::from_sql(bytes)?;
+        Ok(HairColor::from(color))
+    }
+}
+
+#[test]
+fn hair_color_parses_legacy_spellings() {
+    assert_eq!(Ok(HairColor::Black), " Black ".parse());
+    assert_eq!(Ok(HairColor::Grey), "GRAY".parse());
+    assert_eq!(Ok(HairColor::Blonde), "blond".parse::<HairColor>());
+    assert!("green".parse::<HairColor>().is_err());
+}
+
+#[test]
+fn unknown_hair_color_falls_back_to_other() {
+    assert_eq!(HairColor::Yellow, HairColor::from("yellow".to_string()));
+    assert_eq!(
+        HairColor::Other("Teal-ish".to_string()),
+        HairColor::from("Teal-ish".to_string())
+    );
+    assert_eq!(
+        "Teal-ish",
+        HairColor::Other("Teal-ish".to_string()).as_str()
+    );
 }
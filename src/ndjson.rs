@@ -0,0 +1,442 @@
+use crate::audit::Audited;
+use crate::models::{AuditAction, Post};
+use crate::schema::{posts, users};
+use crate::{DbConnection, User};
+use diesel::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// Rows read per query when dumping, and inserted per statement when loading.
+pub const BATCH_SIZE: i64 = 1000;
+
+#[derive(Debug)]
+pub enum NdjsonError {
+    Io(io::Error),
+    Json {
+        line: usize,
+        error: serde_json::Error,
+    },
+    /// The table did not grow by the number of rows loaded.
+    CountMismatch {
+        table: &'static str,
+        expected: i64,
+        actual: i64,
+    },
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for NdjsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NdjsonError::Io(e) => write!(f, "{}", e),
+            NdjsonError::Json { line, error } => write!(f, "line {}: {}", line, error),
+            NdjsonError::CountMismatch {
+                table,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "loaded {} rows into {} but it grew by {}",
+                expected, table, actual
+            ),
+            NdjsonError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for NdjsonError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            NdjsonError::Io(e) => Some(e),
+            NdjsonError::Json { error, .. } => Some(error),
+            NdjsonError::Database(e) => Some(e),
+            NdjsonError::CountMismatch { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for NdjsonError {
+    fn from(e: io::Error) -> Self {
+        NdjsonError::Io(e)
+    }
+}
+
+impl From<diesel::result::Error> for NdjsonError {
+    fn from(e: diesel::result::Error) -> Self {
+        NdjsonError::Database(e)
+    }
+}
+
+impl crate::metrics::ErrorKind for NdjsonError {
+    fn kind(&self) -> &'static str {
+        match self {
+            NdjsonError::Io(_) => "io",
+            NdjsonError::Json { .. } => "json",
+            NdjsonError::CountMismatch { .. } => "count_mismatch",
+            NdjsonError::Database(e) => e.kind(),
+        }
+    }
+}
+
+/// Writes one JSON object per line, fetching rows a batch at a time after
+/// the last key seen so memory use does not grow with the table. `first`
+/// must sort before every key.
+pub(crate) fn dump_rows<T, K, W, F>(
+    out: W,
+    batch_size: i64,
+    first: K,
+    key_of: fn(&T) -> K,
+    fetch: F,
+) -> Result<usize, NdjsonError>
+where
+    T: Serialize,
+    W: Write,
+    F: Fn(&K, i64) -> QueryResult<Vec<T>>,
+{
+    let mut out = BufWriter::new(out);
+    let mut last_key = first;
+    let mut written = 0;
+    loop {
+        let batch = fetch(&last_key, batch_size)?;
+        for row in &batch {
+            serde_json::to_writer(&mut out, row).map_err(io::Error::from)?;
+            out.write_all(b"\n")?;
+        }
+        written += batch.len();
+        match batch.last() {
+            Some(row) if batch.len() as i64 == batch_size => last_key = key_of(row),
+            _ => break,
+        }
+    }
+    out.flush()?;
+    Ok(written)
+}
+
+/// Reads one JSON object per line, handing them to `insert` in chunks.
+/// Blank lines are skipped.
+fn load_rows<T, R, F>(input: R, chunk_size: usize, mut insert: F) -> Result<usize, NdjsonError>
+where
+    T: DeserializeOwned,
+    R: BufRead,
+    F: FnMut(&[T]) -> QueryResult<usize>,
+{
+    let mut chunk = Vec::with_capacity(chunk_size);
+    let mut loaded = 0;
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let row = serde_json::from_str(&line)
+            .map_err(|error| NdjsonError::Json { line: i + 1, error })?;
+        chunk.push(row);
+        if chunk.len() == chunk_size {
+            loaded += insert(&chunk)?;
+            chunk.clear();
+        }
+    }
+    if !chunk.is_empty() {
+        loaded += insert(&chunk)?;
+    }
+    Ok(loaded)
+}
+
+fn verify_count(
+    table: &'static str,
+    before: i64,
+    after: i64,
+    loaded: usize,
+) -> Result<(), NdjsonError> {
+    let expected = loaded as i64;
+    if after - before == expected {
+        Ok(())
+    } else {
+        Err(NdjsonError::CountMismatch {
+            table,
+            expected,
+            actual: after - before,
+        })
+    }
+}
+
+/// Users who are loaded without a hash cannot log in until their password is
+/// reset, but the dump no longer lets anyone crack their passwords.
+fn redact_password_hash(user: User) -> User {
+    User {
+        password_hash: None,
+        ..user
+    }
+}
+
+/// Dumps every user, deleted or not, in id order. Password hashes are left
+/// out unless `password_hashes` is set.
+pub fn dump_users<W: Write>(
+    conn: &DbConnection,
+    out: W,
+    batch_size: i64,
+    password_hashes: bool,
+) -> Result<usize, NdjsonError> {
+    crate::metrics::track("dump_users", || {
+        dump_rows(
+            out,
+            batch_size,
+            i32::MIN,
+            |user: &User| user.id,
+            |after, limit| {
+                let users = users::table
+                    .filter(users::id.gt(*after))
+                    .order(users::id)
+                    .limit(limit)
+                    .load::<User>(conn)?;
+                if password_hashes {
+                    Ok(users)
+                } else {
+                    Ok(users.into_iter().map(redact_password_hash).collect())
+                }
+            },
+        )
+    })
+}
+
+/// Dumps every post, deleted or not, in id order.
+pub fn dump_posts<W: Write>(
+    conn: &DbConnection,
+    out: W,
+    batch_size: i64,
+) -> Result<usize, NdjsonError> {
+    crate::metrics::track("dump_posts", || {
+        dump_rows(
+            out,
+            batch_size,
+            i32::MIN,
+            |post: &Post| post.id,
+            |after, limit| {
+                posts::table
+                    .filter(posts::id.gt(*after))
+                    .order(posts::id)
+                    .limit(limit)
+                    .load(conn)
+            },
+        )
+    })
+}
+
+/// Inserts dumped users with their ids and timestamps in one transaction,
+/// recording each as created by `actor`, then checks the table grew by as
+/// many rows as were read.
+pub fn load_users<R: BufRead>(
+    conn: &DbConnection,
+    actor: Option<&User>,
+    input: R,
+    chunk_size: usize,
+) -> Result<usize, NdjsonError> {
+    crate::metrics::track("load_users", || {
+        conn.transaction(|| {
+            let before = users::table.count().get_result::<i64>(conn)?;
+            let loaded = load_rows(input, chunk_size, |chunk: &[User]| {
+                let ids = chunk.iter().map(|user| user.id).collect::<Vec<_>>();
+                User::audited(conn, actor, AuditAction::Create, &ids, || {
+                    diesel::insert_into(users::table)
+                        .values(chunk)
+                        .execute(conn)
+                })
+            })?;
+            let after = users::table.count().get_result::<i64>(conn)?;
+            verify_count("users", before, after, loaded)?;
+            Ok(loaded)
+        })
+    })
+}
+
+/// Inserts dumped posts like `load_users`. Load users first, since posts
+/// refer to their authors.
+pub fn load_posts<R: BufRead>(
+    conn: &DbConnection,
+    actor: Option<&User>,
+    input: R,
+    chunk_size: usize,
+) -> Result<usize, NdjsonError> {
+    crate::metrics::track("load_posts", || {
+        conn.transaction(|| {
+            let before = posts::table.count().get_result::<i64>(conn)?;
+            let loaded = load_rows(input, chunk_size, |chunk: &[Post]| {
+                let ids = chunk.iter().map(|post| post.id).collect::<Vec<_>>();
+                Post::audited(conn, actor, AuditAction::Create, &ids, || {
+                    diesel::insert_into(posts::table)
+                        .values(chunk)
+                        .execute(conn)
+                })
+            })?;
+            let after = posts::table.count().get_result::<i64>(conn)?;
+            verify_count("posts", before, after, loaded)?;
+            Ok(loaded)
+        })
+    })
+}
+
+/// Writes `users.ndjson` and `posts.ndjson` to `dir`; see `dump_users` for
+/// `password_hashes`.
+pub fn dump_dir(
+    conn: &DbConnection,
+    dir: &Path,
+    password_hashes: bool,
+) -> Result<(usize, usize), NdjsonError> {
+    std::fs::create_dir_all(dir)?;
+    // One snapshot across tables, so every post's author is in the dump.
+    conn.transaction(|| {
+        let users = dump_users(
+            conn,
+            File::create(dir.join("users.ndjson"))?,
+            BATCH_SIZE,
+            password_hashes,
+        )?;
+        let posts = dump_posts(conn, File::create(dir.join("posts.ndjson"))?, BATCH_SIZE)?;
+        Ok((users, posts))
+    })
+}
+
+/// Loads what `dump_dir` wrote, all in one transaction.
+pub fn load_dir(
+    conn: &DbConnection,
+    actor: Option<&User>,
+    dir: &Path,
+) -> Result<(usize, usize), NdjsonError> {
+    let chunk_size = BATCH_SIZE as usize;
+    conn.transaction(|| {
+        let users = load_users(
+            conn,
+            actor,
+            BufReader::new(File::open(dir.join("users.ndjson"))?),
+            chunk_size,
+        )?;
+        let posts = load_posts(
+            conn,
+            actor,
+            BufReader::new(File::open(dir.join("posts.ndjson"))?),
+            chunk_size,
+        )?;
+        Ok((users, posts))
+    })
+}
+
+#[test]
+fn dumps_in_keyset_batches() {
+    use std::cell::RefCell;
+
+    let ids = (1..=5).collect::<Vec<i32>>();
+    let calls = RefCell::new(Vec::new());
+    let mut out = Vec::new();
+
+    let written = dump_rows(
+        &mut out,
+        2,
+        i32::MIN,
+        |id: &i32| *id,
+        |after, limit| {
+            calls.borrow_mut().push(*after);
+            Ok(ids
+                .iter()
+                .copied()
+                .filter(|id| id > after)
+                .take(limit as usize)
+                .collect())
+        },
+    )
+    .unwrap();
+
+    assert_eq!(5, written);
+    assert_eq!(vec![i32::MIN, 2, 4], calls.into_inner());
+    assert_eq!("1\n2\n3\n4\n5\n", String::from_utf8(out).unwrap());
+}
+
+#[test]
+fn loads_in_chunks() {
+    let input = "1\n2\n\n3\n4\n5\n";
+    let mut chunks = Vec::new();
+
+    let loaded = load_rows(input.as_bytes(), 2, |chunk: &[i32]| {
+        chunks.push(chunk.to_vec());
+        Ok(chunk.len())
+    })
+    .unwrap();
+
+    assert_eq!(5, loaded);
+    assert_eq!(vec![vec![1, 2], vec![3, 4], vec![5]], chunks);
+}
+
+#[test]
+fn bad_lines_are_reported() {
+    let result = load_rows("1\n{oops\n".as_bytes(), 10, |chunk: &[i32]| Ok(chunk.len()));
+
+    match result {
+        Err(NdjsonError::Json { line, .. }) => assert_eq!(2, line),
+        other => panic!("expected Json, got {:?}", other),
+    }
+}
+
+#[test]
+fn users_round_trip_through_json() {
+    let created = chrono::NaiveDate::from_ymd(2020, 10, 1).and_hms(9, 30, 0);
+    let user = User {
+        id: 7,
+        name: "Sean".into(),
+        hair_color: Some(crate::models::HairColor::Other("Teal".into())),
+        created_at: created,
+        updated_at: created,
+        deleted_at: Some(created),
+        version: 2,
+        email: Some("sean@example.com".into()),
+        password_hash: None,
+        role: crate::models::Role::Editor,
+    };
+
+    let json = serde_json::to_string(&user).unwrap();
+    assert!(json.contains("\"hair_color\":\"Teal\""));
+    assert!(json.contains("\"role\":\"editor\""));
+    assert!(json.contains("\"created_at\":\"2020-10-01T09:30:00\""));
+    assert_eq!(user, serde_json::from_str(&json).unwrap());
+}
+
+#[test]
+fn password_hashes_are_redacted() {
+    let created = chrono::NaiveDate::from_ymd(2020, 10, 1).and_hms(9, 30, 0);
+    let user = User {
+        id: 7,
+        name: "Sean".into(),
+        hair_color: None,
+        created_at: created,
+        updated_at: created,
+        deleted_at: None,
+        version: 0,
+        email: Some("sean@example.com".into()),
+        password_hash: Some("$argon2id$secret".into()),
+        role: crate::models::Role::Admin,
+    };
+
+    let redacted = redact_password_hash(user);
+    assert_eq!(None, redacted.password_hash);
+    assert_eq!(Some("sean@example.com"), redacted.email.as_deref());
+}
+
+#[test]
+fn examine_sql_from_keyset_batch() {
+    use diesel::debug_query;
+    use diesel::mysql::Mysql;
+
+    let query = users::table
+        .filter(users::id.gt(1000))
+        .order(users::id)
+        .limit(BATCH_SIZE);
+    let sql = "SELECT `users`.`id`, `users`.`name`, `users`.`hair_color`, \
+               `users`.`created_at`, `users`.`updated_at`, `users`.`deleted_at`, \
+               `users`.`version`, `users`.`email`, `users`.`password_hash`, `users`.`role` \
+               FROM `users` WHERE `users`.`id` > ? ORDER BY `users`.`id` LIMIT ? \
+               -- binds: [1000, 1000]";
+    assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
+}
@@ -0,0 +1,323 @@
+use crate::audit::Audited;
+use crate::guard::{Guard, GuardError, Outcome};
+use crate::locking::{update_user, UpdateError};
+use crate::models::{AuditAction, Post, Role};
+use crate::schema::users;
+use crate::{soft_delete, DbConnection, User, UserChanges};
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+
+use std::error::Error;
+use std::fmt;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    Create,
+    Edit,
+    Publish,
+    Delete,
+    /// Undo a soft delete.
+    Restore,
+    /// Remove soft deleted rows for good.
+    Purge,
+    /// Change other users' roles or accounts, delete or restore them.
+    ManageUsers,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Forbidden {
+    pub user_id: i32,
+    pub role: Role,
+    pub action: Action,
+    pub post_id: Option<i32>,
+}
+
+impl fmt::Display for Forbidden {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "user {} ({}) may not {:?}",
+            self.user_id, self.role, self.action
+        )?;
+        match (self.action, self.post_id) {
+            (Action::ManageUsers, _) => Ok(()),
+            (_, Some(post_id)) => write!(f, " post {}", post_id),
+            (_, None) => write!(f, " posts"),
+        }
+    }
+}
+
+impl Error for Forbidden {}
+
+/// Whether `user` may perform `action` on `post` (`None` for `Create`,
+/// `Purge` and `ManageUsers`).
+///
+/// * admins may do anything, and are the only ones who may purge posts and
+///   manage users;
+/// * editors may create, edit and publish any post, and delete or restore
+///   drafts;
+/// * authors may create posts, and edit, delete or restore their own drafts;
+/// * readers may do nothing.
+pub fn can(user: &User, action: Action, post: Option<&Post>) -> bool {
+    let own_draft = post.is_some_and(|post| !post.published && post.author_id == Some(user.id));
+    let draft = post.is_some_and(|post| !post.published);
+
+    match (user.role, action) {
+        (Role::Admin, _) => true,
+        (_, Action::Purge) | (_, Action::ManageUsers) => false,
+        (Role::Editor, Action::Delete) | (Role::Editor, Action::Restore) => draft,
+        (Role::Editor, _) => true,
+        (Role::Author, Action::Create) => true,
+        (Role::Author, Action::Edit)
+        | (Role::Author, Action::Delete)
+        | (Role::Author, Action::Restore) => own_draft,
+        (Role::Author, Action::Publish) => false,
+        (Role::Reader, _) => false,
+    }
+}
+
+pub fn authorize(user: &User, action: Action, post: Option<&Post>) -> Result<(), Forbidden> {
+    if can(user, action, post) {
+        Ok(())
+    } else {
+        Err(Forbidden {
+            user_id: user.id,
+            role: user.role,
+            action,
+            post_id: post.map(|post| post.id),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum PolicyError {
+    Forbidden(Forbidden),
+    Update(UpdateError),
+    Guard(GuardError),
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PolicyError::Forbidden(e) => write!(f, "{}", e),
+            PolicyError::Update(e) => write!(f, "{}", e),
+            PolicyError::Guard(e) => write!(f, "{}", e),
+            PolicyError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for PolicyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PolicyError::Forbidden(e) => Some(e),
+            PolicyError::Update(e) => Some(e),
+            PolicyError::Guard(e) => Some(e),
+            PolicyError::Database(e) => Some(e),
+        }
+    }
+}
+
+impl From<Forbidden> for PolicyError {
+    fn from(e: Forbidden) -> Self {
+        PolicyError::Forbidden(e)
+    }
+}
+
+impl From<UpdateError> for PolicyError {
+    fn from(e: UpdateError) -> Self {
+        PolicyError::Update(e)
+    }
+}
+
+impl From<GuardError> for PolicyError {
+    fn from(e: GuardError) -> Self {
+        PolicyError::Guard(e)
+    }
+}
+
+impl From<diesel::result::Error> for PolicyError {
+    fn from(e: diesel::result::Error) -> Self {
+        PolicyError::Database(e)
+    }
+}
+
+impl crate::metrics::ErrorKind for PolicyError {
+    fn kind(&self) -> &'static str {
+        match self {
+            PolicyError::Forbidden(_) => "forbidden",
+            PolicyError::Update(e) => e.kind(),
+            PolicyError::Guard(e) => e.kind(),
+            PolicyError::Database(e) => e.kind(),
+        }
+    }
+}
+
+/// Changes a user's role; only admins may.
+pub fn set_role(
+    conn: &DbConnection,
+    actor: &User,
+    user_id: i32,
+    role: Role,
+) -> Result<usize, PolicyError> {
+    authorize(actor, Action::ManageUsers, None)?;
+
+    crate::metrics::track("set_role", || {
+        User::audited(conn, Some(actor), AuditAction::Update, &[user_id], || {
+            Ok(diesel::update(users::table.find(user_id))
+                .set((users::role.eq(role), users::version.eq(users::version + 1)))
+                .execute(conn)?)
+        })
+    })
+}
+
+/// Applies `changes` to a user at `expected_version`. Users may edit
+/// themselves; editing anybody else takes an admin.
+pub fn edit_user(
+    conn: &DbConnection,
+    actor: &User,
+    user_id: i32,
+    expected_version: i32,
+    changes: &UserChanges,
+) -> Result<User, PolicyError> {
+    if actor.id != user_id {
+        authorize(actor, Action::ManageUsers, None)?;
+    }
+
+    crate::metrics::track("edit_user", || {
+        User::audited(conn, Some(actor), AuditAction::Update, &[user_id], || {
+            Ok(update_user(conn, user_id, expected_version, changes)?)
+        })
+    })
+}
+
+/// Undoes the soft delete of users; only admins may.
+pub fn restore_users(conn: &DbConnection, actor: &User, ids: &[i32]) -> Result<usize, PolicyError> {
+    authorize(actor, Action::ManageUsers, None)?;
+
+    crate::metrics::track("restore_users", || {
+        Ok(soft_delete::restore_users(conn, actor, ids)?)
+    })
+}
+
+/// Permanently removes users soft deleted before `older_than`; only admins
+/// may.
+pub fn purge_users(
+    conn: &DbConnection,
+    actor: &User,
+    older_than: NaiveDateTime,
+    guard: &Guard,
+) -> Result<Outcome<User>, PolicyError> {
+    authorize(actor, Action::ManageUsers, None)?;
+
+    crate::metrics::track("purge_users", || {
+        Ok(soft_delete::purge_users(conn, actor, older_than, guard)?)
+    })
+}
+
+#[cfg(test)]
+fn user(id: i32, role: Role) -> User {
+    let now = chrono::NaiveDate::from_ymd(2020, 10, 1).and_hms(0, 0, 0);
+    User {
+        id,
+        name: "Sean".into(),
+        hair_color: None,
+        created_at: now,
+        updated_at: now,
+        deleted_at: None,
+        version: 0,
+        email: None,
+        password_hash: None,
+        role,
+    }
+}
+
+#[cfg(test)]
+fn post(author_id: i32, published: bool) -> Post {
+    let now = chrono::NaiveDate::from_ymd(2020, 10, 1).and_hms(0, 0, 0);
+    Post {
+        id: 7,
+        title: "Rust".into(),
+        body: "Diesel".into(),
+        published,
+        deleted_at: None,
+        version: 0,
+        author_id: Some(author_id),
+        slug: "rust".into(),
+        body_html: None,
+        created_at: now,
+        updated_at: now,
+        published_at: if published { Some(now) } else { None },
+    }
+}
+
+#[test]
+fn admins_may_do_anything() {
+    let admin = user(1, Role::Admin);
+    for action in &[
+        Action::Create,
+        Action::Edit,
+        Action::Publish,
+        Action::Delete,
+        Action::Restore,
+        Action::Purge,
+        Action::ManageUsers,
+    ] {
+        assert!(can(&admin, *action, Some(&post(2, true))));
+    }
+}
+
+#[test]
+fn editors_may_not_delete_published_posts() {
+    let editor = user(1, Role::Editor);
+
+    assert!(can(&editor, Action::Edit, Some(&post(2, true))));
+    assert!(can(&editor, Action::Publish, Some(&post(2, false))));
+    assert!(can(&editor, Action::Delete, Some(&post(2, false))));
+    assert!(!can(&editor, Action::Delete, Some(&post(2, true))));
+    assert!(can(&editor, Action::Restore, Some(&post(2, false))));
+    assert!(!can(&editor, Action::Purge, None));
+}
+
+#[test]
+fn authors_may_only_touch_their_own_drafts() {
+    let author = user(1, Role::Author);
+
+    assert!(can(&author, Action::Create, None));
+    assert!(can(&author, Action::Edit, Some(&post(1, false))));
+    assert!(can(&author, Action::Delete, Some(&post(1, false))));
+    assert!(!can(&author, Action::Edit, Some(&post(1, true))));
+    assert!(!can(&author, Action::Edit, Some(&post(2, false))));
+    assert!(!can(&author, Action::Publish, Some(&post(1, false))));
+    assert!(can(&author, Action::Restore, Some(&post(1, false))));
+    assert!(!can(&author, Action::Restore, Some(&post(2, false))));
+}
+
+#[test]
+fn readers_may_do_nothing() {
+    let reader = user(1, Role::Reader);
+
+    assert_eq!(
+        Err(Forbidden {
+            user_id: 1,
+            role: Role::Reader,
+            action: Action::Create,
+            post_id: None,
+        }),
+        authorize(&reader, Action::Create, None)
+    );
+    assert!(!can(&reader, Action::Edit, Some(&post(1, false))));
+}
+
+#[test]
+fn only_admins_manage_users() {
+    for role in &[Role::Editor, Role::Author, Role::Reader] {
+        let forbidden = authorize(&user(1, *role), Action::ManageUsers, None).unwrap_err();
+        assert_eq!(
+            format!("user 1 ({}) may not ManageUsers", role),
+            forbidden.to_string()
+        );
+    }
+    assert!(can(&user(1, Role::Admin), Action::ManageUsers, None));
+}
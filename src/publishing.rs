@@ -0,0 +1,253 @@
+use crate::audit::Audited;
+use crate::guard::{Guard, GuardError, Outcome};
+use crate::locking::{update_post, UpdateError};
+use crate::models::{AuditAction, Post, PostChanges};
+use crate::policy::{authorize, Action, Forbidden};
+use crate::slugs::refresh_slug;
+use crate::soft_delete::{
+    self, active_posts, deleted_posts, soft_delete_post, soft_delete_posts_matching,
+};
+use crate::validation::{Validate, ValidationErrors};
+use crate::{DbConnection, User};
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum PostError {
+    Forbidden(Forbidden),
+    Invalid(ValidationErrors),
+    Update(UpdateError),
+    Guard(GuardError),
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for PostError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PostError::Forbidden(e) => write!(f, "{}", e),
+            PostError::Invalid(e) => write!(f, "{}", e),
+            PostError::Update(e) => write!(f, "{}", e),
+            PostError::Guard(e) => write!(f, "{}", e),
+            PostError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for PostError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PostError::Forbidden(e) => Some(e),
+            PostError::Invalid(e) => Some(e),
+            PostError::Update(e) => Some(e),
+            PostError::Guard(e) => Some(e),
+            PostError::Database(e) => Some(e),
+        }
+    }
+}
+
+impl From<Forbidden> for PostError {
+    fn from(e: Forbidden) -> Self {
+        PostError::Forbidden(e)
+    }
+}
+
+impl From<ValidationErrors> for PostError {
+    fn from(e: ValidationErrors) -> Self {
+        PostError::Invalid(e)
+    }
+}
+
+impl From<UpdateError> for PostError {
+    fn from(e: UpdateError) -> Self {
+        PostError::Update(e)
+    }
+}
+
+impl From<GuardError> for PostError {
+    fn from(e: GuardError) -> Self {
+        PostError::Guard(e)
+    }
+}
+
+impl From<diesel::result::Error> for PostError {
+    fn from(e: diesel::result::Error) -> Self {
+        PostError::Database(e)
+    }
+}
+
+impl crate::metrics::ErrorKind for PostError {
+    fn kind(&self) -> &'static str {
+        match self {
+            PostError::Forbidden(_) => "forbidden",
+            PostError::Invalid(e) => e.kind(),
+            PostError::Update(e) => e.kind(),
+            PostError::Guard(e) => e.kind(),
+            PostError::Database(e) => e.kind(),
+        }
+    }
+}
+
+pub fn find_post(conn: &DbConnection, post_id: i32) -> Result<Post, PostError> {
+    Ok(active_posts().find(post_id).first(conn)?)
+}
+
+/// Applies `changes` if `actor` may edit the post, and may also publish it
+/// when `changes` publishes a draft. The changed fields are validated like a
+/// new post's, and a new title also gets a new slug.
+pub fn edit_post(
+    conn: &DbConnection,
+    actor: &User,
+    post_id: i32,
+    expected_version: i32,
+    changes: &PostChanges,
+) -> Result<Post, PostError> {
+    let changes = &changes.validate()?;
+    let action = if changes.published == Some(true) {
+        AuditAction::Publish
+    } else {
+        AuditAction::Update
+    };
+
+    crate::metrics::track("edit_post", || {
+        Post::audited(conn, Some(actor), action, &[post_id], || {
+            let post = find_post(conn, post_id)?;
+            authorize(actor, Action::Edit, Some(&post))?;
+            if changes.published == Some(true) && !post.published {
+                authorize(actor, Action::Publish, Some(&post))?;
+            }
+
+            let mut post = update_post(conn, post_id, expected_version, changes)?;
+            if changes.title.is_some() {
+                post.slug = refresh_slug(conn, &post)?;
+            }
+            Ok(post)
+        })
+    })
+}
+
+pub fn publish_post(conn: &DbConnection, actor: &User, post_id: i32) -> Result<Post, PostError> {
+    let changes = PostChanges {
+        published: Some(true),
+        ..PostChanges::default()
+    };
+
+    crate::metrics::track("publish_post", || {
+        Post::audited(conn, Some(actor), AuditAction::Publish, &[post_id], || {
+            let post = find_post(conn, post_id)?;
+            authorize(actor, Action::Publish, Some(&post))?;
+
+            Ok(update_post(conn, post_id, post.version, &changes)?)
+        })
+    })
+}
+
+pub fn delete_post(
+    conn: &DbConnection,
+    actor: &User,
+    post_id: i32,
+    guard: &Guard,
+) -> Result<Outcome<Post>, PostError> {
+    crate::metrics::track("delete_post", || {
+        Post::audited(conn, Some(actor), AuditAction::Delete, &[post_id], || {
+            let post = find_post(conn, post_id)?;
+            authorize(actor, Action::Delete, Some(&post))?;
+
+            Ok(soft_delete_post(conn, post_id, guard)?)
+        })
+    })
+}
+
+/// Deletes every post whose title contains `target`, provided `actor` may
+/// delete each one of them; a single forbidden post aborts the whole delete.
+pub fn delete_posts_matching(
+    conn: &DbConnection,
+    actor: &User,
+    target: &str,
+    guard: &Guard,
+) -> Result<Outcome<Post>, PostError> {
+    crate::metrics::track("delete_posts_matching", || {
+        conn.transaction(|| {
+            let preview =
+                soft_delete_posts_matching(conn, target, &Guard::dry_run(guard.max_rows))?;
+            if let Outcome::Preview { rows, .. } = &preview {
+                for post in rows {
+                    authorize(actor, Action::Delete, Some(post))?;
+                }
+            }
+
+            match (guard.mode, preview) {
+                (crate::guard::Mode::Execute, Outcome::Preview { rows, .. }) => {
+                    let ids = rows.iter().map(|post| post.id).collect::<Vec<_>>();
+                    Post::audited(conn, Some(actor), AuditAction::Delete, &ids, || {
+                        Ok(soft_delete_posts_matching(conn, target, guard)?)
+                    })
+                }
+                (_, preview) => Ok(preview),
+            }
+        })
+    })
+}
+
+/// Undoes the soft delete of posts, provided `actor` may restore each one;
+/// a single forbidden post aborts the whole restore.
+pub fn restore_posts(conn: &DbConnection, actor: &User, ids: &[i32]) -> Result<usize, PostError> {
+    crate::metrics::track("restore_posts", || {
+        conn.transaction(|| {
+            let posts = deleted_posts()
+                .filter(crate::schema::posts::id.eq_any(ids))
+                .load::<Post>(conn)?;
+            for post in &posts {
+                authorize(actor, Action::Restore, Some(post))?;
+            }
+
+            Ok(soft_delete::restore_posts(conn, actor, ids)?)
+        })
+    })
+}
+
+/// Permanently removes posts soft deleted before `older_than`; only admins
+/// may.
+pub fn purge_posts(
+    conn: &DbConnection,
+    actor: &User,
+    older_than: NaiveDateTime,
+    guard: &Guard,
+) -> Result<Outcome<Post>, PostError> {
+    authorize(actor, Action::Purge, None)?;
+
+    crate::metrics::track("purge_posts", || {
+        Ok(soft_delete::purge_posts(conn, actor, older_than, guard)?)
+    })
+}
+
+#[test]
+fn authors_cannot_publish_their_own_posts() {
+    use crate::fixtures::{seed, Profile};
+    use diesel::result::Error;
+
+    let conn = crate::establish_connection();
+    conn.test_transaction::<_, Error, _>(|| {
+        let seeded = seed(&conn, Profile::Minimal).unwrap();
+        let author = &seeded.users["ruby"];
+
+        let post = crate::create_post(&conn, author, "Draft", "Not yet").unwrap();
+        assert_eq!(Some(author.id), post.author_id);
+
+        match publish_post(&conn, author, post.id) {
+            Err(PostError::Forbidden(forbidden)) => assert_eq!(Action::Publish, forbidden.action),
+            other => panic!("expected Forbidden, got {:?}", other.map(|p| p.id)),
+        }
+
+        let changes = PostChanges {
+            title: Some("Still a draft"),
+            ..PostChanges::default()
+        };
+        let edited = edit_post(&conn, author, post.id, post.version, &changes).unwrap();
+        assert_eq!("Still a draft", edited.title);
+
+        Ok(())
+    });
+}
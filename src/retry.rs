@@ -0,0 +1,230 @@
+use crate::{metrics, DbConnection};
+use diesel::connection::TransactionManager;
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error};
+use rand::Rng;
+
+use std::cmp;
+use std::thread;
+use std::time::Duration;
+
+/// MySQL reports deadlocks (1213) and lock wait timeouts (1205) with an
+/// unknown error kind, so they can only be recognised by their message.
+const RETRIABLE_MESSAGES: [&str; 2] = [
+    "Deadlock found when trying to get lock",
+    "Lock wait timeout exceeded",
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first one.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(20),
+            max_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with full jitter: a random delay between zero and
+    /// `base_delay * 2^(attempt - 1)`, capped at `max_delay`.
+    pub fn delay_for(&self, attempt: u32) -> Duration {
+        let exponent = cmp::min(attempt.saturating_sub(1), 16);
+        let ceiling = cmp::min(self.base_delay * 2u32.pow(exponent), self.max_delay);
+        let millis = ceiling.as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+    }
+
+    /// The policy to use `depth` transactions deep. MySQL rolls back the
+    /// whole transaction on a deadlock, so a savepoint inside one cannot be
+    /// retried on its own; only the outermost transaction starts over.
+    pub fn at_depth(&self, depth: u32) -> RetryPolicy {
+        if depth == 0 {
+            *self
+        } else {
+            RetryPolicy {
+                max_attempts: 1,
+                ..*self
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RetryOutcome<T> {
+    pub result: QueryResult<T>,
+    /// How many times the work ran, including the one that produced `result`.
+    pub attempts: u32,
+}
+
+/// Whether running the same transaction again may succeed.
+pub fn is_retriable(error: &Error) -> bool {
+    match error {
+        Error::DatabaseError(DatabaseErrorKind::SerializationFailure, _) => true,
+        Error::DatabaseError(_, info) => RETRIABLE_MESSAGES
+            .iter()
+            .any(|message| info.message().contains(message)),
+        _ => false,
+    }
+}
+
+/// Runs `f` in a transaction, starting over with a fresh transaction when it
+/// fails because of a deadlock or lock wait timeout. Each attempt's duration
+/// is recorded under `operation`. Inside another transaction `f` runs once
+/// and the error is left for the outermost caller to retry.
+pub fn transaction_with_retry<T, F>(
+    conn: &DbConnection,
+    operation: &'static str,
+    policy: &RetryPolicy,
+    mut f: F,
+) -> RetryOutcome<T>
+where
+    F: FnMut() -> QueryResult<T>,
+{
+    let depth =
+        TransactionManager::<DbConnection>::get_transaction_depth(conn.transaction_manager());
+    retry_with(&policy.at_depth(depth), thread::sleep, || {
+        metrics::track_transaction(operation, || conn.transaction(&mut f))
+    })
+}
+
+/// The retry loop behind `transaction_with_retry`, with the sleep injected.
+pub fn retry_with<T, F, S>(policy: &RetryPolicy, mut sleep: S, mut f: F) -> RetryOutcome<T>
+where
+    F: FnMut() -> QueryResult<T>,
+    S: FnMut(Duration),
+{
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let result = f();
+        match &result {
+            Err(e) if is_retriable(e) && attempts < policy.max_attempts => {
+                log::warn!(
+                    "retrying after attempt {}/{}: {}",
+                    attempts,
+                    policy.max_attempts,
+                    e
+                );
+                sleep(policy.delay_for(attempts));
+            }
+            _ => return RetryOutcome { result, attempts },
+        }
+    }
+}
+
+#[cfg(test)]
+fn mysql_error(message: &str) -> Error {
+    Error::DatabaseError(DatabaseErrorKind::__Unknown, Box::new(message.to_string()))
+}
+
+#[test]
+fn classify_retriable_errors() {
+    assert!(is_retriable(&mysql_error(
+        "Deadlock found when trying to get lock; try restarting transaction"
+    )));
+    assert!(is_retriable(&mysql_error(
+        "Lock wait timeout exceeded; try restarting transaction"
+    )));
+    assert!(is_retriable(&Error::DatabaseError(
+        DatabaseErrorKind::SerializationFailure,
+        Box::new(String::new())
+    )));
+
+    assert!(!is_retriable(&mysql_error("Table 'users' doesn't exist")));
+    assert!(!is_retriable(&Error::DatabaseError(
+        DatabaseErrorKind::UniqueViolation,
+        Box::new("Duplicate entry '1' for key 'PRIMARY'".to_string())
+    )));
+    assert!(!is_retriable(&Error::NotFound));
+}
+
+#[test]
+fn retry_until_deadlock_clears() {
+    let policy = RetryPolicy::default();
+    let mut failures = 2;
+    let mut slept = Vec::new();
+
+    let outcome = retry_with(
+        &policy,
+        |delay| slept.push(delay),
+        || {
+            if failures > 0 {
+                failures -= 1;
+                Err(mysql_error("Deadlock found when trying to get lock"))
+            } else {
+                Ok(42)
+            }
+        },
+    );
+
+    assert_eq!(Ok(42), outcome.result);
+    assert_eq!(3, outcome.attempts);
+    assert_eq!(2, slept.len());
+}
+
+#[test]
+fn give_up_after_max_attempts() {
+    let policy = RetryPolicy {
+        max_attempts: 3,
+        ..RetryPolicy::default()
+    };
+
+    let outcome = retry_with(
+        &policy,
+        |_| {},
+        || Err::<(), _>(mysql_error("Lock wait timeout exceeded")),
+    );
+
+    assert!(outcome.result.is_err());
+    assert_eq!(3, outcome.attempts);
+}
+
+#[test]
+fn do_not_retry_other_errors() {
+    let outcome = retry_with(
+        &RetryPolicy::default(),
+        |_| {},
+        || Err::<(), _>(Error::NotFound),
+    );
+
+    assert_eq!(Err(Error::NotFound), outcome.result);
+    assert_eq!(1, outcome.attempts);
+}
+
+#[test]
+fn backoff_stays_under_the_cap() {
+    let policy = RetryPolicy {
+        max_attempts: 10,
+        base_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(50),
+    };
+
+    for attempt in 1..10 {
+        assert!(policy.delay_for(attempt) <= Duration::from_millis(50));
+    }
+    assert!(policy.delay_for(1) <= Duration::from_millis(10));
+}
+
+#[test]
+fn nested_transactions_are_not_retried() {
+    let policy = RetryPolicy::default();
+    assert_eq!(policy, policy.at_depth(0));
+
+    let outcome = retry_with(
+        &policy.at_depth(1),
+        |_| panic!("a nested transaction must not sleep and retry"),
+        || Err::<(), _>(mysql_error("Deadlock found when trying to get lock")),
+    );
+
+    assert!(outcome.result.is_err());
+    assert_eq!(1, outcome.attempts);
+}
@@ -1,9 +1,66 @@
+table! {
+    api_tokens (id) {
+        id -> Integer,
+        user_id -> Integer,
+        token_hash -> Char,
+        scopes -> Text,
+        expires_at -> Datetime,
+        last_used_at -> Nullable<Datetime>,
+        revoked_at -> Nullable<Datetime>,
+        created_at -> Datetime,
+    }
+}
+
+table! {
+    audit_log (id) {
+        id -> Integer,
+        actor_id -> Nullable<Integer>,
+        action -> Varchar,
+        table_name -> Varchar,
+        row_id -> Integer,
+        before_json -> Nullable<Text>,
+        after_json -> Nullable<Text>,
+        created_at -> Datetime,
+    }
+}
+
+table! {
+    post_sources (path) {
+        path -> Varchar,
+        post_id -> Integer,
+        imported_at -> Datetime,
+    }
+}
+
+table! {
+    post_tags (post_id, tag) {
+        post_id -> Integer,
+        tag -> Varchar,
+    }
+}
+
 table! {
     posts (id) {
         id -> Integer,
         title -> Varchar,
         body -> Text,
         published -> Bool,
+        deleted_at -> Nullable<Timestamp>,
+        version -> Integer,
+        author_id -> Nullable<Integer>,
+        slug -> Varchar,
+        body_html -> Nullable<Text>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        published_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    post_slugs (slug) {
+        slug -> Varchar,
+        post_id -> Integer,
+        created_at -> Datetime,
     }
 }
 
@@ -14,7 +71,26 @@ table! {
         hair_color -> Nullable<Text>,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        deleted_at -> Nullable<Timestamp>,
+        version -> Integer,
+        email -> Nullable<Varchar>,
+        password_hash -> Nullable<Varchar>,
+        role -> Varchar,
     }
 }
 
-allow_tables_to_appear_in_same_query!(posts, users,);
+joinable!(api_tokens -> users (user_id));
+joinable!(post_slugs -> posts (post_id));
+joinable!(post_sources -> posts (post_id));
+joinable!(post_tags -> posts (post_id));
+joinable!(posts -> users (author_id));
+
+allow_tables_to_appear_in_same_query!(
+    api_tokens,
+    audit_log,
+    post_slugs,
+    post_sources,
+    post_tags,
+    posts,
+    users,
+);
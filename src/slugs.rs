@@ -0,0 +1,328 @@
+use crate::models::Post;
+use crate::schema::{post_slugs, posts};
+use crate::soft_delete::active_posts;
+use crate::DbConnection;
+use deunicode::deunicode;
+use diesel::prelude::*;
+
+use std::collections::HashSet;
+
+/// Long enough to stay readable, short enough to leave room for a suffix
+/// within the `VARCHAR(255)` column.
+pub const MAX_SLUG_LEN: usize = 80;
+
+/// What a slug resolved to. `Moved` means the slug is an old one and callers
+/// should redirect to `post.slug`.
+#[derive(Debug)]
+pub enum SlugLookup {
+    Current(Post),
+    Moved(Post),
+}
+
+impl SlugLookup {
+    pub fn post(&self) -> &Post {
+        match self {
+            SlugLookup::Current(post) | SlugLookup::Moved(post) => post,
+        }
+    }
+
+    pub fn redirect_to(&self) -> Option<&str> {
+        match self {
+            SlugLookup::Current(_) => None,
+            SlugLookup::Moved(post) => Some(&post.slug),
+        }
+    }
+}
+
+/// Lowercase ASCII words joined by dashes, transliterating anything else,
+/// e.g. "Crème Brûlée" becomes "creme-brulee".
+pub fn slugify(title: &str) -> String {
+    let mut slug = String::new();
+    for c in deunicode(title).chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if c == '\'' {
+            continue;
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.truncate(MAX_SLUG_LEN);
+
+    match slug.trim_end_matches('-') {
+        "" => "post".to_string(),
+        slug => slug.to_string(),
+    }
+}
+
+/// Whether `slug` is `slugify(title)`, possibly with a collision suffix.
+pub fn slug_matches_title(slug: &str, title: &str) -> bool {
+    let base = slugify(title);
+    slug == base
+        || slug
+            .strip_prefix(&base)
+            .and_then(|rest| rest.strip_prefix('-'))
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn first_free(base: String, taken: &HashSet<String>) -> String {
+    if !taken.contains(&base) {
+        return base;
+    }
+    (2..)
+        .map(|n| format!("{}-{}", base, n))
+        .find(|slug| !taken.contains(slug))
+        .expect("some suffix is free")
+}
+
+/// Slugs starting with `base` held by other posts, now or in the past.
+fn taken_slugs(
+    conn: &DbConnection,
+    base: &str,
+    post_id: Option<i32>,
+) -> QueryResult<HashSet<String>> {
+    // Slugs only contain `[a-z0-9-]`, so nothing needs escaping.
+    let pattern = format!("{}%", base);
+    let mut current = posts::table
+        .filter(posts::slug.like(&pattern))
+        .select(posts::slug)
+        .into_boxed();
+    let mut old = post_slugs::table
+        .filter(post_slugs::slug.like(&pattern))
+        .select(post_slugs::slug)
+        .into_boxed();
+    if let Some(post_id) = post_id {
+        current = current.filter(posts::id.ne(post_id));
+        old = old.filter(post_slugs::post_id.ne(post_id));
+    }
+
+    let mut taken = current
+        .load::<String>(conn)?
+        .into_iter()
+        .collect::<HashSet<_>>();
+    taken.extend(old.load::<String>(conn)?);
+    Ok(taken)
+}
+
+/// A slug for `title` no other post uses or has used. Pass the post's own id
+/// when renaming so it may take back one of its old slugs.
+pub fn unique_slug(conn: &DbConnection, title: &str, post_id: Option<i32>) -> QueryResult<String> {
+    let base = slugify(title);
+    let taken = taken_slugs(conn, &base, post_id)?;
+    Ok(first_free(base, &taken))
+}
+
+/// Each of `wanted`, or that slug with a suffix if another post has or had
+/// it or an earlier one in `wanted` took it. Looks the whole list up at once,
+/// so it suits batches of slugs that rarely collide.
+pub fn claim_slugs(conn: &DbConnection, wanted: Vec<String>) -> QueryResult<Vec<String>> {
+    let current = posts::table
+        .filter(posts::slug.eq_any(&wanted))
+        .select(posts::slug)
+        .load::<String>(conn)?;
+    let old = post_slugs::table
+        .filter(post_slugs::slug.eq_any(&wanted))
+        .select(post_slugs::slug)
+        .load::<String>(conn)?;
+    let taken = current.into_iter().chain(old).collect::<HashSet<_>>();
+
+    let mut claimed = HashSet::new();
+    let mut slugs = Vec::with_capacity(wanted.len());
+    for slug in wanted {
+        let slug = if taken.contains(&slug) || claimed.contains(&slug) {
+            let mut taken = taken_slugs(conn, &slug, None)?;
+            taken.extend(claimed.iter().cloned());
+            first_free(slug, &taken)
+        } else {
+            slug
+        };
+        claimed.insert(slug.clone());
+        slugs.push(slug);
+    }
+    Ok(slugs)
+}
+
+/// Looks a post up by its current slug, then by the slugs it used to have.
+pub fn find_by_slug(conn: &DbConnection, slug: &str) -> QueryResult<Option<SlugLookup>> {
+    let current = active_posts()
+        .filter(posts::slug.eq(slug))
+        .first::<Post>(conn)
+        .optional()?;
+    if let Some(post) = current {
+        return Ok(Some(SlugLookup::Current(post)));
+    }
+
+    let moved = post_slugs::table
+        .inner_join(posts::table)
+        .filter(post_slugs::slug.eq(slug))
+        .filter(posts::deleted_at.is_null())
+        .select(posts::all_columns)
+        .first::<Post>(conn)
+        .optional()?;
+    Ok(moved.map(SlugLookup::Moved))
+}
+
+/// Gives `post` a slug matching its title if it no longer has one, keeping
+/// the previous slug in `post_slugs`. Returns the slug the post ends up with.
+pub fn refresh_slug(conn: &DbConnection, post: &Post) -> QueryResult<String> {
+    set_slug(conn, post, &post.title)
+}
+
+/// Gives `post` the slug for `wanted`, or that slug with a suffix if another
+/// post holds it, keeping the previous slug in `post_slugs`. Does nothing if
+/// the post already has it. Returns the slug the post ends up with.
+pub fn set_slug(conn: &DbConnection, post: &Post, wanted: &str) -> QueryResult<String> {
+    if slug_matches_title(&post.slug, wanted) {
+        return Ok(post.slug.clone());
+    }
+
+    conn.transaction(|| {
+        let slug = unique_slug(conn, wanted, Some(post.id))?;
+        diesel::delete(
+            post_slugs::table
+                .filter(post_slugs::slug.eq(&slug))
+                .filter(post_slugs::post_id.eq(post.id)),
+        )
+        .execute(conn)?;
+        diesel::insert_into(post_slugs::table)
+            .values((
+                post_slugs::slug.eq(&post.slug),
+                post_slugs::post_id.eq(post.id),
+            ))
+            .execute(conn)?;
+        diesel::update(posts::table.find(post.id))
+            .set(posts::slug.eq(&slug))
+            .execute(conn)?;
+
+        Ok(slug)
+    })
+}
+
+#[test]
+fn slugify_transliterates_unicode() {
+    assert_eq!("creme-brulee", slugify("Crème Brûlée"));
+    assert_eq!("bei-jing", slugify("北京"));
+    assert_eq!("dont-panic", slugify("  Don't panic!  "));
+    assert_eq!("rust-diesel-2-0", slugify("Rust & Diesel -- 2.0"));
+    assert_eq!("post", slugify("!!!"));
+}
+
+#[test]
+fn slugify_truncates_long_titles() {
+    let slug = slugify(&"word ".repeat(40));
+
+    assert!(slug.len() <= MAX_SLUG_LEN);
+    assert!(!slug.ends_with('-'));
+}
+
+#[test]
+fn collisions_get_numeric_suffixes() {
+    let taken = ["rust", "rust-2", "rust-lang"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<HashSet<_>>();
+
+    assert_eq!("rust-3", first_free("rust".to_string(), &taken));
+    assert_eq!("diesel", first_free("diesel".to_string(), &taken));
+}
+
+#[test]
+fn suffixed_slugs_still_match_their_title() {
+    assert!(slug_matches_title("rust", "Rust"));
+    assert!(slug_matches_title("rust-3", "Rust"));
+    assert!(!slug_matches_title("rust-lang", "Rust"));
+    assert!(!slug_matches_title("rust-", "Rust"));
+    assert!(!slug_matches_title("post-5", "Rust"));
+}
+
+#[test]
+fn examine_sql_from_find_by_slug() {
+    use diesel::debug_query;
+    use diesel::mysql::Mysql;
+
+    let query = post_slugs::table
+        .inner_join(posts::table)
+        .filter(post_slugs::slug.eq("old-title"))
+        .filter(posts::deleted_at.is_null())
+        .select(posts::id);
+    let sql = "SELECT `posts`.`id` FROM (`post_slugs` INNER JOIN `posts` \
+               ON `post_slugs`.`post_id` = `posts`.`id`) \
+               WHERE `post_slugs`.`slug` = ? AND `posts`.`deleted_at` IS NULL \
+               -- binds: [\"old-title\"]";
+    assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
+}
+
+#[test]
+fn renamed_posts_keep_resolving() {
+    use crate::fixtures::{seed, Profile};
+    use crate::models::PostChanges;
+    use crate::publishing::edit_post;
+    use diesel::result::Error;
+
+    let conn = crate::establish_connection();
+    conn.test_transaction::<_, Error, _>(|| {
+        let seeded = seed(&conn, Profile::Minimal).unwrap();
+        let author = &seeded.users["ruby"];
+        // Titles carry the fresh author id, so slugs left in a development
+        // database cannot collide with them.
+        let title = format!("Slug test {}", author.id);
+        let new_title = format!("Renamed {}", author.id);
+
+        let first = crate::create_post(&conn, author, &title, "one").unwrap();
+        let second = crate::create_post(&conn, author, &title, "two").unwrap();
+        assert_eq!(slugify(&title), first.slug);
+        assert_eq!(format!("{}-2", first.slug), second.slug);
+
+        let changes = PostChanges {
+            title: Some(&new_title),
+            ..PostChanges::default()
+        };
+        let renamed = edit_post(&conn, author, first.id, first.version, &changes).unwrap();
+        assert_eq!(slugify(&new_title), renamed.slug);
+
+        match find_by_slug(&conn, &first.slug)? {
+            Some(lookup) => assert_eq!(Some(renamed.slug.as_str()), lookup.redirect_to()),
+            None => panic!("old slug no longer resolves"),
+        }
+        let third = crate::create_post(&conn, author, &title, "three").unwrap();
+        assert_eq!(format!("{}-3", first.slug), third.slug);
+
+        Ok(())
+    });
+}
+
+#[test]
+fn claimed_slugs_avoid_current_and_old_ones() {
+    use crate::fixtures::{seed, Profile};
+    use diesel::result::Error;
+
+    let conn = crate::establish_connection();
+    conn.test_transaction::<_, Error, _>(|| {
+        let seeded = seed(&conn, Profile::Minimal).unwrap();
+        let author = &seeded.users["ruby"];
+        let title = format!("Notes on Rust {}", author.id);
+        let taken = slugify(&title);
+        let fresh = format!("fresh-{}", author.id);
+        crate::create_post(&conn, author, &title, "one").unwrap();
+        crate::create_post(&conn, author, &title, "two").unwrap();
+
+        let wanted = vec![
+            format!("{}-2", taken),
+            fresh.clone(),
+            fresh.clone(),
+            format!("{}-3", taken),
+        ];
+        let claimed = claim_slugs(&conn, wanted)?;
+        assert_eq!(
+            vec![
+                format!("{}-2-2", taken),
+                fresh.clone(),
+                format!("{}-2", fresh),
+                format!("{}-3", taken),
+            ],
+            claimed
+        );
+
+        Ok(())
+    });
+}
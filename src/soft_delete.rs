@@ -0,0 +1,196 @@
+use crate::audit::Audited;
+use crate::guard::{guarded, Guard, GuardError, Outcome};
+use crate::models::{AuditAction, Post};
+use crate::schema::{posts, users};
+use crate::{DbConnection, User};
+use chrono::NaiveDateTime;
+use diesel::dsl::{now, Filter, IsNotNull, IsNull};
+use diesel::prelude::*;
+
+pub type ActiveUsers = Filter<users::table, IsNull<users::deleted_at>>;
+pub type DeletedUsers = Filter<users::table, IsNotNull<users::deleted_at>>;
+pub type ActivePosts = Filter<posts::table, IsNull<posts::deleted_at>>;
+pub type DeletedPosts = Filter<posts::table, IsNotNull<posts::deleted_at>>;
+
+/// Users that have not been soft deleted. Start every default query here.
+pub fn active_users() -> ActiveUsers {
+    users::table.filter(users::deleted_at.is_null())
+}
+
+/// Users that have been soft deleted and can still be restored.
+pub fn deleted_users() -> DeletedUsers {
+    users::table.filter(users::deleted_at.is_not_null())
+}
+
+/// Posts that have not been soft deleted. Start every default query here.
+pub fn active_posts() -> ActivePosts {
+    posts::table.filter(posts::deleted_at.is_null())
+}
+
+/// Posts that have been soft deleted and can still be restored.
+pub fn deleted_posts() -> DeletedPosts {
+    posts::table.filter(posts::deleted_at.is_not_null())
+}
+
+pub(crate) fn soft_delete_all_users(
+    conn: &DbConnection,
+    guard: &Guard,
+) -> Result<Outcome<User>, GuardError> {
+    guarded(
+        conn,
+        guard,
+        active_users(),
+        diesel::update(active_users()).set((
+            users::deleted_at.eq(now.nullable()),
+            users::version.eq(users::version + 1),
+        )),
+    )
+}
+
+pub(crate) fn soft_delete_posts_matching(
+    conn: &DbConnection,
+    target: &str,
+    guard: &Guard,
+) -> Result<Outcome<Post>, GuardError> {
+    let pattern = format!("%{}%", target);
+    let matching = active_posts().filter(posts::title.like(pattern));
+
+    guarded(
+        conn,
+        guard,
+        matching.clone(),
+        diesel::update(matching).set((
+            posts::deleted_at.eq(now.nullable()),
+            posts::version.eq(posts::version + 1),
+        )),
+    )
+}
+
+pub(crate) fn soft_delete_post(
+    conn: &DbConnection,
+    post_id: i32,
+    guard: &Guard,
+) -> Result<Outcome<Post>, GuardError> {
+    let matching = active_posts().filter(posts::id.eq(post_id));
+
+    guarded(
+        conn,
+        guard,
+        matching,
+        diesel::update(matching).set((
+            posts::deleted_at.eq(now.nullable()),
+            posts::version.eq(posts::version + 1),
+        )),
+    )
+}
+
+pub(crate) fn restore_users(conn: &DbConnection, actor: &User, ids: &[i32]) -> QueryResult<usize> {
+    User::audited(conn, Some(actor), AuditAction::Restore, ids, || {
+        diesel::update(deleted_users().filter(users::id.eq_any(ids)))
+            .set((
+                users::deleted_at.eq(None::<NaiveDateTime>),
+                users::version.eq(users::version + 1),
+            ))
+            .execute(conn)
+    })
+}
+
+pub(crate) fn restore_posts(conn: &DbConnection, actor: &User, ids: &[i32]) -> QueryResult<usize> {
+    Post::audited(conn, Some(actor), AuditAction::Restore, ids, || {
+        diesel::update(deleted_posts().filter(posts::id.eq_any(ids)))
+            .set((
+                posts::deleted_at.eq(None::<NaiveDateTime>),
+                posts::version.eq(posts::version + 1),
+            ))
+            .execute(conn)
+    })
+}
+
+/// Permanently removes users soft deleted before `older_than`.
+pub(crate) fn purge_users(
+    conn: &DbConnection,
+    actor: &User,
+    older_than: NaiveDateTime,
+    guard: &Guard,
+) -> Result<Outcome<User>, GuardError> {
+    let matching = users::table.filter(users::deleted_at.lt(older_than));
+
+    conn.transaction(|| {
+        let ids = matching.select(users::id).load::<i32>(conn)?;
+        User::audited(conn, Some(actor), AuditAction::Purge, &ids, || {
+            guarded(conn, guard, matching, diesel::delete(matching))
+        })
+    })
+}
+
+/// Permanently removes posts soft deleted before `older_than`.
+pub(crate) fn purge_posts(
+    conn: &DbConnection,
+    actor: &User,
+    older_than: NaiveDateTime,
+    guard: &Guard,
+) -> Result<Outcome<Post>, GuardError> {
+    let matching = posts::table.filter(posts::deleted_at.lt(older_than));
+
+    conn.transaction(|| {
+        let ids = matching.select(posts::id).load::<i32>(conn)?;
+        Post::audited(conn, Some(actor), AuditAction::Purge, &ids, || {
+            guarded(conn, guard, matching, diesel::delete(matching))
+        })
+    })
+}
+
+#[test]
+fn examine_sql_from_active_users() {
+    use diesel::debug_query;
+    use diesel::mysql::Mysql;
+
+    let query = active_users().select(users::name);
+    let sql = "SELECT `users`.`name` FROM `users` \
+               WHERE `users`.`deleted_at` IS NULL -- binds: []";
+    assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
+}
+
+#[test]
+fn examine_sql_from_soft_delete_posts_matching() {
+    use diesel::debug_query;
+    use diesel::mysql::Mysql;
+
+    let query = diesel::update(active_posts().filter(posts::title.like("%rust%"))).set((
+        posts::deleted_at.eq(now.nullable()),
+        posts::version.eq(posts::version + 1),
+    ));
+    let sql = "UPDATE `posts` SET `deleted_at` = CURRENT_TIMESTAMP, \
+               `version` = (`posts`.`version` + ?) \
+               WHERE `posts`.`deleted_at` IS NULL AND `posts`.`title` LIKE ? \
+               -- binds: [1, \"%rust%\"]";
+    assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
+}
+
+#[test]
+fn examine_sql_from_restore_posts() {
+    use diesel::debug_query;
+    use diesel::mysql::Mysql;
+
+    let ids = [1, 2];
+    let query = diesel::update(deleted_posts().filter(posts::id.eq_any(&ids[..]))).set((
+        posts::deleted_at.eq(None::<NaiveDateTime>),
+        posts::version.eq(posts::version + 1),
+    ));
+    let sql = "UPDATE `posts` SET `deleted_at` = ?, `version` = (`posts`.`version` + ?) \
+               WHERE `posts`.`deleted_at` IS NOT NULL AND `posts`.`id` IN (?, ?) \
+               -- binds: [None, 1, 1, 2]";
+    assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
+}
+
+#[test]
+fn examine_sql_from_purge_users() {
+    use diesel::debug_query;
+    use diesel::mysql::Mysql;
+
+    let older_than = chrono::NaiveDate::from_ymd(2020, 9, 1).and_hms(0, 0, 0);
+    let query = diesel::delete(users::table.filter(users::deleted_at.lt(older_than)));
+    let sql = "DELETE FROM `users` WHERE `users`.`deleted_at` < ? \
+               -- binds: [2020-09-01T00:00:00]";
+    assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
+}
@@ -0,0 +1,420 @@
+use crate::feed::{self, escape, FeedConfig, FeedEntry};
+use crate::DbConnection;
+
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const DEFAULT_PER_PAGE: usize = 10;
+
+/// The HTML the site is made from. Placeholders look like `{{title}}`; see
+/// `templates/site` for the ones each template receives. Values are escaped
+/// already, except `content`, `posts` and `pagination`, which are HTML.
+/// Links are relative, so the site works from disk or under any path; the
+/// layout's `{{root}}` leads back to the site root, e.g. `../../`.
+#[derive(Clone, Debug)]
+pub struct Templates {
+    pub layout: String,
+    pub index: String,
+    pub index_item: String,
+    pub post: String,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Templates {
+            layout: include_str!("../templates/site/layout.html").to_string(),
+            index: include_str!("../templates/site/index.html").to_string(),
+            index_item: include_str!("../templates/site/index_item.html").to_string(),
+            post: include_str!("../templates/site/post.html").to_string(),
+        }
+    }
+}
+
+impl Templates {
+    /// The default templates, replaced by any of `layout.html`, `index.html`,
+    /// `index_item.html` and `post.html` found in `dir`.
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let mut templates = Templates::default();
+        for (name, template) in [
+            ("layout.html", &mut templates.layout),
+            ("index.html", &mut templates.index),
+            ("index_item.html", &mut templates.index_item),
+            ("post.html", &mut templates.post),
+        ] {
+            match fs::read_to_string(dir.join(name)) {
+                Ok(contents) => *template = contents,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(templates)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SiteOptions {
+    pub per_page: usize,
+    pub templates: Templates,
+}
+
+impl Default for SiteOptions {
+    fn default() -> Self {
+        SiteOptions {
+            per_page: DEFAULT_PER_PAGE,
+            templates: Templates::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ExportSummary {
+    pub posts: usize,
+    pub index_pages: usize,
+}
+
+/// Replaces each `{{name}}` in one pass, so values are never re-expanded.
+/// Unknown placeholders are left as they are.
+fn fill(template: &str, values: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find("}}") {
+            Some(end) => {
+                let name = after[..end].trim();
+                match values.iter().find(|(key, _)| *key == name) {
+                    Some((_, value)) => out.push_str(value),
+                    None => out.push_str(&rest[start..start + 2 + end + 2]),
+                }
+                rest = &after[end + 2..];
+            }
+            None => {
+                out.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Directories of pages, relative to the site root.
+fn post_path(entry: &FeedEntry) -> String {
+    format!("posts/{}/", entry.post.slug)
+}
+
+fn index_path(page: usize) -> String {
+    if page == 1 {
+        String::new()
+    } else {
+        format!("page/{}/", page)
+    }
+}
+
+/// The way back to the site root from the page in `dir`.
+fn root_of(dir: &str) -> String {
+    "../".repeat(dir.matches('/').count())
+}
+
+/// A link from a page `root` away from the site root to the page in `dir`.
+/// Naming `index.html` keeps links working when opened from disk.
+fn link(root: &str, dir: &str) -> String {
+    format!("{}{}index.html", root, dir)
+}
+
+/// Writes `html` to `dir` + `url_path` + `index.html`.
+fn write_page(dir: &Path, url_path: &str, html: &str) -> io::Result<()> {
+    let page_dir = dir.join(url_path);
+    fs::create_dir_all(&page_dir)?;
+    fs::write(page_dir.join("index.html"), html)
+}
+
+fn render_post(config: &FeedConfig, templates: &Templates, entry: &FeedEntry) -> String {
+    let post = &entry.post;
+    let published = entry.post.published_at.unwrap_or(post.created_at);
+    let author = entry
+        .author
+        .as_ref()
+        .map(|name| format!("by {}", escape(name)))
+        .unwrap_or_default();
+    let title = escape(&post.title);
+    let root = root_of(&post_path(entry));
+
+    let content = fill(
+        &templates.post,
+        &[
+            ("title", &title),
+            ("date", &published.format("%B %e, %Y").to_string()),
+            ("datetime", &published.format("%Y-%m-%d").to_string()),
+            ("author", &author),
+            ("content", &post.html()),
+        ],
+    );
+    fill(
+        &templates.layout,
+        &[
+            ("site_title", &escape(&config.title)),
+            ("title", &title),
+            ("root", &root),
+            ("content", &content),
+        ],
+    )
+}
+
+fn render_index(
+    config: &FeedConfig,
+    templates: &Templates,
+    entries: &[FeedEntry],
+    page: usize,
+    pages: usize,
+) -> String {
+    let root = root_of(&index_path(page));
+    let mut items = String::new();
+    for entry in entries {
+        let published = entry.post.published_at.unwrap_or(entry.post.created_at);
+        items.push_str(&fill(
+            &templates.index_item,
+            &[
+                ("url", &link(&root, &post_path(entry))),
+                ("title", &escape(&entry.post.title)),
+                ("date", &published.format("%B %e, %Y").to_string()),
+                ("datetime", &published.format("%Y-%m-%d").to_string()),
+                ("excerpt", &escape(&entry.post.excerpt())),
+            ],
+        ));
+    }
+
+    let mut pagination = String::new();
+    if page > 1 {
+        let _ = write!(
+            pagination,
+            "<a rel=\"prev\" href=\"{}\">Newer posts</a>",
+            link(&root, &index_path(page - 1))
+        );
+    }
+    if page < pages {
+        let _ = write!(
+            pagination,
+            "<a rel=\"next\" href=\"{}\">Older posts</a>",
+            link(&root, &index_path(page + 1))
+        );
+    }
+
+    let site_title = escape(&config.title);
+    let content = fill(
+        &templates.index,
+        &[
+            ("site_title", &site_title),
+            ("description", &escape(&config.description)),
+            ("posts", &items),
+            ("pagination", &pagination),
+            ("page", &page.to_string()),
+            ("pages", &pages.to_string()),
+        ],
+    );
+    let title = if page == 1 {
+        "Home".to_string()
+    } else {
+        format!("Page {}", page)
+    };
+    fill(
+        &templates.layout,
+        &[
+            ("site_title", &site_title),
+            ("title", &title),
+            ("root", &root),
+            ("content", &content),
+        ],
+    )
+}
+
+fn sitemap(config: &FeedConfig, entries: &[FeedEntry]) -> String {
+    let base = config.site_url.trim_end_matches('/');
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+    let _ = writeln!(xml, "<url><loc>{}/</loc></url>", escape(base));
+    for entry in entries {
+        let _ = writeln!(
+            xml,
+            "<url><loc>{}/{}</loc><lastmod>{}</lastmod></url>",
+            escape(base),
+            escape(&post_path(entry)),
+            entry.post.updated_at.format("%Y-%m-%d")
+        );
+    }
+    xml.push_str("</urlset>\n");
+    xml
+}
+
+/// Renders `entries`, newest first, into `out_dir`: paginated index pages,
+/// a page per post under `posts/<slug>/`, `sitemap.xml` and both feeds.
+pub fn write_site(
+    config: &FeedConfig,
+    entries: &[FeedEntry],
+    out_dir: &Path,
+    options: &SiteOptions,
+) -> io::Result<ExportSummary> {
+    let per_page = options.per_page.max(1);
+    let pages = entries.len().div_ceil(per_page).max(1);
+    fs::create_dir_all(out_dir)?;
+
+    for entry in entries {
+        let html = render_post(config, &options.templates, entry);
+        write_page(out_dir, &post_path(entry), &html)?;
+    }
+    for page in 1..=pages {
+        let start = (page - 1) * per_page;
+        let chunk = &entries[start..entries.len().min(start + per_page)];
+        let html = render_index(config, &options.templates, chunk, page, pages);
+        write_page(out_dir, &index_path(page), &html)?;
+    }
+
+    fs::write(out_dir.join("sitemap.xml"), sitemap(config, entries))?;
+    fs::write(out_dir.join("feed.rss"), feed::rss(config, entries))?;
+    fs::write(out_dir.join("feed.atom"), feed::atom(config, entries))?;
+
+    Ok(ExportSummary {
+        posts: entries.len(),
+        index_pages: pages,
+    })
+}
+
+/// Exports every published post; see `write_site`.
+pub fn export_site(
+    conn: &DbConnection,
+    config: &FeedConfig,
+    out_dir: &Path,
+    options: &SiteOptions,
+) -> Result<ExportSummary, Box<dyn Error>> {
+    let entries = crate::metrics::track("export_site", || feed::published_entries(conn, i64::MAX))?;
+    Ok(write_site(config, &entries, out_dir, options)?)
+}
+
+#[cfg(test)]
+fn entries(count: i32) -> Vec<FeedEntry> {
+    let published = chrono::NaiveDate::from_ymd(2020, 10, 1).and_hms(9, 30, 0);
+    (1..=count)
+        .rev()
+        .map(|id| FeedEntry {
+            post: crate::models::Post {
+                id,
+                title: format!("Post <{}>", id),
+                body: format!("Body of **post {}**", id),
+                published: true,
+                deleted_at: None,
+                version: 0,
+                author_id: Some(1),
+                slug: format!("post-{}", id),
+                body_html: None,
+                created_at: published,
+                updated_at: published,
+                published_at: Some(published),
+            },
+            author: Some("Sean".into()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn config() -> FeedConfig {
+    FeedConfig {
+        title: "Diesel demo".into(),
+        site_url: "https://blog.example.com".into(),
+        description: "Notes".into(),
+        author: "Editors".into(),
+    }
+}
+
+#[test]
+fn fill_replaces_placeholders_once() {
+    assert_eq!(
+        "<h1>{{title}}</h1> {{unknown}} {{",
+        fill(
+            "<h1>{{ title }}</h1> {{unknown}} {{",
+            &[("title", "{{title}}")]
+        )
+    );
+}
+
+#[test]
+fn writes_paginated_site() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = SiteOptions {
+        per_page: 2,
+        ..SiteOptions::default()
+    };
+
+    let summary = write_site(&config(), &entries(5), dir.path(), &options).unwrap();
+    assert_eq!(
+        ExportSummary {
+            posts: 5,
+            index_pages: 3
+        },
+        summary
+    );
+
+    let first = fs::read_to_string(dir.path().join("index.html")).unwrap();
+    assert!(first.contains("<a href=\"posts/post-5/index.html\">Post &lt;5&gt;</a>"));
+    assert!(first.contains("<a rel=\"next\" href=\"page/2/index.html\">Older posts</a>"));
+    assert!(first.contains("<header><a href=\"index.html\">"));
+    assert!(!first.contains("post-3"));
+
+    let last = fs::read_to_string(dir.path().join("page/3/index.html")).unwrap();
+    assert!(last.contains("<a href=\"../../posts/post-1/index.html\">"));
+    assert!(last.contains("<a rel=\"prev\" href=\"../../page/2/index.html\">Newer posts</a>"));
+    assert!(!last.contains("href=\"/"));
+    assert!(!last.contains("Older posts"));
+
+    let post = fs::read_to_string(dir.path().join("posts/post-2/index.html")).unwrap();
+    assert!(post.contains("<title>Post &lt;2&gt; | Diesel demo</title>"));
+    assert!(post.contains("<strong>post 2</strong>"));
+    assert!(post.contains("by Sean"));
+    assert!(post.contains("<header><a href=\"../../index.html\">"));
+    assert!(post.contains("href=\"../../feed.atom\""));
+
+    let sitemap = fs::read_to_string(dir.path().join("sitemap.xml")).unwrap();
+    assert_eq!(6, sitemap.matches("<url>").count());
+    assert!(sitemap.contains("<loc>https://blog.example.com/posts/post-4/</loc>"));
+    assert!(dir.path().join("feed.atom").exists());
+}
+
+#[test]
+fn empty_site_has_an_index() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let summary = write_site(&config(), &[], dir.path(), &SiteOptions::default()).unwrap();
+    assert_eq!(1, summary.index_pages);
+    assert!(dir.path().join("index.html").exists());
+}
+
+#[test]
+fn templates_can_be_overridden() {
+    let templates_dir = tempfile::tempdir().unwrap();
+    fs::write(
+        templates_dir.path().join("post.html"),
+        "<div class=\"custom\">{{title}}</div>",
+    )
+    .unwrap();
+    let options = SiteOptions {
+        templates: Templates::load(templates_dir.path()).unwrap(),
+        ..SiteOptions::default()
+    };
+    assert_eq!(Templates::default().layout, options.templates.layout);
+
+    let out = tempfile::tempdir().unwrap();
+    write_site(&config(), &entries(1), out.path(), &options).unwrap();
+    let post = fs::read_to_string(out.path().join("posts/post-1/index.html")).unwrap();
+    assert!(post.contains("<div class=\"custom\">Post &lt;1&gt;</div>"));
+}
+
+#[test]
+fn links_are_relative_to_each_page() {
+    assert_eq!("", root_of(&index_path(1)));
+    assert_eq!("../../", root_of(&index_path(3)));
+    assert_eq!("../../index.html", link("../../", &index_path(1)));
+}
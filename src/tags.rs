@@ -0,0 +1,121 @@
+use crate::models::Post;
+use crate::schema::{post_tags, posts};
+use crate::soft_delete::active_posts;
+use crate::DbConnection;
+use diesel::prelude::*;
+
+use std::collections::BTreeSet;
+
+/// Matches the `post_tags.tag` column.
+pub const MAX_TAG_LEN: usize = 64;
+
+/// Trimmed, lowercase, without empties or duplicates, in sorted order.
+pub fn normalize_tags<S: AsRef<str>>(tags: &[S]) -> Vec<String> {
+    tags.iter()
+        .map(|tag| tag.as_ref().trim().to_lowercase())
+        .filter(|tag| !tag.is_empty())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+pub fn tags_for(conn: &DbConnection, post_id: i32) -> QueryResult<Vec<String>> {
+    post_tags::table
+        .filter(post_tags::post_id.eq(post_id))
+        .select(post_tags::tag)
+        .order(post_tags::tag)
+        .load(conn)
+}
+
+/// Replaces the tags of a post. Tags are exported with the post, so a change
+/// also bumps its `updated_at`; setting the same tags again changes nothing.
+pub fn set_tags<S: AsRef<str>>(conn: &DbConnection, post_id: i32, tags: &[S]) -> QueryResult<()> {
+    let tags = normalize_tags(tags);
+
+    conn.transaction(|| {
+        let current = tags_for(conn, post_id)?;
+        if current.iter().collect::<BTreeSet<_>>() == tags.iter().collect::<BTreeSet<_>>() {
+            return Ok(());
+        }
+
+        let rows = tags
+            .iter()
+            .map(|tag| (post_tags::post_id.eq(post_id), post_tags::tag.eq(tag)))
+            .collect::<Vec<_>>();
+        diesel::delete(post_tags::table.filter(post_tags::post_id.eq(post_id))).execute(conn)?;
+        if !rows.is_empty() {
+            diesel::insert_into(post_tags::table)
+                .values(&rows)
+                .execute(conn)?;
+        }
+        diesel::update(posts::table.find(post_id))
+            .set(posts::updated_at.eq(diesel::dsl::now))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+pub fn posts_tagged(conn: &DbConnection, tag: &str) -> QueryResult<Vec<Post>> {
+    active_posts()
+        .inner_join(post_tags::table)
+        .filter(post_tags::tag.eq(tag.trim().to_lowercase()))
+        .select(posts::all_columns)
+        .order(posts::id)
+        .load(conn)
+}
+
+#[test]
+fn tags_are_normalised() {
+    assert_eq!(
+        vec!["diesel", "rust"],
+        normalize_tags(&[" Rust", "diesel", "RUST", ""])
+    );
+}
+
+#[test]
+fn retagging_bumps_updated_at() {
+    use crate::fixtures::{seed, Profile};
+    use chrono::NaiveDate;
+    use diesel::result::Error;
+
+    let conn = crate::establish_connection();
+    conn.test_transaction::<_, Error, _>(|| {
+        let seeded = seed(&conn, Profile::Minimal).unwrap();
+        let post = &seeded.posts["welcome"];
+        let long_ago = NaiveDate::from_ymd(2020, 1, 1).and_hms(0, 0, 0);
+        diesel::update(posts::table.find(post.id))
+            .set(posts::updated_at.eq(long_ago))
+            .execute(&conn)?;
+        let updated_at = || {
+            posts::table
+                .find(post.id)
+                .select(posts::updated_at)
+                .first::<chrono::NaiveDateTime>(&conn)
+        };
+
+        set_tags(&conn, post.id, &["Meta"])?;
+        assert_eq!(long_ago, updated_at()?);
+
+        set_tags(&conn, post.id, &["meta", "news"])?;
+        assert_eq!(vec!["meta", "news"], tags_for(&conn, post.id)?);
+        assert!(updated_at()? > long_ago);
+
+        Ok(())
+    });
+}
+
+#[test]
+fn examine_sql_from_posts_tagged() {
+    use diesel::debug_query;
+    use diesel::mysql::Mysql;
+
+    let query = active_posts()
+        .inner_join(post_tags::table)
+        .filter(post_tags::tag.eq("rust"))
+        .select(posts::id);
+    let sql = "SELECT `posts`.`id` FROM (`posts` INNER JOIN `post_tags` \
+               ON `post_tags`.`post_id` = `posts`.`id`) \
+               WHERE `posts`.`deleted_at` IS NULL AND `post_tags`.`tag` = ? \
+               -- binds: [\"rust\"]";
+    assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
+}
@@ -0,0 +1,295 @@
+use crate::models::{ApiToken, NewApiToken};
+use crate::schema::api_tokens;
+use crate::soft_delete::active_users;
+use crate::{DbConnection, User};
+use chrono::{Duration, NaiveDateTime};
+use diesel::prelude::*;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use std::error::Error;
+use std::fmt;
+
+/// Makes tokens recognisable in logs and secret scanners.
+const TOKEN_PREFIX: &str = "dd_";
+
+pub const SESSION_SCOPE: &str = "session";
+
+#[derive(Debug)]
+pub enum TokenError {
+    /// Unknown, malformed or revoked token.
+    Invalid,
+    Expired,
+    MissingScope(String),
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenError::Invalid => write!(f, "invalid token"),
+            TokenError::Expired => write!(f, "token has expired"),
+            TokenError::MissingScope(scope) => write!(f, "token lacks the {:?} scope", scope),
+            TokenError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for TokenError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TokenError::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<diesel::result::Error> for TokenError {
+    fn from(e: diesel::result::Error) -> Self {
+        TokenError::Database(e)
+    }
+}
+
+impl crate::metrics::ErrorKind for TokenError {
+    fn kind(&self) -> &'static str {
+        match self {
+            TokenError::Invalid => "invalid_token",
+            TokenError::Expired => "expired_token",
+            TokenError::MissingScope(_) => "missing_scope",
+            TokenError::Database(e) => e.kind(),
+        }
+    }
+}
+
+/// A freshly issued token. `token` is the only copy of the plaintext.
+#[derive(Debug)]
+pub struct IssuedToken {
+    pub token: String,
+    pub record: ApiToken,
+}
+
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("{}{}", TOKEN_PREFIX, hex::encode(bytes))
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Times are taken from the database so expiry does not depend on the
+/// clock of whichever machine issued the token.
+fn db_now(conn: &DbConnection) -> QueryResult<NaiveDateTime> {
+    diesel::select(diesel::dsl::now).get_result(conn)
+}
+
+pub fn issue_token(
+    conn: &DbConnection,
+    user_id: i32,
+    scopes: &[&str],
+    ttl: Duration,
+) -> Result<IssuedToken, TokenError> {
+    let token = generate_token();
+    let token_hash = hash_token(&token);
+    let scopes = scopes.join(" ");
+
+    conn.transaction(|| {
+        let new_token = NewApiToken {
+            user_id,
+            token_hash: &token_hash,
+            scopes: &scopes,
+            expires_at: db_now(conn)? + ttl,
+        };
+        diesel::insert_into(api_tokens::table)
+            .values(&new_token)
+            .execute(conn)?;
+
+        let record = api_tokens::table
+            .filter(api_tokens::token_hash.eq(&token_hash))
+            .first(conn)?;
+        Ok(IssuedToken {
+            token: token.clone(),
+            record,
+        })
+    })
+}
+
+/// A login session is a token with the `session` scope and a short life.
+pub fn issue_session(conn: &DbConnection, user_id: i32) -> Result<IssuedToken, TokenError> {
+    issue_token(conn, user_id, &[SESSION_SCOPE], Duration::hours(12))
+}
+
+/// Resolves a presented token to its user, recording when it was used.
+pub fn validate_token(
+    conn: &DbConnection,
+    token: &str,
+    required_scope: Option<&str>,
+) -> Result<(User, ApiToken), TokenError> {
+    if !token.starts_with(TOKEN_PREFIX) {
+        return Err(TokenError::Invalid);
+    }
+
+    conn.transaction(|| {
+        let now = db_now(conn)?;
+        let record = api_tokens::table
+            .filter(api_tokens::token_hash.eq(hash_token(token)))
+            .filter(api_tokens::revoked_at.is_null())
+            .first::<ApiToken>(conn)
+            .optional()?
+            .ok_or(TokenError::Invalid)?;
+
+        if record.expires_at <= now {
+            return Err(TokenError::Expired);
+        }
+        if let Some(scope) = required_scope {
+            if !record.has_scope(scope) {
+                return Err(TokenError::MissingScope(scope.to_string()));
+            }
+        }
+        let user = active_users()
+            .find(record.user_id)
+            .first::<User>(conn)
+            .optional()?
+            .ok_or(TokenError::Invalid)?;
+
+        diesel::update(api_tokens::table.find(record.id))
+            .set(api_tokens::last_used_at.eq(now))
+            .execute(conn)?;
+
+        Ok((user, record))
+    })
+}
+
+/// Replaces a valid token with a new one carrying the same scopes.
+pub fn rotate_token(
+    conn: &DbConnection,
+    token: &str,
+    ttl: Duration,
+) -> Result<IssuedToken, TokenError> {
+    conn.transaction(|| {
+        let (user, record) = validate_token(conn, token, None)?;
+        revoke_token(conn, token)?;
+
+        let scopes = record.scopes.split_whitespace().collect::<Vec<_>>();
+        issue_token(conn, user.id, &scopes, ttl)
+    })
+}
+
+/// The user behind the `API_TOKEN` environment variable, for the CLI.
+pub fn user_from_env(conn: &DbConnection) -> Result<User, TokenError> {
+    dotenv::dotenv().ok();
+
+    let token = std::env::var("API_TOKEN").map_err(|_| TokenError::Invalid)?;
+    validate_token(conn, &token, None).map(|(user, _)| user)
+}
+
+pub fn revoke_token(conn: &DbConnection, token: &str) -> QueryResult<usize> {
+    let now = db_now(conn)?;
+
+    diesel::update(
+        api_tokens::table
+            .filter(api_tokens::token_hash.eq(hash_token(token)))
+            .filter(api_tokens::revoked_at.is_null()),
+    )
+    .set(api_tokens::revoked_at.eq(now))
+    .execute(conn)
+}
+
+/// Revokes every token of a user, e.g. after a password change.
+pub fn revoke_all_tokens(conn: &DbConnection, user_id: i32) -> QueryResult<usize> {
+    let now = db_now(conn)?;
+
+    diesel::update(
+        api_tokens::table
+            .filter(api_tokens::user_id.eq(user_id))
+            .filter(api_tokens::revoked_at.is_null()),
+    )
+    .set(api_tokens::revoked_at.eq(now))
+    .execute(conn)
+}
+
+/// Deletes tokens that expired or were revoked before `older_than`.
+pub fn cleanup_tokens(conn: &DbConnection, older_than: NaiveDateTime) -> QueryResult<usize> {
+    diesel::delete(
+        api_tokens::table.filter(
+            api_tokens::expires_at
+                .lt(older_than)
+                .or(api_tokens::revoked_at.lt(older_than)),
+        ),
+    )
+    .execute(conn)
+}
+
+#[test]
+fn generated_tokens_are_prefixed_and_unique() {
+    let first = generate_token();
+    let second = generate_token();
+
+    assert!(first.starts_with(TOKEN_PREFIX));
+    assert_eq!(TOKEN_PREFIX.len() + 64, first.len());
+    assert_ne!(first, second);
+}
+
+#[test]
+fn hash_token_is_sha256_hex() {
+    assert_eq!(
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        hash_token("abc")
+    );
+}
+
+#[test]
+fn examine_sql_from_cleanup_tokens() {
+    use diesel::debug_query;
+    use diesel::mysql::Mysql;
+
+    let older_than = chrono::NaiveDate::from_ymd(2020, 9, 1).and_hms(0, 0, 0);
+    let query = diesel::delete(
+        api_tokens::table.filter(
+            api_tokens::expires_at
+                .lt(older_than)
+                .or(api_tokens::revoked_at.lt(older_than)),
+        ),
+    );
+    let sql = "DELETE FROM `api_tokens` WHERE (`api_tokens`.`expires_at` < ? \
+               OR `api_tokens`.`revoked_at` < ?) \
+               -- binds: [2020-09-01T00:00:00, 2020-09-01T00:00:00]";
+    assert_eq!(sql, debug_query::<Mysql, _>(&query).to_string());
+}
+
+#[test]
+fn issue_validate_rotate_and_revoke() {
+    use crate::fixtures::{seed, Profile};
+    use diesel::result::Error;
+
+    let conn = crate::establish_connection();
+    conn.test_transaction::<_, Error, _>(|| {
+        let seeded = seed(&conn, Profile::Minimal).unwrap();
+        let user = &seeded.users["sean"];
+        let issued = issue_token(&conn, user.id, &["posts:write"], Duration::days(1)).unwrap();
+
+        let (owner, record) = validate_token(&conn, &issued.token, Some("posts:write")).unwrap();
+        assert_eq!(user.id, owner.id);
+        assert!(record.has_scope("posts:write"));
+        match validate_token(&conn, &issued.token, Some("admin")) {
+            Err(TokenError::MissingScope(_)) => {}
+            other => panic!("expected MissingScope, got {:?}", other),
+        }
+
+        let rotated = rotate_token(&conn, &issued.token, Duration::days(1)).unwrap();
+        assert!(validate_token(&conn, &issued.token, None).is_err());
+        assert!(validate_token(&conn, &rotated.token, Some("posts:write")).is_ok());
+
+        revoke_token(&conn, &rotated.token).unwrap();
+        assert!(validate_token(&conn, &rotated.token, None).is_err());
+
+        let expired = issue_token(&conn, user.id, &[], Duration::seconds(-1)).unwrap();
+        match validate_token(&conn, &expired.token, None) {
+            Err(TokenError::Expired) => {}
+            other => panic!("expected Expired, got {:?}", other),
+        }
+
+        Ok(())
+    });
+}
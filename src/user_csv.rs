@@ -0,0 +1,351 @@
+use crate::audit::Audited;
+use crate::models::HairColor;
+use crate::schema::users;
+use crate::soft_delete::active_users;
+use crate::validation::Validate;
+use crate::{insert_chunked, DbConnection, User, UserForm};
+use diesel::prelude::*;
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Rows per `INSERT` statement.
+pub const CHUNK_SIZE: usize = 500;
+
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Values of `hair_color` read as `NULL`, besides an empty cell.
+const NULLS: &[&str] = &["null", "\\N", "none"];
+
+/// Which CSV columns hold which user fields. Headers are matched ignoring
+/// case and surrounding whitespace.
+#[derive(Clone, Debug)]
+pub struct ColumnMapping {
+    pub name: String,
+    pub hair_color: String,
+}
+
+impl Default for ColumnMapping {
+    fn default() -> Self {
+        ColumnMapping {
+            name: "name".to_string(),
+            hair_color: "hair_color".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ImportMode {
+    /// Insert nothing if any row is invalid.
+    AllOrNothing,
+    /// Insert the valid rows and report the others.
+    SkipBadRows,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct RowError {
+    /// Line in the file, counting the header as line 1.
+    pub line: u64,
+    pub message: String,
+}
+
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[derive(Default, PartialEq, Debug)]
+pub struct CsvImportReport {
+    pub inserted: usize,
+    /// Rows that were skipped; always empty for `AllOrNothing`.
+    pub skipped: Vec<RowError>,
+}
+
+#[derive(Debug)]
+pub enum CsvError {
+    Csv(csv::Error),
+    MissingColumn(String),
+    /// Invalid rows in `AllOrNothing` mode; nothing was inserted.
+    Rejected(Vec<RowError>),
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for CsvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CsvError::Csv(e) => write!(f, "{}", e),
+            CsvError::MissingColumn(column) => write!(f, "no {:?} column in the header", column),
+            CsvError::Rejected(errors) => {
+                write!(f, "{} invalid rows, nothing imported", errors.len())?;
+                for error in errors {
+                    write!(f, "\n  {}", error)?;
+                }
+                Ok(())
+            }
+            CsvError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for CsvError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CsvError::Csv(e) => Some(e),
+            CsvError::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<csv::Error> for CsvError {
+    fn from(e: csv::Error) -> Self {
+        CsvError::Csv(e)
+    }
+}
+
+impl From<diesel::result::Error> for CsvError {
+    fn from(e: diesel::result::Error) -> Self {
+        CsvError::Database(e)
+    }
+}
+
+impl crate::metrics::ErrorKind for CsvError {
+    fn kind(&self) -> &'static str {
+        match self {
+            CsvError::Csv(_) => "csv",
+            CsvError::MissingColumn(_) | CsvError::Rejected(_) => "invalid",
+            CsvError::Database(e) => e.kind(),
+        }
+    }
+}
+
+/// A row that passed validation.
+#[derive(Clone, PartialEq, Debug)]
+pub struct UserRow {
+    pub line: u64,
+    pub name: String,
+    pub hair_color: Option<HairColor>,
+}
+
+fn column(headers: &csv::StringRecord, wanted: &str) -> Option<usize> {
+    headers
+        .iter()
+        .position(|header| header.trim().eq_ignore_ascii_case(wanted.trim()))
+}
+
+fn nullable(cell: Option<&str>) -> Option<&str> {
+    cell.map(str::trim)
+        .filter(|cell| !cell.is_empty() && !NULLS.iter().any(|n| cell.eq_ignore_ascii_case(n)))
+}
+
+/// Reads and validates every row, returning the valid rows and an error for
+/// each invalid one.
+pub fn read_rows<R: io::Read>(
+    input: R,
+    mapping: &ColumnMapping,
+) -> Result<(Vec<UserRow>, Vec<RowError>), CsvError> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(input);
+    let headers = reader.headers()?.clone();
+    let name_at = column(&headers, &mapping.name)
+        .ok_or_else(|| CsvError::MissingColumn(mapping.name.clone()))?;
+    // A file without hair colours is fine; every user gets NULL.
+    let hair_color_at = column(&headers, &mapping.hair_color);
+
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                let line = e.position().map_or(0, |p| p.line());
+                errors.push(RowError {
+                    line,
+                    message: e.to_string(),
+                });
+                continue;
+            }
+        };
+        let line = record.position().map_or(0, |p| p.line());
+        let form = UserForm {
+            name: record.get(name_at).unwrap_or_default(),
+            hair_color: hair_color_at
+                .and_then(|at| nullable(record.get(at)))
+                .map(|color| HairColor::from(color.to_string())),
+        };
+
+        match form.validate() {
+            Ok(form) => rows.push(UserRow {
+                line,
+                name: form.name.to_string(),
+                hair_color: form.hair_color,
+            }),
+            Err(e) => errors.push(RowError {
+                line,
+                message: e.to_string(),
+            }),
+        }
+    }
+    Ok((rows, errors))
+}
+
+/// Inserts the users in `input` in one transaction, `CHUNK_SIZE` at a time,
+/// recording each as created by `actor`.
+pub fn import_users<R: io::Read>(
+    conn: &DbConnection,
+    actor: Option<&User>,
+    input: R,
+    mapping: &ColumnMapping,
+    mode: ImportMode,
+) -> Result<CsvImportReport, CsvError> {
+    let (rows, errors) = read_rows(input, mapping)?;
+    if mode == ImportMode::AllOrNothing && !errors.is_empty() {
+        return Err(CsvError::Rejected(errors));
+    }
+
+    crate::metrics::track("import_users_csv", || {
+        conn.transaction(|| {
+            let forms = rows.iter().map(|row| UserForm {
+                name: &row.name,
+                hair_color: row.hair_color.clone(),
+            });
+            let inserted = insert_chunked(
+                forms,
+                CHUNK_SIZE,
+                |chunk| {
+                    User::audited_inserts(conn, actor, || {
+                        diesel::insert_into(users::table)
+                            .values(chunk)
+                            .execute(conn)
+                    })
+                },
+                |_| (),
+            )?;
+            Ok(CsvImportReport {
+                inserted,
+                skipped: errors.clone(),
+            })
+        })
+    })
+}
+
+/// Writes users with their timestamps. Password hashes are never exported.
+pub fn write_users<W: io::Write>(output: W, users: &[User]) -> Result<(), CsvError> {
+    let mut writer = csv::Writer::from_writer(output);
+    writer.write_record([
+        "id",
+        "name",
+        "hair_color",
+        "email",
+        "role",
+        "created_at",
+        "updated_at",
+    ])?;
+    for user in users {
+        writer.write_record([
+            user.id.to_string(),
+            user.name.clone(),
+            user.hair_color
+                .as_ref()
+                .map(|color| color.to_string())
+                .unwrap_or_default(),
+            user.email.clone().unwrap_or_default(),
+            user.role.to_string(),
+            user.created_at.format(TIMESTAMP_FORMAT).to_string(),
+            user.updated_at.format(TIMESTAMP_FORMAT).to_string(),
+        ])?;
+    }
+    writer.flush().map_err(csv::Error::from)?;
+    Ok(())
+}
+
+/// Exports every user that is not deleted, in id order.
+pub fn export_users<W: io::Write>(conn: &DbConnection, output: W) -> Result<usize, CsvError> {
+    let users = crate::metrics::track("export_users_csv", || {
+        active_users().order(users::id).load::<User>(conn)
+    })?;
+    write_users(output, &users)?;
+    Ok(users.len())
+}
+
+#[test]
+fn reads_rows_with_mapped_headers() {
+    let csv = "Full Name,Hair\nSean,Black\n Tess ,NULL\nRuby,\n";
+    let mapping = ColumnMapping {
+        name: "full name".into(),
+        hair_color: "HAIR".into(),
+    };
+
+    let (rows, errors) = read_rows(csv.as_bytes(), &mapping).unwrap();
+    assert!(errors.is_empty());
+    assert_eq!(
+        vec![
+            UserRow {
+                line: 2,
+                name: "Sean".into(),
+                hair_color: Some(HairColor::Black),
+            },
+            UserRow {
+                line: 3,
+                name: "Tess".into(),
+                hair_color: None,
+            },
+            UserRow {
+                line: 4,
+                name: "Ruby".into(),
+                hair_color: None,
+            },
+        ],
+        rows
+    );
+}
+
+#[test]
+fn reports_bad_rows_by_line() {
+    let csv = "name,hair_color\nSean,Black\n,Brown\nTess,Green\n";
+
+    let (rows, errors) = read_rows(csv.as_bytes(), &ColumnMapping::default()).unwrap();
+    assert_eq!(1, rows.len());
+    assert_eq!(
+        vec![3, 4],
+        errors.iter().map(|e| e.line).collect::<Vec<_>>()
+    );
+    assert_eq!("line 3: name is required", errors[0].to_string());
+}
+
+#[test]
+fn missing_name_column_is_an_error() {
+    match read_rows(
+        "email\nsean@example.com\n".as_bytes(),
+        &ColumnMapping::default(),
+    ) {
+        Err(CsvError::MissingColumn(column)) => assert_eq!("name", column),
+        other => panic!("expected MissingColumn, got {:?}", other),
+    }
+}
+
+#[test]
+fn writes_users_with_timestamps() {
+    let created = chrono::NaiveDate::from_ymd(2020, 10, 1).and_hms(9, 30, 0);
+    let user = User {
+        id: 1,
+        name: "Sean, Jr.".into(),
+        hair_color: Some(HairColor::Black),
+        created_at: created,
+        updated_at: created,
+        deleted_at: None,
+        version: 0,
+        email: None,
+        password_hash: Some("secret".into()),
+        role: crate::models::Role::Reader,
+    };
+
+    let mut out = Vec::new();
+    write_users(&mut out, &[user]).unwrap();
+    assert_eq!(
+        "id,name,hair_color,email,role,created_at,updated_at\n\
+         1,\"Sean, Jr.\",black,,reader,2020-10-01 09:30:00,2020-10-01 09:30:00\n",
+        String::from_utf8(out).unwrap()
+    );
+}
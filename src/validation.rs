@@ -0,0 +1,262 @@
+use crate::models::{HairColor, NewPost, PostChanges};
+use crate::UserForm;
+
+use std::error::Error;
+use std::fmt;
+
+/// `posts.title` is a `VARCHAR(255)`, which MySQL counts in characters.
+pub const TITLE_MAX_CHARS: usize = 255;
+/// `TEXT` columns hold at most 65,535 bytes.
+pub const TEXT_MAX_BYTES: usize = 65_535;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+/// Every problem found with a value, in the order the fields were checked.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ValidationErrors(pub Vec<FieldError>);
+
+impl ValidationErrors {
+    pub fn fields(&self) -> Vec<&'static str> {
+        self.0.iter().map(|e| e.field).collect()
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, e) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{} {}", e.field, e.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ValidationErrors {}
+
+impl crate::metrics::ErrorKind for ValidationErrors {
+    fn kind(&self) -> &'static str {
+        "invalid"
+    }
+}
+
+/// Checks a value before it is written and returns it with surrounding
+/// whitespace trimmed from single-line fields, or every field error at once.
+/// Post bodies are Markdown, where leading and trailing whitespace matters,
+/// so they are kept as they are.
+pub trait Validate: Sized {
+    fn validate(self) -> Result<Self, ValidationErrors>;
+}
+
+/// Collects field errors from a sequence of rule checks.
+#[derive(Default)]
+pub struct Rules {
+    errors: Vec<FieldError>,
+}
+
+impl Rules {
+    pub fn new() -> Self {
+        Rules::default()
+    }
+
+    fn fail(&mut self, field: &'static str, message: String) -> &mut Self {
+        self.errors.push(FieldError { field, message });
+        self
+    }
+
+    pub fn required(&mut self, field: &'static str, value: &str) -> &mut Self {
+        if value.is_empty() {
+            self.fail(field, "is required".to_string());
+        }
+        self
+    }
+
+    pub fn max_chars(&mut self, field: &'static str, value: &str, max: usize) -> &mut Self {
+        let len = value.chars().count();
+        if len > max {
+            self.fail(
+                field,
+                format!("must be at most {} characters (got {})", max, len),
+            );
+        }
+        self
+    }
+
+    pub fn max_bytes(&mut self, field: &'static str, value: &str, max: usize) -> &mut Self {
+        if value.len() > max {
+            self.fail(
+                field,
+                format!("must be at most {} bytes (got {})", max, value.len()),
+            );
+        }
+        self
+    }
+
+    pub fn one_of(&mut self, field: &'static str, value: &str, allowed: &[&str]) -> &mut Self {
+        if !allowed.iter().any(|a| a.eq_ignore_ascii_case(value)) {
+            self.fail(
+                field,
+                format!("must be one of {} (got {:?})", allowed.join(", "), value),
+            );
+        }
+        self
+    }
+
+    pub fn finish<T>(&mut self, value: T) -> Result<T, ValidationErrors> {
+        if self.errors.is_empty() {
+            Ok(value)
+        } else {
+            Err(ValidationErrors(std::mem::take(&mut self.errors)))
+        }
+    }
+}
+
+impl<'a> Validate for UserForm<'a> {
+    fn validate(self) -> Result<Self, ValidationErrors> {
+        let name = self.name.trim();
+
+        let mut rules = Rules::new();
+        rules
+            .required("name", name)
+            .max_bytes("name", name, TEXT_MAX_BYTES);
+        // Deserializing never fails on a colour, so unknown ones end up here.
+        if let Some(HairColor::Other(raw)) = &self.hair_color {
+            rules.one_of("hair_color", raw, HairColor::NAMES);
+        }
+        rules.finish(UserForm { name, ..self })
+    }
+}
+
+impl<'a> Validate for NewPost<'a> {
+    fn validate(self) -> Result<Self, ValidationErrors> {
+        let title = self.title.trim();
+
+        Rules::new()
+            .required("title", title)
+            .max_chars("title", title, TITLE_MAX_CHARS)
+            .required("body", self.body.trim())
+            .max_bytes("body", self.body, TEXT_MAX_BYTES)
+            .finish(NewPost { title, ..self })
+    }
+}
+
+/// Only the fields being changed are checked, with the rules of `NewPost`.
+impl<'a> Validate for PostChanges<'a> {
+    fn validate(self) -> Result<Self, ValidationErrors> {
+        let title = self.title.map(str::trim);
+
+        let mut rules = Rules::new();
+        if let Some(title) = title {
+            rules
+                .required("title", title)
+                .max_chars("title", title, TITLE_MAX_CHARS);
+        }
+        if let Some(body) = self.body {
+            rules
+                .required("body", body.trim())
+                .max_bytes("body", body, TEXT_MAX_BYTES);
+        }
+        rules.finish(PostChanges { title, ..self })
+    }
+}
+
+#[test]
+fn user_form_is_trimmed() {
+    let form = serde_json::from_str::<UserForm>(r#"{ "name": " Sean ", "hair_color": "Black" }"#)
+        .unwrap()
+        .validate()
+        .unwrap();
+
+    assert_eq!("Sean", form.name);
+    assert_eq!(Some(HairColor::Black), form.hair_color);
+}
+
+#[test]
+fn user_form_reports_every_field() {
+    let errors = serde_json::from_str::<UserForm>(r#"{ "name": "  ", "hair_color": "Green" }"#)
+        .unwrap()
+        .validate()
+        .unwrap_err();
+
+    assert_eq!(vec!["name", "hair_color"], errors.fields());
+}
+
+#[test]
+fn missing_hair_color_is_allowed() {
+    let form =
+        serde_json::from_str::<UserForm>(r#"{ "name": "Ruby", "hair_color": null }"#).unwrap();
+
+    assert!(form.validate().is_ok());
+}
+
+#[test]
+fn post_title_length_matches_the_column() {
+    let at_limit = "é".repeat(TITLE_MAX_CHARS);
+    let too_long = "é".repeat(TITLE_MAX_CHARS + 1);
+    let post = |title| NewPost {
+        title,
+        body: "Diesel",
+        author_id: None,
+    };
+
+    assert!(post(&at_limit).validate().is_ok());
+    let errors = post(&too_long).validate().unwrap_err();
+    assert_eq!(
+        "title must be at most 255 characters (got 256)",
+        errors.to_string()
+    );
+}
+
+#[test]
+fn empty_post_is_rejected() {
+    let errors = NewPost {
+        title: "",
+        body: " \n",
+        author_id: None,
+    }
+    .validate()
+    .unwrap_err();
+
+    assert_eq!(vec!["title", "body"], errors.fields());
+}
+
+#[test]
+fn post_bodies_keep_their_whitespace() {
+    let post = NewPost {
+        title: " Rust ",
+        body: "    let x = 1;\n",
+        author_id: None,
+    }
+    .validate()
+    .unwrap();
+
+    assert_eq!("Rust", post.title);
+    assert_eq!("    let x = 1;\n", post.body);
+}
+
+#[test]
+fn post_changes_check_only_changed_fields() {
+    let too_long = "x".repeat(TITLE_MAX_CHARS + 1);
+    let errors = PostChanges {
+        title: Some(&too_long),
+        body: Some("  "),
+        published: None,
+    }
+    .validate()
+    .unwrap_err();
+    assert_eq!(vec!["title", "body"], errors.fields());
+
+    let changes = PostChanges {
+        title: Some(" Renamed "),
+        ..PostChanges::default()
+    }
+    .validate()
+    .unwrap();
+    assert_eq!(Some("Renamed"), changes.title);
+    assert_eq!(None, changes.body);
+}